@@ -1,3 +1,22 @@
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+
+use fontdue::{Font, FontSettings};
+
+const FONT_PATH: &str = "fonts/DejaVuSans.ttf";
+const FONT_NAME: &str = "DEJAVU_SANS";
+
+// Pixel sizes pre-rasterized into atlases. Other sizes are resampled from the
+// nearest larger atlas at draw time.
+const ATLAS_SIZES: [u32; 4] = [16, 24, 32, 48];
+
+const FIRST_CHAR: u8 = b' ';
+const LAST_CHAR: u8 = b'~';
+
+// Names a file holding the hex encoded Ed25519 public key kernels must be signed with
+const TRUSTED_KEY_ENV: &str = "BOOT_PUBLIC_KEY";
+
 fn main() {
     let target = std::env::var("TARGET").unwrap();
 
@@ -5,4 +24,107 @@ fn main() {
     if target == "aarch64-unknown-none" {
         println!("cargo:rustc-link-arg=-Tlink.ld");
     }
+
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed={FONT_PATH}");
+
+    let out_dir = std::env::var("OUT_DIR").unwrap();
+    generate_glyph_atlases(Path::new(&out_dir));
+    generate_trusted_key(Path::new(&out_dir));
+}
+
+/// Embeds the public key named by `TRUSTED_KEY_ENV`, if set, as `TRUSTED_KEY`.
+fn generate_trusted_key(out_dir: &Path) {
+    println!("cargo:rerun-if-env-changed={TRUSTED_KEY_ENV}");
+
+    let key = match std::env::var(TRUSTED_KEY_ENV) {
+        Ok(path) if !path.is_empty() => {
+            println!("cargo:rerun-if-changed={path}");
+            let hex = fs::read_to_string(&path)
+                .unwrap_or_else(|err| panic!("Failed to read public key {path}: {err}"));
+            let key = parse_hex_key(hex.trim())
+                .unwrap_or_else(|| panic!("{path} is not a hex encoded 32 byte public key"));
+            format!("Some({key:?})")
+        }
+        _ => "None".to_string(),
+    };
+
+    fs::write(
+        out_dir.join("trusted_key.rs"),
+        format!("pub const TRUSTED_KEY: Option<[u8; PUBLIC_KEY_SIZE]> = {key};\n"),
+    )
+    .expect("Failed to write trusted key source");
+}
+
+fn parse_hex_key(hex: &str) -> Option<[u8; 32]> {
+    if hex.len() != 64 {
+        return None;
+    }
+    let mut key = [0u8; 32];
+    for (byte, pair) in key.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(key)
+}
+
+/// Rasterizes the printable ASCII range of `FONT_PATH` at each of `ATLAS_SIZES`
+/// and writes the coverage bitmaps plus a Rust source file describing them.
+fn generate_glyph_atlases(out_dir: &Path) {
+    let data = fs::read(FONT_PATH).expect("Failed to read font file");
+    let font = Font::from_bytes(data, FontSettings::default()).expect("Failed to parse font file");
+
+    let mut src = String::new();
+    let mut atlas_names = Vec::new();
+
+    for size in ATLAS_SIZES {
+        let px = size as f32;
+        let line = font
+            .horizontal_line_metrics(px)
+            .expect("Font has no horizontal metrics");
+
+        let mut coverage = Vec::new();
+        let mut glyphs = String::new();
+        for ch in FIRST_CHAR..=LAST_CHAR {
+            let (metrics, bitmap) = font.rasterize(ch as char, px);
+            writeln!(
+                glyphs,
+                "        GlyphInfo {{ offset: {}, width: {}, height: {}, x_min: {}, y_min: {}, advance: {} }},",
+                coverage.len(),
+                metrics.width,
+                metrics.height,
+                metrics.xmin,
+                metrics.ymin,
+                (metrics.advance_width * 64.0).round() as u32,
+            )
+            .unwrap();
+            coverage.extend_from_slice(&bitmap);
+        }
+
+        let bin_name = format!("{}_{size}.bin", FONT_NAME.to_lowercase());
+        fs::write(out_dir.join(&bin_name), &coverage).expect("Failed to write glyph atlas");
+
+        let atlas_name = format!("{FONT_NAME}_{size}");
+        writeln!(
+            src,
+            "static {atlas_name}: GlyphAtlas = GlyphAtlas {{\n    size: {size},\n    ascent: {},\n    descent: {},\n    line_height: {},\n    glyphs: [\n{glyphs}    ],\n    coverage: include_bytes!(concat!(env!(\"OUT_DIR\"), \"/{bin_name}\")),\n}};\n",
+            line.ascent.round() as i32,
+            line.descent.round() as i32,
+            line.new_line_size.round() as u32,
+        )
+        .unwrap();
+        atlas_names.push(atlas_name);
+    }
+
+    writeln!(
+        src,
+        "pub static {FONT_NAME}: AaFont = AaFont {{ atlases: &[{}] }};",
+        atlas_names
+            .iter()
+            .map(|name| format!("&{name}"))
+            .collect::<Vec<_>>()
+            .join(", ")
+    )
+    .unwrap();
+
+    fs::write(out_dir.join("glyph_atlases.rs"), src).expect("Failed to write glyph atlas source");
 }
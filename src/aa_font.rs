@@ -0,0 +1,114 @@
+pub const FIRST_CHAR: u8 = b' ';
+pub const LAST_CHAR: u8 = b'~';
+pub const GLYPH_COUNT: usize = (LAST_CHAR - FIRST_CHAR + 1) as usize;
+
+/// Placement of a single glyph inside its atlas, in atlas pixels.
+#[derive(Clone, Copy, Debug)]
+pub struct GlyphInfo {
+    /// Byte offset of the glyph's coverage bitmap in `GlyphAtlas::coverage`
+    pub offset: usize,
+    pub width: usize,
+    pub height: usize,
+    /// Left edge of the bitmap relative to the pen position
+    pub x_min: i32,
+    /// Bottom edge of the bitmap relative to the baseline, positive upwards
+    pub y_min: i32,
+    /// Pen advance in 1/64 pixels
+    pub advance: u32,
+}
+
+/// Grayscale glyph bitmaps for one font rasterized at a single pixel size.
+pub struct GlyphAtlas {
+    pub size: u32,
+    pub ascent: i32,
+    pub descent: i32,
+    pub line_height: u32,
+    pub glyphs: [GlyphInfo; GLYPH_COUNT],
+    pub coverage: &'static [u8],
+}
+
+/// A font made of several pre-rasterized atlases, generated by `build.rs`.
+pub struct AaFont {
+    pub atlases: &'static [&'static GlyphAtlas],
+}
+
+include!(concat!(env!("OUT_DIR"), "/glyph_atlases.rs"));
+
+impl GlyphAtlas {
+    pub fn glyph(&self, ch: u8) -> Option<&GlyphInfo> {
+        if (FIRST_CHAR..=LAST_CHAR).contains(&ch) {
+            Some(&self.glyphs[(ch - FIRST_CHAR) as usize])
+        } else {
+            None
+        }
+    }
+
+    fn coverage_at(&self, glyph: &GlyphInfo, x: i32, y: i32) -> u32 {
+        if x < 0 || y < 0 || x as usize >= glyph.width || y as usize >= glyph.height {
+            return 0;
+        }
+        self.coverage[glyph.offset + y as usize * glyph.width + x as usize] as u32
+    }
+
+    /// Bilinearly samples glyph coverage at a position given in 1/256 atlas pixels,
+    /// measured from the center of the top-left bitmap pixel.
+    pub fn sample(&self, glyph: &GlyphInfo, x_fx: i32, y_fx: i32) -> u8 {
+        let (x0, y0) = (x_fx >> 8, y_fx >> 8);
+        let (fx, fy) = ((x_fx & 0xFF) as u32, (y_fx & 0xFF) as u32);
+
+        let top =
+            self.coverage_at(glyph, x0, y0) * (256 - fx) + self.coverage_at(glyph, x0 + 1, y0) * fx;
+        let bottom = self.coverage_at(glyph, x0, y0 + 1) * (256 - fx)
+            + self.coverage_at(glyph, x0 + 1, y0 + 1) * fx;
+
+        ((top * (256 - fy) + bottom * fy + (1 << 15)) >> 16) as u8
+    }
+}
+
+impl AaFont {
+    /// Picks the smallest atlas rasterized at `size` pixels or larger, so glyphs are only
+    /// ever scaled down. Falls back to the largest atlas for sizes beyond it.
+    pub fn atlas_for(&self, size: f32) -> &GlyphAtlas {
+        self.atlases
+            .iter()
+            .find(|atlas| atlas.size as f32 >= size)
+            .unwrap_or(&self.atlases[self.atlases.len() - 1])
+    }
+}
+
+/// Converts a pixel size into the scale factor, in 1/256 units, applied to `atlas`.
+pub fn scale_for(atlas: &GlyphAtlas, size: f32) -> i32 {
+    ((size * 256.0) / atlas.size as f32 + 0.5) as i32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_atlas_for_picks_smallest_covering_atlas() {
+        assert_eq!(DEJAVU_SANS.atlas_for(12.0).size, 16);
+        assert_eq!(DEJAVU_SANS.atlas_for(24.0).size, 24);
+        assert_eq!(DEJAVU_SANS.atlas_for(24.5).size, 32);
+        assert_eq!(DEJAVU_SANS.atlas_for(200.0).size, 48);
+    }
+
+    #[test]
+    fn test_sample_interpolates_between_pixels() {
+        let atlas = DEJAVU_SANS.atlas_for(48.0);
+        let glyph = atlas.glyph(b'I').unwrap();
+
+        // The stem of 'I' is solid, so its center samples at full coverage
+        let center_x = (glyph.width as i32 / 2) << 8;
+        let center_y = (glyph.height as i32 / 2) << 8;
+        assert_eq!(atlas.sample(glyph, center_x, center_y), 255);
+
+        // Halfway past the right edge blends with the transparent border
+        let edge = (glyph.width as i32 - 1) << 8;
+        let exact = atlas.sample(glyph, edge, center_y);
+        assert_eq!(
+            atlas.sample(glyph, edge + 128, center_y),
+            (exact as u32).div_ceil(2) as u8
+        );
+    }
+}
@@ -1,7 +1,71 @@
+/*
+ * Drops from EL3 or EL2 to EL1h with all interrupts masked and continues at \target,
+ * unless built to stay at EL2 or already below it. Every core goes through this.
+ */
+.macro ENTER_EL1 target
+	mrs	x0, CurrentEL
+	cmp	x0, #(3 << 2)
+	b.ne	.Lnot_el3_\@
+
+	/*
+	 * Only a custom armstub leaves us at EL3. Set up what the firmware's armstub does
+	 * before it drops to EL2: non-secure AArch64 below EL3 with HVC enabled, FP/SIMD
+	 * untrapped, the counter frequency and EL2 with its MMU and caches off
+	 */
+	ldr	x0, ={CONST_SCR_EL3}
+	msr	SCR_EL3, x0
+	msr	CPTR_EL3, xzr
+	ldr	x0, ={CONST_CNTFRQ}
+	msr	CNTFRQ_EL0, x0
+	ldr	x0, ={CONST_SCTLR_EL2}
+	msr	SCTLR_EL2, x0
+
+	/* "Return" to EL2h with all interrupts masked, then carry on as if started there */
+	mov	x0, #0x3c9
+	msr	SPSR_EL3, x0
+	adr	x0, .Lat_el2_\@
+	msr	ELR_EL3, x0
+	eret
+
+.Lnot_el3_\@:
+	cmp	x0, #(2 << 2)
+	b.ne	\target
+.Lat_el2_\@:
+.if {CONST_STAY_AT_EL2} == 0
+	/* Don't trap EL1 accesses to the physical counter and timer, no virtual offset */
+	mrs	x0, CNTHCTL_EL2
+	orr	x0, x0, #0b11
+	msr	CNTHCTL_EL2, x0
+	msr	CNTVOFF_EL2, xzr
+
+	/* Don't trap FP/SIMD at EL2 or EL1, Rust code uses the NEON registers */
+	mov	x0, #0x33ff
+	msr	CPTR_EL2, x0
+	mov	x0, #(0b11 << 20)
+	msr	CPACR_EL1, x0
+
+	/* EL1 is AArch64, with the MMU and caches off */
+	mov	x0, #(1 << 31)
+	msr	HCR_EL2, x0
+	ldr	x0, ={CONST_SCTLR_EL1}
+	msr	SCTLR_EL1, x0
+
+	/* "Return" to EL1h with all interrupts masked */
+	mov	x0, #0x3c5
+	msr	SPSR_EL2, x0
+	adr	x0, \target
+	msr	ELR_EL2, x0
+	eret
+.endif
+.endm
+
 
 .section .text._start
 
 _start:
+	/* The firmware passes the device tree's address in x0, keep it for Rust */
+	mov	x19, x0
+
 	mrs	x0, MPIDR_EL1 /* Read multiprocessor affinity register */
 	and	x0, x0, {CONST_CORE_ID_MASK} /* Mask core id info */
 
@@ -10,11 +74,39 @@ _start:
 	cmp	x0, x1
 	b.ne	.do_nothing
 
+	/*
+	 * The firmware loaded us at __rpi_phys_binary_load_addr but we're linked at
+	 * __rpi_phys_binary_link_addr. Only position independent code may run until
+	 * the image has been copied there, so addresses come from literal pools.
+	 */
+	ldr	x0, =__rpi_phys_binary_load_addr
+	ldr	x1, =__binary_nonzero_start
+	ldr	x2, =__binary_nonzero_end_exclusive
+
+.copy_binary:
+	ldp	x3, x4, [x0], #16
+	stp	x3, x4, [x1], #16
+	cmp	x1, x2
+	b.lo	.copy_binary
+
+	/* Make sure instruction fetches see the copied code */
+	dsb	sy
+	ic	iallu
+	dsb	sy
+	isb
+
+	/* Continue executing from the relocated copy */
+	ldr	x0, =.relocated
+	br	x0
+
+.relocated:
+	/* The firmware starts us at EL2, drop to EL1 unless built to stay there */
+	ENTER_EL1 .clear_bss
+
+.clear_bss:
 	/* Grab start and end of uninitialized data section */
-	adrp    x0, __bss_start
-	add     x0, x0, #:lo12:__bss_start
-	adrp    x1, __bss_end
-	add     x1, x1, #:lo12:__bss_end
+	ldr	x0, =__bss_start
+	ldr	x1, =__bss_end
 
 .zero_uninitialized_data:
 	cmp	x0, x1
@@ -23,17 +115,54 @@ _start:
 	b	.zero_uninitialized_data
 
 .set_stack_pointer:
-	adrp    x0, __boot_core_stack_end
-	add     x0, x0, #:lo12:__boot_core_stack_end
+	ldr	x0, =__boot_core_stack_end
 	mov	sp, x0
 
-	/* Calls our entry point rust function */
+	/* Calls our entry point rust function with the device tree address */
+	mov	x0, x19
 	b	_start_rust
 
 .do_nothing:
 	wfe
 	b	.do_nothing
 
+/*
+ * Cores 1-3 start here once smp::start_secondary_cores writes this address to their
+ * spin table entry. BSS is already cleared and the image relocated by the boot core.
+ */
+.global _start_secondary
+_start_secondary:
+	ENTER_EL1 .secondary_stack
+
+.secondary_stack:
+	/* Core n's stack ends n stacks above __core_stacks_start, see link.ld */
+	mrs	x0, MPIDR_EL1
+	and	x0, x0, {CONST_CORE_ID_MASK}
+	ldr	x1, =__core_stacks_start
+	ldr	x2, =__core_stack_size
+	madd	x1, x0, x2, x1
+	mov	sp, x1
+
+	/* Calls the secondary entry point with the core number */
+	b	_start_secondary_rust
+
+/*
+ * Parks a secondary core the way the firmware's armstub does, polling the spin table
+ * entry at x0 until a kernel writes an entry point there. Called with interrupts masked.
+ * Runs from the copy smp::park_secondary_cores makes below the image, which kernels may
+ * be loaded over, so it must stay position independent.
+ */
+.global __secondary_park_start
+.global __secondary_park_end
+__secondary_park_start:
+	wfe
+	ldr	x1, [x0]
+	cbz	x1, __secondary_park_start
+	br	x1
+__secondary_park_end:
+
+.ltorg
+
 /* set _start metadata for the linker */
 .size	_start, . - _start
 .type	_start, function
@@ -0,0 +1,367 @@
+//! Integrity header prepended to kernel images, checked before they are booted.
+//!
+//! Layout, integers little endian:
+//!
+//! | Offset | Size | Field                                       |
+//! |--------|------|---------------------------------------------|
+//! | 0      | 4    | `MAGIC`                                     |
+//! | 4      | 2    | format version, `VERSION`                   |
+//! | 6      | 2    | header size, the payload starts after it    |
+//! | 8      | 4    | payload size                                |
+//! | 12     | 4    | CRC-32 of the payload                       |
+//! | 16     | 32   | SHA-256 of the payload                      |
+//! | 48     | 4    | flags, `FLAG_SIGNED`                        |
+//! | 52     | 12   | reserved, zero                              |
+//! | 64     | 64   | Ed25519 signature of the payload, if signed |
+//!
+//! Builds with a trusted public key embedded (see `TRUSTED_KEY`) only boot images signed
+//! with the matching secret key.
+
+use core::fmt;
+
+use ed25519_compact::{PublicKey, Signature};
+
+use crate::crc32::crc32;
+use crate::sha256::{DIGEST_SIZE, sha256};
+
+pub const MAGIC: [u8; 4] = *b"RPIK";
+pub const VERSION: u16 = 1;
+pub const HEADER_SIZE: usize = 64;
+pub const SIGNED_HEADER_SIZE: usize = HEADER_SIZE + SIGNATURE_SIZE;
+
+pub const PUBLIC_KEY_SIZE: usize = 32;
+pub const SIGNATURE_SIZE: usize = 64;
+
+pub const FLAG_SIGNED: u32 = 1 << 0;
+
+// Generated by build.rs from the key file named by the BOOT_PUBLIC_KEY environment variable:
+// pub const TRUSTED_KEY: Option<[u8; PUBLIC_KEY_SIZE]>
+include!(concat!(env!("OUT_DIR"), "/trusted_key.rs"));
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ImageHeader {
+    pub payload_size: u32,
+    pub crc32: u32,
+    pub sha256: [u8; DIGEST_SIZE],
+    pub signature: Option<[u8; SIGNATURE_SIZE]>,
+}
+
+/// A digest delivered separately from the image, e.g. alongside it on the wire.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Digest {
+    Crc32(u32),
+    Sha256([u8; DIGEST_SIZE]),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VerifyError {
+    UnsupportedVersion(u16),
+    Truncated {
+        expected: usize,
+        actual: usize,
+    },
+    Crc32Mismatch {
+        expected: u32,
+        actual: u32,
+    },
+    Sha256Mismatch {
+        expected: [u8; DIGEST_SIZE],
+        actual: [u8; DIGEST_SIZE],
+    },
+    BadHeaderSize(u16),
+    Unsigned,
+    BadSignature,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VerifyError::UnsupportedVersion(version) => {
+                write!(f, "unsupported image header version {version}")
+            }
+            VerifyError::Truncated { expected, actual } => {
+                write!(f, "image truncated: {actual} of {expected} bytes")
+            }
+            VerifyError::Crc32Mismatch { expected, actual } => {
+                write!(f, "CRC32 {actual:#010x} does not match {expected:#010x}")
+            }
+            VerifyError::Sha256Mismatch { expected, actual } => {
+                write!(f, "SHA-256 mismatch\nexpected ")?;
+                write_hex(f, expected)?;
+                write!(f, "\nactual   ")?;
+                write_hex(f, actual)
+            }
+            VerifyError::BadHeaderSize(size) => write!(f, "bad image header size {size}"),
+            VerifyError::Unsigned => write!(f, "image is not signed"),
+            VerifyError::BadSignature => write!(f, "image signature is not valid"),
+        }
+    }
+}
+
+fn write_hex(f: &mut fmt::Formatter<'_>, bytes: &[u8]) -> fmt::Result {
+    bytes.iter().try_for_each(|byte| write!(f, "{byte:02x}"))
+}
+
+impl ImageHeader {
+    pub fn for_payload(payload: &[u8]) -> Self {
+        ImageHeader {
+            payload_size: payload.len() as u32,
+            crc32: crc32(payload),
+            sha256: sha256(payload),
+            signature: None,
+        }
+    }
+
+    /// Size of the header as written by `to_bytes`.
+    pub fn size(&self) -> usize {
+        if self.signature.is_some() {
+            SIGNED_HEADER_SIZE
+        } else {
+            HEADER_SIZE
+        }
+    }
+
+    /// Parses the header at the start of `image`, or returns `None` if it doesn't carry one.
+    pub fn parse(image: &[u8]) -> Option<Result<Self, VerifyError>> {
+        if image.len() < HEADER_SIZE || image[..4] != MAGIC {
+            return None;
+        }
+
+        let version = u16::from_le_bytes([image[4], image[5]]);
+        if version != VERSION {
+            return Some(Err(VerifyError::UnsupportedVersion(version)));
+        }
+
+        let read_u32 =
+            |offset: usize| u32::from_le_bytes(image[offset..offset + 4].try_into().unwrap());
+        let mut header = ImageHeader {
+            payload_size: read_u32(8),
+            crc32: read_u32(12),
+            sha256: image[16..48].try_into().unwrap(),
+            signature: None,
+        };
+
+        if read_u32(48) & FLAG_SIGNED != 0 {
+            let Some(signature) = image.get(HEADER_SIZE..SIGNED_HEADER_SIZE) else {
+                return Some(Err(VerifyError::Truncated {
+                    expected: SIGNED_HEADER_SIZE,
+                    actual: image.len(),
+                }));
+            };
+            header.signature = Some(signature.try_into().unwrap());
+        }
+        Some(Ok(header))
+    }
+
+    /// Serializes the header, of which the first `size()` bytes are meaningful.
+    pub fn to_bytes(&self) -> [u8; SIGNED_HEADER_SIZE] {
+        let mut bytes = [0u8; SIGNED_HEADER_SIZE];
+        bytes[..4].copy_from_slice(&MAGIC);
+        bytes[4..6].copy_from_slice(&VERSION.to_le_bytes());
+        bytes[6..8].copy_from_slice(&(self.size() as u16).to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.payload_size.to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.crc32.to_le_bytes());
+        bytes[16..48].copy_from_slice(&self.sha256);
+        if let Some(signature) = &self.signature {
+            bytes[48..52].copy_from_slice(&FLAG_SIGNED.to_le_bytes());
+            bytes[HEADER_SIZE..SIGNED_HEADER_SIZE].copy_from_slice(signature);
+        }
+        bytes
+    }
+
+    /// Checks `payload` against both digests in the header.
+    pub fn verify(&self, payload: &[u8]) -> Result<(), VerifyError> {
+        if payload.len() != self.payload_size as usize {
+            return Err(VerifyError::Truncated {
+                expected: self.payload_size as usize,
+                actual: payload.len(),
+            });
+        }
+        verify_digest(payload, Digest::Crc32(self.crc32))?;
+        verify_digest(payload, Digest::Sha256(self.sha256))
+    }
+
+    /// Checks the header's signature of `payload` against `public_key`.
+    pub fn verify_signature(
+        &self,
+        payload: &[u8],
+        public_key: &[u8; PUBLIC_KEY_SIZE],
+    ) -> Result<(), VerifyError> {
+        let signature = self.signature.ok_or(VerifyError::Unsigned)?;
+        PublicKey::new(*public_key)
+            .verify(payload, &Signature::new(signature))
+            .map_err(|_| VerifyError::BadSignature)
+    }
+}
+
+pub fn verify_digest(payload: &[u8], digest: Digest) -> Result<(), VerifyError> {
+    match digest {
+        Digest::Crc32(expected) => {
+            let actual = crc32(payload);
+            if actual != expected {
+                return Err(VerifyError::Crc32Mismatch { expected, actual });
+            }
+        }
+        Digest::Sha256(expected) => {
+            let actual = sha256(payload);
+            if actual != expected {
+                return Err(VerifyError::Sha256Mismatch { expected, actual });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Verifies a loaded image and returns the range of its payload. Images with a header are
+/// checked against it, images without one are passed through whole unless `trusted_key` is
+/// given, in which case only images carrying a valid signature by it are accepted.
+pub fn verify_image(
+    image: &[u8],
+    trusted_key: Option<&[u8; PUBLIC_KEY_SIZE]>,
+) -> Result<core::ops::Range<usize>, VerifyError> {
+    let Some(header) = ImageHeader::parse(image) else {
+        return match trusted_key {
+            Some(_) => Err(VerifyError::Unsigned),
+            None => Ok(0..image.len()),
+        };
+    };
+    let header = header?;
+
+    let header_size = u16::from_le_bytes([image[6], image[7]]);
+    if (header_size as usize) < header.size() {
+        return Err(VerifyError::BadHeaderSize(header_size));
+    }
+    let payload = image.get(header_size as usize..).unwrap_or_default();
+    header.verify(payload)?;
+    if let Some(key) = trusted_key {
+        header.verify_signature(payload, key)?;
+    }
+    Ok(header_size as usize..image.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::vec::Vec;
+
+    use ed25519_compact::{KeyPair, Seed};
+
+    fn wrap_header(header: ImageHeader, payload: &[u8]) -> Vec<u8> {
+        let mut image = header.to_bytes()[..header.size()].to_vec();
+        image.extend_from_slice(payload);
+        image
+    }
+
+    fn wrap(payload: &[u8]) -> Vec<u8> {
+        wrap_header(ImageHeader::for_payload(payload), payload)
+    }
+
+    fn sign(key_pair: &KeyPair, payload: &[u8]) -> Vec<u8> {
+        let mut header = ImageHeader::for_payload(payload);
+        header.signature = Some(*key_pair.sk.sign(payload, None));
+        wrap_header(header, payload)
+    }
+
+    #[test]
+    fn test_header_round_trip_and_verify() {
+        let payload: Vec<u8> = (0..5000u32).map(|i| (i * 31) as u8).collect();
+        let image = wrap(&payload);
+
+        let header = ImageHeader::parse(&image).unwrap().unwrap();
+        assert_eq!(header, ImageHeader::for_payload(&payload));
+        assert_eq!(verify_image(&image, None), Ok(HEADER_SIZE..image.len()));
+
+        // Without a header the image passes through untouched
+        assert_eq!(verify_image(&payload, None), Ok(0..payload.len()));
+    }
+
+    #[test]
+    fn test_verify_rejects_corrupt_and_truncated_payloads() {
+        let payload = [0x5Au8; 300];
+        let mut image = wrap(&payload);
+
+        image[HEADER_SIZE + 100] ^= 0x80;
+        assert!(matches!(
+            verify_image(&image, None),
+            Err(VerifyError::Crc32Mismatch { .. })
+        ));
+
+        image[HEADER_SIZE + 100] ^= 0x80;
+        image.pop();
+        assert_eq!(
+            verify_image(&image, None),
+            Err(VerifyError::Truncated {
+                expected: 300,
+                actual: 299
+            })
+        );
+    }
+
+    #[test]
+    fn test_verify_sidecar_sha256() {
+        let payload = b"abc";
+        assert_eq!(
+            verify_digest(payload, Digest::Sha256(sha256(payload))),
+            Ok(())
+        );
+
+        let err = verify_digest(b"abd", Digest::Sha256(sha256(payload))).unwrap_err();
+        let message = std::format!("{err}");
+        assert!(
+            message.contains("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad")
+        );
+    }
+
+    #[test]
+    fn test_signed_image_accepted_only_with_matching_key() {
+        let key_pair = KeyPair::from_seed(Seed::new([7; 32]));
+        let other = KeyPair::from_seed(Seed::new([8; 32]));
+        let payload: Vec<u8> = (0..3000u32).map(|i| (i * 13) as u8).collect();
+        let image = sign(&key_pair, &payload);
+
+        let header = ImageHeader::parse(&image).unwrap().unwrap();
+        assert!(header.signature.is_some());
+        assert_eq!(
+            verify_image(&image, Some(&*key_pair.pk)),
+            Ok(SIGNED_HEADER_SIZE..image.len())
+        );
+        assert_eq!(
+            verify_image(&image, Some(&*other.pk)),
+            Err(VerifyError::BadSignature)
+        );
+
+        // Unsigned images, with or without a header, are refused once a key is trusted
+        assert_eq!(
+            verify_image(&wrap(&payload), Some(&*key_pair.pk)),
+            Err(VerifyError::Unsigned)
+        );
+        assert_eq!(
+            verify_image(&payload, Some(&*key_pair.pk)),
+            Err(VerifyError::Unsigned)
+        );
+    }
+
+    #[test]
+    fn test_signature_rejects_modified_payload_with_fixed_digests() {
+        let key_pair = KeyPair::from_seed(Seed::new([7; 32]));
+        let image = sign(&key_pair, b"original kernel");
+
+        // Re-digesting a modified payload keeps the CRC and SHA-256 checks happy, but the
+        // signature still covers the original
+        let modified = b"modified kernel";
+        let mut header = ImageHeader::parse(&image).unwrap().unwrap();
+        header.crc32 = crc32(modified);
+        header.sha256 = sha256(modified);
+        let forged = wrap_header(header, modified);
+
+        assert_eq!(
+            verify_image(&forged, None),
+            Ok(SIGNED_HEADER_SIZE..forged.len())
+        );
+        assert_eq!(
+            verify_image(&forged, Some(&*key_pair.pk)),
+            Err(VerifyError::BadSignature)
+        );
+    }
+}
@@ -0,0 +1,198 @@
+//! Container for sending a kernel together with the files it boots with.
+//!
+//! Layout, integers little endian:
+//!
+//! | Offset     | Size | Field                                       |
+//! |------------|------|---------------------------------------------|
+//! | 0          | 4    | `MAGIC`                                     |
+//! | 4          | 4    | entry count, at most `MAX_ENTRIES`          |
+//! | 8 + 16 * i | 16   | entry i: kind, offset, size, reserved (u32) |
+//!
+//! Entry data follows the table, each part starting on an `ALIGN` boundary.
+
+use core::fmt;
+
+pub const MAGIC: [u8; 4] = *b"RPIB";
+pub const MAX_ENTRIES: usize = 4;
+pub const ALIGN: usize = 8;
+
+const ENTRY_SIZE: usize = 16;
+const TABLE_OFFSET: usize = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u32)]
+pub enum Kind {
+    Kernel = 1,
+    DeviceTree = 2,
+    Initrd = 3,
+}
+
+impl Kind {
+    fn from_u32(value: u32) -> Option<Self> {
+        match value {
+            1 => Some(Kind::Kernel),
+            2 => Some(Kind::DeviceTree),
+            3 => Some(Kind::Initrd),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BundleError {
+    TooManyEntries(usize),
+    UnknownKind(u32),
+    EntryOutOfBounds(Kind),
+}
+
+impl fmt::Display for BundleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BundleError::TooManyEntries(count) => write!(f, "bundle has {count} entries"),
+            BundleError::UnknownKind(kind) => write!(f, "unknown bundle entry kind {kind}"),
+            BundleError::EntryOutOfBounds(kind) => {
+                write!(f, "bundle entry {kind:?} extends past the end")
+            }
+        }
+    }
+}
+
+pub fn is_bundle(data: &[u8]) -> bool {
+    data.starts_with(&MAGIC)
+}
+
+pub struct Bundle<'a> {
+    entries: [Option<(Kind, &'a [u8])>; MAX_ENTRIES],
+}
+
+impl<'a> Bundle<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self, BundleError> {
+        let read_u32 = |offset: usize| {
+            data.get(offset..offset + 4)
+                .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+        };
+
+        let count = read_u32(4).unwrap_or(0) as usize;
+        if count > MAX_ENTRIES {
+            return Err(BundleError::TooManyEntries(count));
+        }
+
+        let mut entries = [None; MAX_ENTRIES];
+        for (i, entry) in entries.iter_mut().take(count).enumerate() {
+            let base = TABLE_OFFSET + i * ENTRY_SIZE;
+            let kind_value = read_u32(base).unwrap_or(0);
+            let kind = Kind::from_u32(kind_value).ok_or(BundleError::UnknownKind(kind_value))?;
+            let part = read_u32(base + 4)
+                .zip(read_u32(base + 8))
+                .and_then(|(offset, size)| {
+                    let offset = offset as usize;
+                    data.get(offset..offset.checked_add(size as usize)?)
+                })
+                .ok_or(BundleError::EntryOutOfBounds(kind))?;
+            *entry = Some((kind, part));
+        }
+
+        Ok(Bundle { entries })
+    }
+
+    /// The first entry of `kind`, if the bundle has one.
+    pub fn get(&self, kind: Kind) -> Option<&'a [u8]> {
+        self.entries
+            .iter()
+            .flatten()
+            .find(|(entry_kind, _)| *entry_kind == kind)
+            .map(|(_, data)| *data)
+    }
+}
+
+/// Size of the bundle `encode` produces for `parts`.
+pub fn encoded_len(parts: &[(Kind, &[u8])]) -> usize {
+    parts
+        .iter()
+        .fold(data_offset(parts.len()), |end, (_, data)| {
+            end.next_multiple_of(ALIGN) + data.len()
+        })
+}
+
+/// Writes a bundle of `parts` into `out`, which must be `encoded_len(parts)` bytes long
+/// and zeroed. Returns `None` if there are too many parts or `out` is the wrong size.
+pub fn encode(parts: &[(Kind, &[u8])], out: &mut [u8]) -> Option<()> {
+    if parts.len() > MAX_ENTRIES || out.len() != encoded_len(parts) {
+        return None;
+    }
+
+    out[..4].copy_from_slice(&MAGIC);
+    out[4..8].copy_from_slice(&(parts.len() as u32).to_le_bytes());
+
+    let mut offset = data_offset(parts.len());
+    for (i, (kind, data)) in parts.iter().enumerate() {
+        offset = offset.next_multiple_of(ALIGN);
+        let entry = TABLE_OFFSET + i * ENTRY_SIZE;
+        out[entry..entry + 4].copy_from_slice(&(*kind as u32).to_le_bytes());
+        out[entry + 4..entry + 8].copy_from_slice(&(offset as u32).to_le_bytes());
+        out[entry + 8..entry + 12].copy_from_slice(&(data.len() as u32).to_le_bytes());
+        out[offset..offset + data.len()].copy_from_slice(data);
+        offset += data.len();
+    }
+    Some(())
+}
+
+fn data_offset(count: usize) -> usize {
+    TABLE_OFFSET + count * ENTRY_SIZE
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::vec;
+
+    #[test]
+    fn test_encode_parse_round_trip() {
+        let kernel = [0x11u8; 13];
+        let dtb = [0x22u8; 7];
+        let parts = [(Kind::Kernel, &kernel[..]), (Kind::DeviceTree, &dtb[..])];
+        let mut out = vec![0u8; encoded_len(&parts)];
+        encode(&parts, &mut out).unwrap();
+
+        assert!(is_bundle(&out));
+        let bundle = Bundle::parse(&out).unwrap();
+        assert_eq!(bundle.get(Kind::Kernel), Some(&kernel[..]));
+        assert_eq!(bundle.get(Kind::DeviceTree), Some(&dtb[..]));
+        assert_eq!(bundle.get(Kind::Initrd), None);
+
+        let parsed_dtb = bundle.get(Kind::DeviceTree).unwrap();
+        assert_eq!(
+            (parsed_dtb.as_ptr() as usize - out.as_ptr() as usize) % ALIGN,
+            0
+        );
+    }
+
+    #[test]
+    fn test_parse_rejects_bad_tables() {
+        let kernel = [0u8; 16];
+        let parts = [(Kind::Kernel, &kernel[..])];
+        let mut out = vec![0u8; encoded_len(&parts)];
+        encode(&parts, &mut out).unwrap();
+
+        let mut truncated = out.clone();
+        truncated.truncate(out.len() - 1);
+        assert_eq!(
+            Bundle::parse(&truncated).err(),
+            Some(BundleError::EntryOutOfBounds(Kind::Kernel))
+        );
+
+        let mut unknown = out.clone();
+        unknown[8] = 9;
+        assert_eq!(
+            Bundle::parse(&unknown).err(),
+            Some(BundleError::UnknownKind(9))
+        );
+
+        out[4] = 5;
+        assert_eq!(
+            Bundle::parse(&out).err(),
+            Some(BundleError::TooManyEntries(5))
+        );
+    }
+}
@@ -0,0 +1,219 @@
+//! Cache maintenance for memory other bus masters or a kernel read and write.
+//!
+//! With the MMU on, RAM is cached write-back, so the VideoCore, DMA and kernels started
+//! with their caches off don't see what the CPU wrote until it is cleaned to memory, and
+//! the CPU may keep reading stale lines of memory they wrote until those are invalidated.
+//! Code written through the data side also needs the instruction cache invalidated before
+//! it runs. Range operations work by virtual address, which is the physical address under
+//! the identity map.
+
+#[cfg(target_arch = "aarch64")]
+use core::arch::{asm, global_asm};
+
+/// Line size assumed off target, where the maintenance itself does nothing
+#[cfg(not(target_arch = "aarch64"))]
+const HOST_LINE_SIZE: usize = 64;
+
+/// Smallest data cache line, from CTR_EL0.
+fn dcache_line_size() -> usize {
+    #[cfg(target_arch = "aarch64")]
+    {
+        let ctr: u64;
+        // SAFETY: Reading CTR_EL0 has no side effects
+        unsafe { asm!("mrs {}, CTR_EL0", out(reg) ctr) };
+        4 << ((ctr >> 16) & 0xF)
+    }
+
+    #[cfg(not(target_arch = "aarch64"))]
+    HOST_LINE_SIZE
+}
+
+/// Smallest instruction cache line, from CTR_EL0.
+fn icache_line_size() -> usize {
+    #[cfg(target_arch = "aarch64")]
+    {
+        let ctr: u64;
+        // SAFETY: As above
+        unsafe { asm!("mrs {}, CTR_EL0", out(reg) ctr) };
+        4 << (ctr & 0xF)
+    }
+
+    #[cfg(not(target_arch = "aarch64"))]
+    HOST_LINE_SIZE
+}
+
+/// Line addresses covering `len` bytes at `start`, each with whether the line also holds
+/// bytes outside them.
+fn lines(start: usize, len: usize, line: usize) -> impl Iterator<Item = (usize, bool)> {
+    let end = start.saturating_add(len);
+    (start & !(line - 1)..end)
+        .step_by(line)
+        .map(move |address| {
+            (
+                address,
+                address < start || address.saturating_add(line) > end,
+            )
+        })
+}
+
+macro_rules! line_op {
+    ($name:ident, $instruction:literal) => {
+        fn $name(address: usize) {
+            #[cfg(target_arch = "aarch64")]
+            // SAFETY: Maintenance by address only changes where the memory's contents
+            // live, callers decide whether dropping a line is safe
+            unsafe {
+                asm!($instruction, in(reg) address)
+            };
+
+            #[cfg(not(target_arch = "aarch64"))]
+            let _ = address;
+        }
+    };
+}
+
+line_op!(clean_line, "dc cvac, {}");
+line_op!(clean_line_to_pou, "dc cvau, {}");
+line_op!(invalidate_line, "dc ivac, {}");
+line_op!(clean_invalidate_line, "dc civac, {}");
+line_op!(invalidate_icache_line, "ic ivau, {}");
+
+/// Waits for maintenance to finish, and makes later instruction fetches see it.
+fn complete() {
+    #[cfg(target_arch = "aarch64")]
+    // SAFETY: Barriers only order memory accesses and maintenance
+    unsafe {
+        asm!("dsb sy", "isb")
+    };
+}
+
+/// Writes `len` bytes at `start` back to memory, for another bus master to read.
+pub fn clean_dcache_range(start: usize, len: usize) {
+    for (address, _) in lines(start, len, dcache_line_size()) {
+        clean_line(address);
+    }
+    complete();
+}
+
+/// Drops the cached copy of `len` bytes at `start`, so the next reads see what another
+/// bus master wrote there. Lines shared with bytes outside the range are cleaned first so
+/// their writes aren't lost.
+pub fn invalidate_dcache_range(start: usize, len: usize) {
+    for (address, partial) in lines(start, len, dcache_line_size()) {
+        if partial {
+            clean_invalidate_line(address);
+        } else {
+            invalidate_line(address);
+        }
+    }
+    complete();
+}
+
+/// Writes back, then drops, the cached copy of `len` bytes at `start`: for buffers the
+/// CPU hands to another bus master and reads the answer back from.
+pub fn clean_invalidate_dcache_range(start: usize, len: usize) {
+    for (address, _) in lines(start, len, dcache_line_size()) {
+        clean_invalidate_line(address);
+    }
+    complete();
+}
+
+/// Makes `len` bytes of code just written at `start` visible to instruction fetches on
+/// this core while the caches stay on.
+pub fn sync_icache_range(start: usize, len: usize) {
+    for (address, _) in lines(start, len, dcache_line_size()) {
+        clean_line_to_pou(address);
+    }
+    complete();
+    for (address, _) in lines(start, len, icache_line_size()) {
+        invalidate_icache_line(address);
+    }
+    complete();
+}
+
+/// Drops every line of this core's instruction cache.
+pub fn invalidate_icache() {
+    #[cfg(target_arch = "aarch64")]
+    // SAFETY: Instruction cache lines are never dirty
+    unsafe {
+        asm!("ic iallu", "dsb sy", "isb")
+    };
+}
+
+/// Writes back and drops every data cache line by set/way, down to the point of coherency.
+/// Only meaningful with the data cache off, or other cores could dirty lines again.
+pub fn clean_invalidate_dcache_all() {
+    #[cfg(target_arch = "aarch64")]
+    // SAFETY: Cleaning before invalidating loses nothing
+    unsafe {
+        asm!("bl __dcache_clean_invalidate_all", clobber_abi("C"))
+    };
+}
+
+// The set/way walk behind `clean_invalidate_dcache_all`. A leaf routine using only x0-x11
+// and no memory, so `mmu::disable` can call it between turning the data cache off and
+// touching the stack again.
+#[cfg(target_arch = "aarch64")]
+global_asm!(
+    ".global __dcache_clean_invalidate_all",
+    "__dcache_clean_invalidate_all:",
+    "    mrs x0, CLIDR_EL1",
+    // Level of coherency, times two to match the CSSELR level field
+    "    ubfx x3, x0, #24, #3",
+    "    lsl x3, x3, #1",
+    "    cbz x3, 5f",
+    "    mov x10, #0",
+    "1:  add x2, x10, x10, lsr #1",
+    "    lsr x1, x0, x2",
+    "    and x1, x1, #7",
+    // No data or unified cache at this level
+    "    cmp x1, #2",
+    "    b.lt 4f",
+    "    msr CSSELR_EL1, x10",
+    "    isb",
+    "    mrs x1, CCSIDR_EL1",
+    // Line size, way count and set count
+    "    and x2, x1, #7",
+    "    add x2, x2, #4",
+    "    ubfx x4, x1, #3, #10",
+    "    clz w5, w4",
+    "    ubfx x7, x1, #13, #15",
+    "2:  mov x9, x4",
+    "3:  lsl x6, x9, x5",
+    "    orr x11, x10, x6",
+    "    lsl x6, x7, x2",
+    "    orr x11, x11, x6",
+    "    dc cisw, x11",
+    "    subs x9, x9, #1",
+    "    b.ge 3b",
+    "    subs x7, x7, #1",
+    "    b.ge 2b",
+    "4:  add x10, x10, #2",
+    "    cmp x3, x10",
+    "    b.gt 1b",
+    "5:  msr CSSELR_EL1, xzr",
+    "    dsb sy",
+    "    isb",
+    "    ret",
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::vec::Vec;
+
+    #[test]
+    fn test_covers_every_line_of_a_range() {
+        let covered: Vec<_> = lines(0x1010, 0x80, 64).collect();
+        assert_eq!(covered, [(0x1000, true), (0x1040, false), (0x1080, true)]);
+
+        let covered: Vec<_> = lines(0x1000, 0x80, 64).collect();
+        assert_eq!(covered, [(0x1000, false), (0x1040, false)]);
+
+        // A mailbox buffer smaller than a line, with the stack on either side
+        let covered: Vec<_> = lines(0x7FF90, 32, 64).collect();
+        assert_eq!(covered, [(0x7FF80, true)]);
+        assert_eq!(lines(0x1000, 0, 64).count(), 0);
+    }
+}
@@ -0,0 +1,283 @@
+//! Receives a kernel image over serial and hands control to it.
+//!
+//! The exchange, all integers little endian:
+//!
+//! 1. Board sends `READY_MARKER`, repeated until the host answers.
+//! 2. Host sends the image size as a `u32` followed by its `checksum` as a `u32`.
+//! 3. Board answers `ACK`, or `SIZE_ERROR` if the image doesn't fit and gives up.
+//! 4. Host streams the image.
+//! 5. Board answers `ACK`, or `CHECKSUM_ERROR` if the image was corrupted in transit.
+//!
+//! A host that goes quiet for a whole retry interval partway through is given up on.
+
+use crate::crc32::{Crc32, crc32};
+use crate::serial::SerialInterface;
+
+pub const READY_MARKER: [u8; 3] = [0x03, 0x03, 0x03];
+pub const ACK: [u8; 2] = *b"OK";
+pub const SIZE_ERROR: [u8; 2] = *b"SZ";
+pub const CHECKSUM_ERROR: [u8; 2] = *b"CS";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Header {
+    pub size: u32,
+    pub checksum: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChainloadError {
+    TooLarge {
+        size: u32,
+        capacity: usize,
+    },
+    ChecksumMismatch {
+        expected: u32,
+        actual: u32,
+    },
+    /// The host stopped sending after `received` of `size` bytes
+    Timeout {
+        received: usize,
+        size: u32,
+    },
+}
+
+impl core::fmt::Display for ChainloadError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ChainloadError::TooLarge { size, capacity } => {
+                write!(f, "image of {size} bytes exceeds {capacity} byte load area")
+            }
+            ChainloadError::ChecksumMismatch { expected, actual } => {
+                write!(f, "checksum {actual:#010x} does not match {expected:#010x}")
+            }
+            ChainloadError::Timeout { received, size } => {
+                write!(f, "host stopped sending after {received} of {size} bytes")
+            }
+        }
+    }
+}
+
+/// CRC-32 of the whole image, computed on the board while bytes arrive.
+pub fn checksum(data: &[u8]) -> u32 {
+    crc32(data)
+}
+
+/// Announces readiness up to `attempts` times, polling for the host's header between
+/// announcements until `tick` reports the retry interval has passed. A header that stops
+/// arriving, such as a single byte of line noise, is dropped and no host is reported.
+pub fn wait_for_host<S: SerialInterface>(
+    serial: &mut S,
+    attempts: u32,
+    mut tick: impl FnMut() -> bool,
+) -> Option<Header> {
+    for _ in 0..attempts {
+        serial.write_bytes(&READY_MARKER);
+        if let Some(first) = read_byte_before(serial, &mut tick) {
+            let mut size = [first, 0, 0, 0];
+            let mut checksum = [0; 4];
+            for byte in size[1..].iter_mut().chain(checksum.iter_mut()) {
+                *byte = read_byte_within(serial, &mut tick)?;
+            }
+            return Some(Header {
+                size: u32::from_le_bytes(size),
+                checksum: u32::from_le_bytes(checksum),
+            });
+        }
+    }
+    None
+}
+
+/// Receives the image announced by `header` into the start of `dest`, acknowledging or
+/// rejecting it on the wire. Gives up if the host stops sending, with `tick` reporting
+/// retry intervals as for `wait_for_host`. Returns the image length.
+pub fn receive<S: SerialInterface>(
+    serial: &mut S,
+    header: Header,
+    dest: &mut [u8],
+    mut tick: impl FnMut() -> bool,
+) -> Result<usize, ChainloadError> {
+    let size = header.size as usize;
+    if size > dest.len() {
+        serial.write_bytes(&SIZE_ERROR);
+        return Err(ChainloadError::TooLarge {
+            size: header.size,
+            capacity: dest.len(),
+        });
+    }
+    serial.write_bytes(&ACK);
+
+    let mut crc = Crc32::new();
+    for (received, byte) in dest[..size].iter_mut().enumerate() {
+        *byte = read_byte_within(serial, &mut tick).ok_or(ChainloadError::Timeout {
+            received,
+            size: header.size,
+        })?;
+        crc.update_byte(*byte);
+    }
+
+    let actual = crc.finalize();
+    if actual != header.checksum {
+        serial.write_bytes(&CHECKSUM_ERROR);
+        return Err(ChainloadError::ChecksumMismatch {
+            expected: header.checksum,
+            actual,
+        });
+    }
+    serial.write_bytes(&ACK);
+    serial.flush();
+
+    Ok(size)
+}
+
+/// The next byte, unless `tick` reports the retry interval passed first.
+fn read_byte_before<S: SerialInterface>(
+    serial: &mut S,
+    tick: &mut impl FnMut() -> bool,
+) -> Option<u8> {
+    loop {
+        if let Some(byte) = serial.try_read_byte() {
+            return Some(byte);
+        }
+        if tick() {
+            return None;
+        }
+    }
+}
+
+/// The next byte of a transfer under way, unless nothing arrives for a whole retry
+/// interval. The first tick may only be an interval ending just after the previous byte.
+fn read_byte_within<S: SerialInterface>(
+    serial: &mut S,
+    tick: &mut impl FnMut() -> bool,
+) -> Option<u8> {
+    read_byte_before(serial, tick).or_else(|| read_byte_before(serial, tick))
+}
+
+/// Jumps to a kernel image that has been loaded at `entry`.
+///
+/// # Safety
+///
+/// `entry` must point at the first instruction of a complete AArch64 image, and nothing
+/// the image relies on may be overwritten by the caller's state.
+pub unsafe fn jump_to(entry: usize) -> ! {
+    #[cfg(target_arch = "aarch64")]
+    unsafe {
+        // The image was written through the data side, make sure it's what gets fetched.
+        // Kernels expect to be entered with interrupts masked.
+        core::arch::asm!("msr daifset, #0xf", "dsb sy", "ic iallu", "dsb sy", "isb");
+        let kernel: extern "C" fn() -> ! = core::mem::transmute(entry);
+        kernel()
+    }
+
+    #[cfg(not(target_arch = "aarch64"))]
+    panic!("Cannot jump to a kernel at {entry:#x} on this architecture");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::vec::Vec;
+
+    use crate::serial::mock::MockSerial;
+
+    fn host_transfer(image: &[u8]) -> Vec<u8> {
+        let mut input = Vec::new();
+        input.extend_from_slice(&(image.len() as u32).to_le_bytes());
+        input.extend_from_slice(&checksum(image).to_le_bytes());
+        input.extend_from_slice(image);
+        input
+    }
+
+    #[test]
+    fn test_wait_for_host_retries_then_gives_up() {
+        let mut serial = MockSerial::default();
+        let mut ticks = 0;
+        let header = wait_for_host(&mut serial, 3, || {
+            ticks += 1;
+            true
+        });
+
+        assert_eq!(header, None);
+        assert_eq!(ticks, 3);
+        assert_eq!(serial.tx, READY_MARKER.repeat(3));
+    }
+
+    #[test]
+    fn test_wait_for_host_drops_a_stray_byte() {
+        let mut serial = MockSerial::with_input(&[0x42]);
+        let mut ticks = 0;
+        let header = wait_for_host(&mut serial, 3, || {
+            ticks += 1;
+            true
+        });
+
+        assert_eq!(header, None);
+        assert_eq!(ticks, 2);
+        assert!(serial.rx.is_empty());
+    }
+
+    #[test]
+    fn test_receive_loads_image_and_acks() {
+        let image: Vec<u8> = (0..=255).cycle().take(1000).collect();
+        let mut serial = MockSerial::with_input(&host_transfer(&image));
+        let mut dest = [0u8; 2048];
+
+        let header = wait_for_host(&mut serial, 1, || false).unwrap();
+        assert_eq!(header.size, 1000);
+        assert_eq!(receive(&mut serial, header, &mut dest, || false), Ok(1000));
+
+        assert_eq!(&dest[..1000], &image[..]);
+        assert_eq!(serial.tx, [&READY_MARKER[..], &ACK, &ACK].concat());
+    }
+
+    #[test]
+    fn test_receive_rejects_oversized_and_corrupt_images() {
+        let image = [0xAAu8; 64];
+        let mut dest = [0u8; 32];
+        let mut serial = MockSerial::with_input(&host_transfer(&image));
+        let header = wait_for_host(&mut serial, 1, || false).unwrap();
+        assert_eq!(
+            receive(&mut serial, header, &mut dest, || false),
+            Err(ChainloadError::TooLarge {
+                size: 64,
+                capacity: 32
+            })
+        );
+        assert!(serial.tx.ends_with(&SIZE_ERROR));
+
+        let mut transfer = host_transfer(&image[..16]);
+        *transfer.last_mut().unwrap() ^= 0x01;
+        let mut serial = MockSerial::with_input(&transfer);
+        let header = wait_for_host(&mut serial, 1, || false).unwrap();
+        assert!(matches!(
+            receive(&mut serial, header, &mut dest, || false),
+            Err(ChainloadError::ChecksumMismatch { .. })
+        ));
+        assert!(serial.tx.ends_with(&CHECKSUM_ERROR));
+    }
+
+    #[test]
+    fn test_receive_gives_up_on_a_stalled_host() {
+        let image = [0x55u8; 64];
+        let transfer = host_transfer(&image);
+        let mut serial = MockSerial::with_input(&transfer[..8 + 40]);
+        let header = wait_for_host(&mut serial, 1, || false).unwrap();
+
+        // One tick on its own is only the interval rolling over between two bytes
+        let mut ticks = 0;
+        let mut dest = [0u8; 64];
+        assert_eq!(
+            receive(&mut serial, header, &mut dest, || {
+                ticks += 1;
+                true
+            }),
+            Err(ChainloadError::Timeout {
+                received: 40,
+                size: 64
+            })
+        );
+        assert_eq!(ticks, 2);
+        assert_eq!(&dest[..40], &image[..40]);
+    }
+}
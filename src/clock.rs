@@ -0,0 +1,268 @@
+//! Monotonic time from the ARM generic timer's system counter.
+//!
+//! `Instant` counts nanoseconds since the counter started, converted from counter ticks
+//! with 128-bit intermediates so nothing truncates or overflows whatever the counter
+//! frequency. Lengths of time are plain `core::time::Duration`s. The counter is read
+//! through `CounterSource`, so host tests can drive a `Clock` by hand.
+
+#[cfg(target_arch = "aarch64")]
+use core::arch::asm;
+use core::ops::{Add, AddAssign, Sub};
+use core::time::Duration;
+
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+/// A free-running counter and its frequency.
+pub trait CounterSource {
+    fn ticks(&self) -> u64;
+    /// Ticks per second
+    fn frequency(&self) -> u64;
+}
+
+/// The core's physical counter, `CNTPCT_EL0` at `CNTFRQ_EL0` Hz.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemCounter;
+
+impl CounterSource for SystemCounter {
+    fn ticks(&self) -> u64 {
+        #[cfg(target_arch = "aarch64")]
+        {
+            let value: u64;
+            // SAFETY: Reading CNTPCT_EL0 has no side effects, boot.s lets EL1 access it
+            unsafe { asm!("mrs {}, CNTPCT_EL0", out(reg) value) };
+            value
+        }
+
+        // There's no counter off target, tests use a `MockCounter` instead
+        #[cfg(not(target_arch = "aarch64"))]
+        0
+    }
+
+    fn frequency(&self) -> u64 {
+        #[cfg(target_arch = "aarch64")]
+        {
+            let value: u64;
+            // SAFETY: Reading CNTFRQ_EL0 has no side effects
+            unsafe { asm!("mrs {}, CNTFRQ_EL0", out(reg) value) };
+            value
+        }
+
+        // The BCM2711's 54 MHz crystal
+        #[cfg(not(target_arch = "aarch64"))]
+        54_000_000
+    }
+}
+
+/// Converts counter ticks at `frequency` Hz to a duration, rounding down.
+pub fn ticks_to_duration(ticks: u64, frequency: u64) -> Duration {
+    let nanos = ticks as u128 * NANOS_PER_SEC / frequency.max(1) as u128;
+    Duration::new(
+        (nanos / NANOS_PER_SEC) as u64,
+        (nanos % NANOS_PER_SEC) as u32,
+    )
+}
+
+/// Converts a duration to counter ticks at `frequency` Hz, rounding up so waits are never
+/// short. Saturates at `u64::MAX`.
+pub fn duration_to_ticks(duration: Duration, frequency: u64) -> u64 {
+    let ticks = (duration.as_nanos() * frequency as u128).div_ceil(NANOS_PER_SEC);
+    ticks.try_into().unwrap_or(u64::MAX)
+}
+
+/// A point in time, as nanoseconds since the counter started.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant {
+    nanos: u64,
+}
+
+impl Instant {
+    /// The current time on the system counter.
+    pub fn now() -> Self {
+        Clock::new(SystemCounter).now()
+    }
+
+    /// Time since the system counter read `self`.
+    pub fn elapsed(&self) -> Duration {
+        Self::now().saturating_duration_since(*self)
+    }
+
+    pub fn from_nanos(nanos: u64) -> Self {
+        Instant { nanos }
+    }
+
+    /// Time since the counter started, which is roughly since power on.
+    pub fn since_start(&self) -> Duration {
+        Duration::from_nanos(self.nanos)
+    }
+
+    pub fn as_nanos(&self) -> u64 {
+        self.nanos
+    }
+
+    pub fn as_micros(&self) -> u64 {
+        self.nanos / 1_000
+    }
+
+    pub fn as_millis(&self) -> u64 {
+        self.nanos / 1_000_000
+    }
+
+    pub fn checked_duration_since(&self, earlier: Instant) -> Option<Duration> {
+        self.nanos
+            .checked_sub(earlier.nanos)
+            .map(Duration::from_nanos)
+    }
+
+    /// Time from `earlier` to `self`, or zero if `earlier` is actually later.
+    pub fn saturating_duration_since(&self, earlier: Instant) -> Duration {
+        self.checked_duration_since(earlier).unwrap_or_default()
+    }
+
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        let nanos = u64::try_from(duration.as_nanos()).ok()?;
+        self.nanos.checked_add(nanos).map(Instant::from_nanos)
+    }
+
+    pub fn checked_sub(&self, duration: Duration) -> Option<Instant> {
+        let nanos = u64::try_from(duration.as_nanos()).ok()?;
+        self.nanos.checked_sub(nanos).map(Instant::from_nanos)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    /// Panics if the result doesn't fit, like `std::time::Instant`.
+    fn add(self, duration: Duration) -> Instant {
+        self.checked_add(duration)
+            .expect("overflow when adding duration to instant")
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, duration: Duration) {
+        *self = *self + duration;
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.saturating_duration_since(earlier)
+    }
+}
+
+/// Reads `Instant`s from a counter source.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Clock<S: CounterSource> {
+    source: S,
+}
+
+impl<S: CounterSource> Clock<S> {
+    pub const fn new(source: S) -> Self {
+        Clock { source }
+    }
+
+    pub fn source(&self) -> &S {
+        &self.source
+    }
+
+    pub fn now(&self) -> Instant {
+        let elapsed = ticks_to_duration(self.source.ticks(), self.source.frequency());
+        Instant::from_nanos(elapsed.as_nanos() as u64)
+    }
+
+    pub fn elapsed(&self, since: Instant) -> Duration {
+        self.now().saturating_duration_since(since)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod mock {
+    use super::CounterSource;
+
+    use core::cell::Cell;
+
+    /// Counter that only moves when a test advances it.
+    pub struct MockCounter {
+        ticks: Cell<u64>,
+        frequency: u64,
+    }
+
+    impl MockCounter {
+        pub fn new(frequency: u64) -> Self {
+            MockCounter {
+                ticks: Cell::new(0),
+                frequency,
+            }
+        }
+
+        pub fn advance(&self, ticks: u64) {
+            self.ticks.set(self.ticks.get() + ticks);
+        }
+
+        pub fn set(&self, ticks: u64) {
+            self.ticks.set(ticks);
+        }
+    }
+
+    impl CounterSource for &MockCounter {
+        fn ticks(&self) -> u64 {
+            self.ticks.get()
+        }
+
+        fn frequency(&self) -> u64 {
+            self.frequency
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::mock::MockCounter;
+    use super::*;
+
+    #[test]
+    fn test_converts_without_truncating_the_frequency() {
+        // 19.2 MHz, the counter rate on many other boards, isn't a whole number of MHz
+        assert_eq!(
+            ticks_to_duration(19_200_000, 19_200_000),
+            Duration::from_secs(1)
+        );
+        assert_eq!(ticks_to_duration(1, 19_200_000), Duration::from_nanos(52));
+        assert_eq!(duration_to_ticks(Duration::from_micros(1), 19_200_000), 20);
+        assert_eq!(duration_to_ticks(Duration::from_micros(1), 54_000_000), 54);
+        // Years of uptime at 54 MHz don't overflow the intermediates
+        let ten_years = 10 * 365 * 24 * 3600 * 54_000_000u64;
+        assert_eq!(
+            ticks_to_duration(ten_years, 54_000_000),
+            Duration::from_secs(10 * 365 * 24 * 3600)
+        );
+        assert_eq!(duration_to_ticks(Duration::MAX, 54_000_000), u64::MAX);
+    }
+
+    #[test]
+    fn test_mock_counter_drives_the_clock() {
+        let counter = MockCounter::new(54_000_000);
+        let clock = Clock::new(&counter);
+        let start = clock.now();
+        assert_eq!(start, Instant::from_nanos(0));
+
+        counter.advance(54 * 1_500);
+        let later = clock.now();
+        assert_eq!(later.as_micros(), 1_500);
+        assert_eq!(later.as_millis(), 1);
+        assert_eq!(clock.elapsed(start), Duration::from_micros(1_500));
+        assert_eq!(later - start, Duration::from_micros(1_500));
+        assert_eq!(start - later, Duration::ZERO);
+        assert_eq!(start.checked_duration_since(later), None);
+
+        assert_eq!(start + Duration::from_micros(1_500), later);
+        assert_eq!(later.checked_sub(Duration::from_millis(2)), None);
+        assert_eq!(
+            Instant::from_nanos(u64::MAX).checked_add(Duration::from_nanos(1)),
+            None
+        );
+    }
+}
@@ -0,0 +1,315 @@
+//! Boot configuration from `key=value` settings.
+//!
+//! Settings come from a config.txt style file, one per line with `#` comment lines, and from
+//! the kernel command line, where they are prefixed with `CMDLINE_PREFIX` so they can sit
+//! next to options meant for Linux:
+//!
+//! ```text
+//! resolution=1280x720
+//! font_scale=4
+//! foreground=#ebdbb2
+//! background=#282828
+//! boot=builtin
+//! baud=921600
+//! boot_delay=1
+//! rtc=none
+//! ```
+
+use core::fmt;
+
+/// Marks command line words meant for the bootloader, e.g. `bootloader.baud=921600`
+pub const CMDLINE_PREFIX: &str = "bootloader.";
+
+const MIN_RESOLUTION: (u32, u32) = (320, 240);
+const MAX_RESOLUTION: (u32, u32) = (3840, 2160);
+const MAX_FONT_SCALE: usize = 16;
+const MIN_BAUD: u32 = 1200;
+// The PL011 divides its 48 MHz clock by at least 16
+const MAX_BAUD: u32 = 3_000_000;
+const MAX_BOOT_DELAY_S: u32 = 60;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BootTarget {
+    /// Wait for a chainload host, then fall back to the built-in console
+    Chainload,
+    /// Go straight to the built-in console
+    Builtin,
+}
+
+/// Where the wall clock is read from at boot.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RtcKind {
+    /// Only set over the serial console
+    None,
+    /// A DS3231 on the header's I2C pins
+    Ds3231,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Config {
+    pub width: u32,
+    pub height: u32,
+    pub font_scale: usize,
+    pub foreground: u32,
+    pub background: u32,
+    pub boot_target: BootTarget,
+    pub serial_baud: u32,
+    /// Seconds to wait for a chainload host
+    pub boot_delay_s: u32,
+    pub rtc: RtcKind,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConfigErrorKind {
+    MissingValue,
+    UnknownKey,
+    InvalidValue,
+}
+
+/// A setting that was skipped, with the text it came from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ConfigError<'a> {
+    pub setting: &'a str,
+    pub kind: ConfigErrorKind,
+}
+
+impl fmt::Display for ConfigError<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self.kind {
+            ConfigErrorKind::MissingValue => "expected key=value",
+            ConfigErrorKind::UnknownKey => "unknown setting",
+            ConfigErrorKind::InvalidValue => "invalid value",
+        };
+        write!(f, "{}: {reason}", self.setting)
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config::DEFAULT
+    }
+}
+
+impl Config {
+    pub const DEFAULT: Config = Config {
+        width: 1920,
+        height: 1080,
+        font_scale: 8,
+        foreground: 0xD7D7D7,
+        background: 0x282828,
+        boot_target: BootTarget::Chainload,
+        serial_baud: 115_200,
+        boot_delay_s: 3,
+        rtc: RtcKind::Ds3231,
+    };
+
+    /// Applies the settings in a config file. Bad lines are passed to `on_error` and
+    /// skipped, leaving the setting as it was.
+    pub fn apply_file<'a>(&mut self, text: &'a str, mut on_error: impl FnMut(ConfigError<'a>)) {
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Err(kind) = self.apply_setting(line) {
+                on_error(ConfigError {
+                    setting: line,
+                    kind,
+                });
+            }
+        }
+    }
+
+    /// Applies the `CMDLINE_PREFIX`ed settings in a kernel command line, ignoring the
+    /// other words.
+    pub fn apply_cmdline<'a>(
+        &mut self,
+        cmdline: &'a str,
+        mut on_error: impl FnMut(ConfigError<'a>),
+    ) {
+        for word in cmdline.split_ascii_whitespace() {
+            let Some(setting) = word.strip_prefix(CMDLINE_PREFIX) else {
+                continue;
+            };
+            if let Err(kind) = self.apply_setting(setting) {
+                on_error(ConfigError {
+                    setting: word,
+                    kind,
+                });
+            }
+        }
+    }
+
+    fn apply_setting(&mut self, setting: &str) -> Result<(), ConfigErrorKind> {
+        let (key, value) = setting
+            .split_once('=')
+            .ok_or(ConfigErrorKind::MissingValue)?;
+        self.set(key.trim(), value.trim())
+    }
+
+    /// Sets one setting by name.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), ConfigErrorKind> {
+        let invalid = ConfigErrorKind::InvalidValue;
+        match key {
+            "resolution" => {
+                let (width, height) = value.split_once('x').ok_or(invalid)?;
+                let width = in_range(parse_number(width)?, MIN_RESOLUTION.0, MAX_RESOLUTION.0)?;
+                let height = in_range(parse_number(height)?, MIN_RESOLUTION.1, MAX_RESOLUTION.1)?;
+                (self.width, self.height) = (width, height);
+            }
+            "font_scale" => {
+                self.font_scale = in_range(parse_number(value)?, 1, MAX_FONT_SCALE as u32)? as usize
+            }
+            "foreground" => self.foreground = parse_color(value)?,
+            "background" => self.background = parse_color(value)?,
+            "boot" => {
+                self.boot_target = match value {
+                    "chainload" => BootTarget::Chainload,
+                    "builtin" => BootTarget::Builtin,
+                    _ => return Err(invalid),
+                }
+            }
+            "baud" => self.serial_baud = in_range(parse_number(value)?, MIN_BAUD, MAX_BAUD)?,
+            "boot_delay" => {
+                self.boot_delay_s = in_range(parse_number(value)?, 0, MAX_BOOT_DELAY_S)?
+            }
+            "rtc" => {
+                self.rtc = match value {
+                    "ds3231" => RtcKind::Ds3231,
+                    "none" => RtcKind::None,
+                    _ => return Err(invalid),
+                }
+            }
+            _ => return Err(ConfigErrorKind::UnknownKey),
+        }
+        Ok(())
+    }
+}
+
+/// Decimal, or hexadecimal with a `0x` prefix.
+fn parse_number(value: &str) -> Result<u32, ConfigErrorKind> {
+    match value.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => value.parse(),
+    }
+    .map_err(|_| ConfigErrorKind::InvalidValue)
+}
+
+/// `#rrggbb` or `0xrrggbb`.
+fn parse_color(value: &str) -> Result<u32, ConfigErrorKind> {
+    let hex = value
+        .strip_prefix('#')
+        .or_else(|| value.strip_prefix("0x"))
+        .filter(|hex| hex.len() == 6)
+        .ok_or(ConfigErrorKind::InvalidValue)?;
+    u32::from_str_radix(hex, 16).map_err(|_| ConfigErrorKind::InvalidValue)
+}
+
+fn in_range(value: u32, min: u32, max: u32) -> Result<u32, ConfigErrorKind> {
+    if (min..=max).contains(&value) {
+        Ok(value)
+    } else {
+        Err(ConfigErrorKind::InvalidValue)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::vec::Vec;
+
+    #[test]
+    fn test_applies_file_settings() {
+        let text = "# Console\n\
+                    resolution = 1280x720\n\
+                    font_scale=4\n\
+                      # Gruvbox\n\
+                    foreground=#EBDBB2\n\
+                    background=0x1d2021\n\
+                    \n\
+                    boot=builtin\n\
+                    baud=921600\n\
+                    boot_delay=0x0\n\
+                    rtc=none\n";
+        let mut config = Config::default();
+        config.apply_file(text, |err| panic!("unexpected error: {err}"));
+
+        assert_eq!(
+            config,
+            Config {
+                width: 1280,
+                height: 720,
+                font_scale: 4,
+                foreground: 0xEBDBB2,
+                background: 0x1D2021,
+                boot_target: BootTarget::Builtin,
+                serial_baud: 921_600,
+                boot_delay_s: 0,
+                rtc: RtcKind::None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_skips_malformed_settings() {
+        let text = "resolution=1280\n\
+                    resolution=100x100\n\
+                    font_scale=-1\n\
+                    font_scale=0\n\
+                    foreground=#fff\n\
+                    background=#gggggg\n\
+                    boot=network\n\
+                    baud=115200baud\n\
+                    boot_delay=99999999999\n\
+                    rtc=pcf8523\n\
+                    colour=#ffffff\n\
+                    baud\n\
+                    =3\n";
+        let mut config = Config::default();
+        let mut errors = Vec::new();
+        config.apply_file(text, |err| errors.push(err));
+
+        assert_eq!(config, Config::default());
+        let kinds: Vec<ConfigErrorKind> = errors.iter().map(|err| err.kind).collect();
+        use ConfigErrorKind::*;
+        assert_eq!(
+            kinds,
+            [
+                InvalidValue,
+                InvalidValue,
+                InvalidValue,
+                InvalidValue,
+                InvalidValue,
+                InvalidValue,
+                InvalidValue,
+                InvalidValue,
+                InvalidValue,
+                InvalidValue,
+                UnknownKey,
+                MissingValue,
+                UnknownKey
+            ]
+        );
+        assert_eq!(errors[0].setting, "resolution=1280");
+    }
+
+    #[test]
+    fn test_cmdline_only_reads_prefixed_words() {
+        let cmdline = "console=ttyS0,115200 bootloader.boot_delay=10 baud=9600 \
+                       bootloader.baud=9600 bootloader.nope=1 root=/dev/mmcblk0p2";
+        let mut config = Config::default();
+        let mut errors = Vec::new();
+        config.apply_cmdline(cmdline, |err| errors.push(err));
+
+        assert_eq!(config.boot_delay_s, 10);
+        assert_eq!(config.serial_baud, 9600);
+        assert_eq!(
+            errors,
+            [ConfigError {
+                setting: "bootloader.nope=1",
+                kind: ConfigErrorKind::UnknownKey
+            }]
+        );
+    }
+}
@@ -0,0 +1,302 @@
+//! Calendar dates and times, and the wall clock.
+//!
+//! There is no battery-backed clock on the Pi itself. The wall clock starts out unset and
+//! is set from an RTC or by hand; from then on it runs off the system counter, so reading
+//! it costs no bus traffic.
+
+use core::fmt;
+use core::str::FromStr;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+
+use crate::clock::Instant;
+
+const SECONDS_PER_DAY: u64 = 86_400;
+
+/// A UTC date and time, to the second.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InvalidDateTime;
+
+impl fmt::Display for InvalidDateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "expected a date and time like 2024-06-30 13:45:00")
+    }
+}
+
+fn is_leap_year(year: u16) -> bool {
+    year.is_multiple_of(4) && (!year.is_multiple_of(100) || year.is_multiple_of(400))
+}
+
+fn days_in_month(year: u16, month: u8) -> u8 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+impl DateTime {
+    /// Builds a date and time, checking every field is in range. Years before the Unix
+    /// epoch aren't supported.
+    pub fn new(
+        year: u16,
+        month: u8,
+        day: u8,
+        hour: u8,
+        minute: u8,
+        second: u8,
+    ) -> Result<Self, InvalidDateTime> {
+        let valid = (1970..=9999).contains(&year)
+            && (1..=12).contains(&month)
+            && (1..=days_in_month(year, month)).contains(&day)
+            && hour < 24
+            && minute < 60
+            && second < 60;
+        if !valid {
+            return Err(InvalidDateTime);
+        }
+        Ok(DateTime {
+            year,
+            month,
+            day,
+            hour,
+            minute,
+            second,
+        })
+    }
+
+    /// Seconds since 1970-01-01 00:00:00.
+    pub fn to_unix_seconds(&self) -> u64 {
+        // Days from the epoch by Howard Hinnant's days_from_civil, counting years from March
+        // so the leap day falls at the end
+        let year = self.year as i64 - (self.month <= 2) as i64;
+        let era = year.div_euclid(400);
+        let year_of_era = year - era * 400;
+        let month = self.month as i64;
+        let day_of_year =
+            (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + self.day as i64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = (era * 146_097 + day_of_era - 719_468) as u64;
+        days * SECONDS_PER_DAY
+            + self.hour as u64 * 3600
+            + self.minute as u64 * 60
+            + self.second as u64
+    }
+
+    /// The date and time `seconds` after 1970-01-01 00:00:00.
+    pub fn from_unix_seconds(seconds: u64) -> Self {
+        // civil_from_days, the inverse of the above
+        let days = (seconds / SECONDS_PER_DAY) as i64 + 719_468;
+        let era = days.div_euclid(146_097);
+        let day_of_era = days - era * 146_097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let shifted_month = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+        let month = if shifted_month < 10 {
+            shifted_month + 3
+        } else {
+            shifted_month - 9
+        };
+        let year = year_of_era + era * 400 + (month <= 2) as i64;
+
+        let time = seconds % SECONDS_PER_DAY;
+        DateTime {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (time / 3600) as u8,
+            minute: (time / 60 % 60) as u8,
+            second: (time % 60) as u8,
+        }
+    }
+
+    /// Day of the week, 0 for Sunday.
+    pub fn weekday(&self) -> u8 {
+        // 1970-01-01 was a Thursday
+        ((self.to_unix_seconds() / SECONDS_PER_DAY + 4) % 7) as u8
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+/// Parses `YYYY-MM-DD HH:MM:SS`, with a `T` accepted in place of the space as in ISO 8601.
+impl FromStr for DateTime {
+    type Err = InvalidDateTime;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let (date, time) = text.trim().split_once([' ', 'T']).ok_or(InvalidDateTime)?;
+        let mut date = date.split('-');
+        let mut time = time.trim_start().split(':');
+        let field = |parts: &mut core::str::Split<'_, char>| -> Result<u16, InvalidDateTime> {
+            let part = parts.next().ok_or(InvalidDateTime)?;
+            if part.is_empty() || !part.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(InvalidDateTime);
+            }
+            part.parse().map_err(|_| InvalidDateTime)
+        };
+        let year = field(&mut date)?;
+        let month = field(&mut date)?;
+        let day = field(&mut date)?;
+        let hour = field(&mut time)?;
+        let minute = field(&mut time)?;
+        let second = field(&mut time)?;
+        if date.next().is_some() || time.next().is_some() {
+            return Err(InvalidDateTime);
+        }
+        let narrow = |value: u16| u8::try_from(value).map_err(|_| InvalidDateTime);
+        DateTime::new(
+            year,
+            narrow(month)?,
+            narrow(day)?,
+            narrow(hour)?,
+            narrow(minute)?,
+            narrow(second)?,
+        )
+    }
+}
+
+/// Formats time since boot as `[Nd ]H:MM:SS`, with as many fractional digits as the
+/// format precision asks for, up to 9: `{:.3}` shows milliseconds.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Uptime(pub Duration);
+
+impl fmt::Display for Uptime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let seconds = self.0.as_secs();
+        let days = seconds / SECONDS_PER_DAY;
+        if days > 0 {
+            write!(f, "{days}d ")?;
+        }
+        write!(
+            f,
+            "{}:{:02}:{:02}",
+            seconds % SECONDS_PER_DAY / 3600,
+            seconds / 60 % 60,
+            seconds % 60
+        )?;
+        match f.precision().map(|digits| digits.min(9)) {
+            Some(digits) if digits > 0 => {
+                let fraction = self.0.subsec_nanos() / 10u32.pow(9 - digits as u32);
+                write!(f, ".{fraction:0digits$}")
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+// Unix time in nanoseconds when the system counter read zero, valid once WALL_CLOCK_SET
+static WALL_CLOCK_BASE_NS: AtomicU64 = AtomicU64::new(0);
+static WALL_CLOCK_SET: AtomicBool = AtomicBool::new(false);
+
+/// Sets the wall clock to `now`.
+pub fn set_wall_clock(now: DateTime) {
+    let unix_ns = now.to_unix_seconds().saturating_mul(1_000_000_000);
+    WALL_CLOCK_BASE_NS.store(
+        unix_ns.saturating_sub(Instant::now().as_nanos()),
+        Ordering::Relaxed,
+    );
+    WALL_CLOCK_SET.store(true, Ordering::Release);
+}
+
+/// The current date and time, or `None` until the wall clock has been set.
+pub fn wall_clock() -> Option<DateTime> {
+    if !WALL_CLOCK_SET.load(Ordering::Acquire) {
+        return None;
+    }
+    let unix_ns = WALL_CLOCK_BASE_NS.load(Ordering::Relaxed) + Instant::now().as_nanos();
+    Some(DateTime::from_unix_seconds(unix_ns / 1_000_000_000))
+}
+
+/// Timestamp for log lines: the wall clock once it is set, time since boot until then.
+pub struct Timestamp;
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match wall_clock() {
+            Some(now) => write!(f, "{now}"),
+            None => write!(f, "{:.3}", Uptime(Instant::now().since_start())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::string::ToString;
+
+    #[test]
+    fn test_converts_to_and_from_unix_time() {
+        let cases = [
+            (DateTime::new(1970, 1, 1, 0, 0, 0).unwrap(), 0),
+            (DateTime::new(2000, 2, 29, 12, 0, 0).unwrap(), 951_825_600),
+            (
+                DateTime::new(2024, 12, 31, 23, 59, 59).unwrap(),
+                1_735_689_599,
+            ),
+            (DateTime::new(2100, 3, 1, 0, 0, 0).unwrap(), 4_107_542_400),
+        ];
+        for (date, unix) in cases {
+            assert_eq!(date.to_unix_seconds(), unix, "{date}");
+            assert_eq!(DateTime::from_unix_seconds(unix), date);
+        }
+        // 2024-06-30 was a Sunday
+        assert_eq!(DateTime::new(2024, 6, 30, 0, 0, 0).unwrap().weekday(), 0);
+    }
+
+    #[test]
+    fn test_parses_and_formats() {
+        let date: DateTime = "2024-06-30 13:45:07".parse().unwrap();
+        assert_eq!(date, DateTime::new(2024, 6, 30, 13, 45, 7).unwrap());
+        assert_eq!(date.to_string(), "2024-06-30 13:45:07");
+        assert_eq!(
+            "2024-6-30T01:02:03".parse(),
+            DateTime::new(2024, 6, 30, 1, 2, 3)
+        );
+
+        for bad in [
+            "2024-06-30",
+            "2023-02-29 00:00:00",
+            "2024-06-30 24:00:00",
+            "2024-06-30 12:00",
+            "2024-06-30 12:00:00:00",
+            "2024-06-30 +1:00:00",
+            "1969-12-31 23:59:59",
+        ] {
+            assert_eq!(bad.parse::<DateTime>(), Err(InvalidDateTime), "{bad}");
+        }
+    }
+
+    #[test]
+    fn test_formats_uptime() {
+        let uptime = Uptime(Duration::from_millis(5_042));
+        assert_eq!(uptime.to_string(), "0:00:05");
+        assert_eq!(std::format!("{uptime:.3}"), "0:00:05.042");
+        assert_eq!(std::format!("{uptime:.1}"), "0:00:05.0");
+        assert_eq!(
+            Uptime(Duration::from_secs(2 * 86_400 + 3 * 3600 + 4 * 60 + 5)).to_string(),
+            "2d 3:04:05"
+        );
+    }
+}
@@ -0,0 +1,196 @@
+//! Maxim DS3231 real-time clock, as found on most Pi RTC HATs.
+
+use core::fmt;
+
+use crate::datetime::DateTime;
+use crate::i2c::{I2cBus, I2cError};
+
+/// The DS3231's fixed bus address
+pub const DS3231_ADDR: u8 = 0x68;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_STATUS: u8 = 0x0F;
+
+const HOUR_12H: u8 = 1 << 6;
+const HOUR_PM: u8 = 1 << 5;
+const MONTH_CENTURY: u8 = 1 << 7;
+/// Set when the oscillator stopped, e.g. the battery ran flat, so the time is stale
+const STATUS_OSF: u8 = 1 << 7;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RtcError {
+    I2c(I2cError),
+    /// The clock lost power and hasn't been set since
+    NotSet,
+    /// The registers hold something that isn't a date
+    Invalid,
+}
+
+impl From<I2cError> for RtcError {
+    fn from(err: I2cError) -> Self {
+        RtcError::I2c(err)
+    }
+}
+
+impl fmt::Display for RtcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RtcError::I2c(err) => write!(f, "{err}"),
+            RtcError::NotSet => write!(f, "clock not set since it lost power"),
+            RtcError::Invalid => write!(f, "invalid time in the clock registers"),
+        }
+    }
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0F)
+}
+
+fn to_bcd(value: u8) -> u8 {
+    ((value / 10) << 4) | (value % 10)
+}
+
+pub struct Ds3231<B: I2cBus> {
+    bus: B,
+}
+
+impl<B: I2cBus> Ds3231<B> {
+    pub const fn new(bus: B) -> Self {
+        Ds3231 { bus }
+    }
+
+    pub fn read_time(&mut self) -> Result<DateTime, RtcError> {
+        let mut status = [0];
+        self.bus
+            .write_read(DS3231_ADDR, &[REG_STATUS], &mut status)?;
+        if status[0] & STATUS_OSF != 0 {
+            return Err(RtcError::NotSet);
+        }
+
+        let mut regs = [0; 7];
+        self.bus
+            .write_read(DS3231_ADDR, &[REG_SECONDS], &mut regs)?;
+        let [seconds, minutes, hours, _weekday, day, month, year] = regs;
+        let hour = if hours & HOUR_12H != 0 {
+            let hour = from_bcd(hours & 0x1F) % 12;
+            if hours & HOUR_PM != 0 {
+                hour + 12
+            } else {
+                hour
+            }
+        } else {
+            from_bcd(hours & 0x3F)
+        };
+        let century = if month & MONTH_CENTURY != 0 {
+            2100
+        } else {
+            2000
+        };
+        DateTime::new(
+            century + from_bcd(year) as u16,
+            from_bcd(month & 0x1F),
+            from_bcd(day & 0x3F),
+            hour,
+            from_bcd(minutes & 0x7F),
+            from_bcd(seconds & 0x7F),
+        )
+        .map_err(|_| RtcError::Invalid)
+    }
+
+    /// Sets the clock, in 24 hour mode, and clears the oscillator stop flag. The DS3231
+    /// covers 2000 to 2199.
+    pub fn set_time(&mut self, time: &DateTime) -> Result<(), RtcError> {
+        if !(2000..2200).contains(&time.year) {
+            return Err(RtcError::Invalid);
+        }
+        let century = if time.year >= 2100 { MONTH_CENTURY } else { 0 };
+        self.bus.write(
+            DS3231_ADDR,
+            &[
+                REG_SECONDS,
+                to_bcd(time.second),
+                to_bcd(time.minute),
+                to_bcd(time.hour),
+                // The DS3231 counts weekdays 1 to 7
+                time.weekday() + 1,
+                to_bcd(time.day),
+                to_bcd(time.month) | century,
+                to_bcd((time.year % 100) as u8),
+            ],
+        )?;
+
+        let mut status = [0];
+        self.bus
+            .write_read(DS3231_ADDR, &[REG_STATUS], &mut status)?;
+        self.bus
+            .write(DS3231_ADDR, &[REG_STATUS, status[0] & !STATUS_OSF])?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Register file behind a DS3231 address pointer, like the real chip.
+    struct MockRtc {
+        regs: [u8; 0x13],
+        pointer: usize,
+    }
+
+    impl I2cBus for MockRtc {
+        fn write(&mut self, addr: u8, bytes: &[u8]) -> Result<(), I2cError> {
+            if addr != DS3231_ADDR {
+                return Err(I2cError::Nack(addr));
+            }
+            self.pointer = bytes[0] as usize;
+            for &byte in &bytes[1..] {
+                self.regs[self.pointer] = byte;
+                self.pointer += 1;
+            }
+            Ok(())
+        }
+
+        fn read(&mut self, _addr: u8, buf: &mut [u8]) -> Result<(), I2cError> {
+            for byte in buf {
+                *byte = self.regs[self.pointer];
+                self.pointer += 1;
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_reads_bcd_time() {
+        let mut regs = [0; 0x13];
+        // 2024-06-30 01:45:07 PM in 12 hour mode
+        regs[..7].copy_from_slice(&[0x07, 0x45, HOUR_12H | HOUR_PM | 0x01, 1, 0x30, 0x06, 0x24]);
+        let mut rtc = Ds3231::new(MockRtc { regs, pointer: 0 });
+        assert_eq!(
+            rtc.read_time(),
+            DateTime::new(2024, 6, 30, 13, 45, 7).map_err(|_| RtcError::Invalid)
+        );
+
+        rtc.bus.regs[REG_STATUS as usize] = STATUS_OSF;
+        assert_eq!(rtc.read_time(), Err(RtcError::NotSet));
+    }
+
+    #[test]
+    fn test_sets_time_and_clears_stop_flag() {
+        let mut rtc = Ds3231::new(MockRtc {
+            regs: [0; 0x13],
+            pointer: 0,
+        });
+        rtc.bus.regs[REG_STATUS as usize] = STATUS_OSF | 0b1000;
+
+        // A Sunday, weekday 1 to the DS3231
+        let time = DateTime::new(2101, 1, 2, 23, 59, 58).unwrap();
+        rtc.set_time(&time).unwrap();
+        assert_eq!(&rtc.bus.regs[..7], [0x58, 0x59, 0x23, 1, 0x02, 0x81, 0x01]);
+        assert_eq!(rtc.bus.regs[REG_STATUS as usize], 0b1000);
+        assert_eq!(rtc.read_time(), Ok(time));
+
+        let too_early = DateTime::new(1999, 1, 1, 0, 0, 0).unwrap();
+        assert_eq!(rtc.set_time(&too_early), Err(RtcError::Invalid));
+    }
+}
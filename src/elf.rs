@@ -0,0 +1,414 @@
+//! Loads statically linked AArch64 ELF64 executables.
+//!
+//! Only what booting needs is supported: the file header is validated, every `PT_LOAD`
+//! segment is copied to its physical address (`p_paddr`) with the rest of its memory size
+//! zeroed, and the physical address of the entry point is returned.
+
+use core::fmt;
+use core::ops::Range;
+
+use crate::cache;
+
+const MAGIC: [u8; 4] = *b"\x7fELF";
+const CLASS_64: u8 = 2;
+const DATA_LITTLE_ENDIAN: u8 = 1;
+const VERSION_CURRENT: u8 = 1;
+
+pub const TYPE_EXEC: u16 = 2;
+pub const MACHINE_AARCH64: u16 = 183;
+
+const FILE_HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+const PT_LOAD: u32 = 1;
+pub const PF_X: u32 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ElfError {
+    NotElf,
+    UnsupportedFormat,
+    WrongMachine(u16),
+    NotExecutable(u16),
+    BadProgramHeaders,
+    SegmentOutsideFile {
+        index: usize,
+    },
+    SegmentNotLoadable {
+        index: usize,
+        addr: u64,
+        size: u64,
+    },
+    /// The entry point isn't inside an executable segment
+    BadEntry(u64),
+}
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ElfError::NotElf => write!(f, "not an ELF file"),
+            ElfError::UnsupportedFormat => write!(f, "not a little endian ELF64 file"),
+            ElfError::WrongMachine(machine) => write!(f, "ELF machine {machine} is not AArch64"),
+            ElfError::NotExecutable(kind) => write!(f, "ELF type {kind} is not an executable"),
+            ElfError::BadProgramHeaders => write!(f, "bad ELF program header table"),
+            ElfError::SegmentOutsideFile { index } => {
+                write!(f, "segment {index} extends past the end of the file")
+            }
+            ElfError::SegmentNotLoadable { index, addr, size } => {
+                write!(
+                    f,
+                    "segment {index} at {addr:#x} ({size} bytes) is not loadable"
+                )
+            }
+            ElfError::BadEntry(entry) => {
+                write!(f, "entry point {entry:#x} is not in an executable segment")
+            }
+        }
+    }
+}
+
+/// Physical memory that segments are loaded into.
+pub trait LoadMemory {
+    /// Returns `len` bytes of memory at physical address `addr`, or `None` if the range
+    /// can't be loaded to.
+    fn region(&mut self, addr: u64, len: usize) -> Option<&mut [u8]>;
+}
+
+/// Physical RAM loaded through the data cache. Dropping it writes everything loaded back to
+/// memory and invalidates the instruction cache, so the loaded code can run, or be handed
+/// to a kernel that starts with its caches off.
+pub struct PhysicalMemory {
+    range: Range<u64>,
+    touched: Option<Range<u64>>,
+}
+
+impl PhysicalMemory {
+    /// # Safety
+    ///
+    /// `range` must be RAM that nothing else uses, including the ELF file being loaded.
+    pub unsafe fn new(range: Range<u64>) -> Self {
+        PhysicalMemory {
+            range,
+            touched: None,
+        }
+    }
+}
+
+impl LoadMemory for PhysicalMemory {
+    fn region(&mut self, addr: u64, len: usize) -> Option<&mut [u8]> {
+        let end = addr.checked_add(len as u64)?;
+        if addr < self.range.start || end > self.range.end {
+            return None;
+        }
+        self.touched = Some(match self.touched.take() {
+            Some(touched) => touched.start.min(addr)..touched.end.max(end),
+            None => addr..end,
+        });
+        Some(unsafe { core::slice::from_raw_parts_mut(addr as *mut u8, len) })
+    }
+}
+
+impl Drop for PhysicalMemory {
+    fn drop(&mut self) {
+        if let Some(touched) = self.touched.take() {
+            cache::clean_dcache_range(
+                touched.start as usize,
+                (touched.end - touched.start) as usize,
+            );
+            cache::invalidate_icache();
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Segment {
+    pub offset: u64,
+    pub virt_addr: u64,
+    pub phys_addr: u64,
+    pub file_size: u64,
+    pub mem_size: u64,
+    pub flags: u32,
+}
+
+pub struct ElfFile<'a> {
+    data: &'a [u8],
+    entry: u64,
+    program_headers: &'a [u8],
+}
+
+pub fn is_elf(data: &[u8]) -> bool {
+    data.starts_with(&MAGIC)
+}
+
+impl<'a> ElfFile<'a> {
+    /// Validates the file header of an AArch64 executable.
+    pub fn parse(data: &'a [u8]) -> Result<Self, ElfError> {
+        if data.len() < FILE_HEADER_SIZE || !is_elf(data) {
+            return Err(ElfError::NotElf);
+        }
+        if data[4] != CLASS_64 || data[5] != DATA_LITTLE_ENDIAN || data[6] != VERSION_CURRENT {
+            return Err(ElfError::UnsupportedFormat);
+        }
+
+        let kind = read_u16(data, 16);
+        if kind != TYPE_EXEC {
+            return Err(ElfError::NotExecutable(kind));
+        }
+        let machine = read_u16(data, 18);
+        if machine != MACHINE_AARCH64 {
+            return Err(ElfError::WrongMachine(machine));
+        }
+
+        let ph_offset = read_u64(data, 32) as usize;
+        let ph_entry_size = read_u16(data, 54) as usize;
+        let ph_count = read_u16(data, 56) as usize;
+        if ph_count > 0 && ph_entry_size != PROGRAM_HEADER_SIZE {
+            return Err(ElfError::BadProgramHeaders);
+        }
+        let program_headers = ph_offset
+            .checked_add(ph_count * PROGRAM_HEADER_SIZE)
+            .and_then(|end| data.get(ph_offset..end))
+            .ok_or(ElfError::BadProgramHeaders)?;
+
+        Ok(ElfFile {
+            data,
+            entry: read_u64(data, 24),
+            program_headers,
+        })
+    }
+
+    pub fn entry(&self) -> u64 {
+        self.entry
+    }
+
+    /// The `PT_LOAD` segments, in program header order.
+    pub fn segments(&self) -> impl Iterator<Item = Segment> + '_ {
+        self.program_headers
+            .chunks_exact(PROGRAM_HEADER_SIZE)
+            .filter(|header| read_u32(header, 0) == PT_LOAD)
+            .map(|header| Segment {
+                flags: read_u32(header, 4),
+                offset: read_u64(header, 8),
+                virt_addr: read_u64(header, 16),
+                phys_addr: read_u64(header, 24),
+                file_size: read_u64(header, 32),
+                mem_size: read_u64(header, 40),
+            })
+    }
+
+    /// Copies every segment into `memory` and zeroes the part of it past the file data.
+    /// Returns the physical address of the entry point.
+    pub fn load(&self, memory: &mut impl LoadMemory) -> Result<u64, ElfError> {
+        // Check everything before writing anything, a half loaded kernel is no use
+        let entry = self.physical_entry()?;
+        for (index, segment) in self.segments().enumerate() {
+            self.file_data(index, &segment)?;
+            let size = segment.mem_size.max(segment.file_size);
+            memory.region(segment.phys_addr, size as usize).ok_or(
+                ElfError::SegmentNotLoadable {
+                    index,
+                    addr: segment.phys_addr,
+                    size,
+                },
+            )?;
+        }
+
+        for (index, segment) in self.segments().enumerate() {
+            let file_data = self.file_data(index, &segment)?;
+            let size = segment.mem_size.max(segment.file_size) as usize;
+            let dest = memory.region(segment.phys_addr, size).unwrap();
+            let (loaded, bss) = dest.split_at_mut(file_data.len());
+            loaded.copy_from_slice(file_data);
+            bss.fill(0);
+        }
+
+        Ok(entry)
+    }
+
+    /// The entry point translated by the executable segment it lies in, since it is
+    /// jumped to with the MMU off.
+    fn physical_entry(&self) -> Result<u64, ElfError> {
+        self.segments()
+            .filter(|segment| segment.flags & PF_X != 0)
+            .find_map(|segment| {
+                let offset = self.entry.checked_sub(segment.virt_addr)?;
+                let size = segment.mem_size.max(segment.file_size);
+                (offset < size).then(|| segment.phys_addr.checked_add(offset))?
+            })
+            .ok_or(ElfError::BadEntry(self.entry))
+    }
+
+    fn file_data(&self, index: usize, segment: &Segment) -> Result<&'a [u8], ElfError> {
+        let start = segment.offset as usize;
+        start
+            .checked_add(segment.file_size as usize)
+            .and_then(|end| self.data.get(start..end))
+            .ok_or(ElfError::SegmentOutsideFile { index })
+    }
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::vec;
+    use std::vec::Vec;
+
+    // Built from tests/fixtures/elf/kernel.s by build.sh in the same directory
+    const KERNEL_ELF: &[u8] = include_bytes!("../tests/fixtures/elf/kernel.elf");
+    const KERNEL_OBJECT: &[u8] = include_bytes!("../tests/fixtures/elf/kernel.o");
+
+    /// Host memory standing in for the physical range starting at `base`
+    struct TestMemory {
+        base: u64,
+        bytes: Vec<u8>,
+    }
+
+    impl TestMemory {
+        fn new(base: u64, size: usize) -> Self {
+            TestMemory {
+                base,
+                bytes: vec![0xEE; size],
+            }
+        }
+
+        fn at(&self, addr: u64, len: usize) -> &[u8] {
+            let start = (addr - self.base) as usize;
+            &self.bytes[start..start + len]
+        }
+    }
+
+    impl LoadMemory for TestMemory {
+        fn region(&mut self, addr: u64, len: usize) -> Option<&mut [u8]> {
+            let start = addr.checked_sub(self.base)? as usize;
+            self.bytes.get_mut(start..start.checked_add(len)?)
+        }
+    }
+
+    #[test]
+    fn test_parses_fixture_headers() {
+        let elf = ElfFile::parse(KERNEL_ELF).unwrap();
+        assert_eq!(elf.entry(), 0x80004);
+
+        let segments: Vec<Segment> = elf.segments().collect();
+        assert_eq!(segments.len(), 2);
+        assert_eq!(segments[0].phys_addr, 0x80000);
+        assert_eq!(segments[1].virt_addr, 0xFFFF_0000_0010_0000);
+        assert_eq!(segments[1].phys_addr, 0x100000);
+        assert!(segments[1].mem_size > segments[1].file_size);
+    }
+
+    #[test]
+    fn test_loads_segments_at_physical_addresses_and_zeroes_bss() {
+        let elf = ElfFile::parse(KERNEL_ELF).unwrap();
+        let mut memory = TestMemory::new(0x80000, 0x100000);
+
+        assert_eq!(elf.load(&mut memory), Ok(0x80004));
+
+        // nop, then the entry point's first instruction
+        assert_eq!(memory.at(0x80000, 4), 0xd503201fu32.to_le_bytes());
+        let message = b"hello from the data segment";
+        assert_eq!(memory.at(0x100000, message.len()), message);
+
+        let data = elf.segments().nth(1).unwrap();
+        let bss_start = data.phys_addr + data.file_size;
+        let bss_len = (data.mem_size - data.file_size) as usize;
+        assert!(memory.at(bss_start, bss_len).iter().all(|&b| b == 0));
+        // Nothing past the segment is touched
+        assert_eq!(memory.at(bss_start + bss_len as u64, 1), [0xEE]);
+    }
+
+    #[test]
+    fn test_translates_entry_and_rejects_it_outside_code() {
+        let ph_offset = read_u64(KERNEL_ELF, 32) as usize;
+        let text = ElfFile::parse(KERNEL_ELF)
+            .unwrap()
+            .segments()
+            .next()
+            .unwrap();
+        assert_ne!(text.flags & PF_X, 0);
+
+        // Linked high, loaded low: the entry is jumped to at its physical address
+        let mut linked_high = KERNEL_ELF.to_vec();
+        let high = 0xFFFF_0000_0000_0000;
+        linked_high[24..32].copy_from_slice(&(high + 0x80004u64).to_le_bytes());
+        linked_high[ph_offset + 16..ph_offset + 24]
+            .copy_from_slice(&(high + text.virt_addr).to_le_bytes());
+        let elf = ElfFile::parse(&linked_high).unwrap();
+        assert_eq!(
+            elf.load(&mut TestMemory::new(0x80000, 0x100000)),
+            Ok(0x80004)
+        );
+
+        // In the data segment, and in no segment at all
+        let data = ElfFile::parse(KERNEL_ELF)
+            .unwrap()
+            .segments()
+            .nth(1)
+            .unwrap();
+        for entry in [data.virt_addr, 0x40_0000] {
+            let mut bad_entry = KERNEL_ELF.to_vec();
+            bad_entry[24..32].copy_from_slice(&entry.to_le_bytes());
+            let mut memory = TestMemory::new(0x80000, 0x100000);
+            assert_eq!(
+                ElfFile::parse(&bad_entry).unwrap().load(&mut memory),
+                Err(ElfError::BadEntry(entry))
+            );
+            assert!(memory.bytes.iter().all(|&b| b == 0xEE));
+        }
+    }
+
+    #[test]
+    fn test_rejects_invalid_files() {
+        assert!(matches!(
+            ElfFile::parse(b"not an elf"),
+            Err(ElfError::NotElf)
+        ));
+        assert!(matches!(
+            ElfFile::parse(KERNEL_OBJECT),
+            Err(ElfError::NotExecutable(1))
+        ));
+
+        let mut x86 = KERNEL_ELF.to_vec();
+        x86[18..20].copy_from_slice(&62u16.to_le_bytes());
+        assert!(matches!(
+            ElfFile::parse(&x86),
+            Err(ElfError::WrongMachine(62))
+        ));
+
+        let truncated = &KERNEL_ELF[..0x2000];
+        assert_eq!(
+            ElfFile::parse(truncated)
+                .unwrap()
+                .load(&mut TestMemory::new(0x80000, 0x100000)),
+            Err(ElfError::SegmentOutsideFile { index: 1 })
+        );
+    }
+
+    #[test]
+    fn test_refuses_segments_outside_memory_without_writing() {
+        let elf = ElfFile::parse(KERNEL_ELF).unwrap();
+        // Covers the text segment but not the data segment
+        let mut memory = TestMemory::new(0x80000, 0x1000);
+
+        assert!(matches!(
+            elf.load(&mut memory),
+            Err(ElfError::SegmentNotLoadable {
+                index: 1,
+                addr: 0x100000,
+                ..
+            })
+        ));
+        assert!(memory.bytes.iter().all(|&b| b == 0xEE));
+    }
+}
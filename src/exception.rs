@@ -0,0 +1,376 @@
+//! Exception vectors and crash reports for faults.
+//!
+//! `exception.s` saves the interrupted context as an `ExceptionFrame` and calls
+//! `_handle_exception(frame, vector)`, which the binary defines. `CrashReport` turns a
+//! frame into something readable: the exception class decoded from ESR, the fault address
+//! and the register dump.
+
+use core::fmt;
+
+#[cfg(target_arch = "aarch64")]
+use core::arch::{asm, global_asm};
+
+/// Registers saved on exception entry, in the order `exception.s` pushes them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct ExceptionFrame {
+    pub x: [u64; 31],
+    /// Stack pointer at the time of the exception
+    pub sp: u64,
+    /// Where execution resumes, written back on return
+    pub elr: u64,
+    /// Saved processor state, written back on return
+    pub spsr: u64,
+    pub esr: u64,
+    pub far: u64,
+}
+
+pub const FRAME_SIZE: usize = size_of::<ExceptionFrame>();
+
+#[cfg(target_arch = "aarch64")]
+global_asm!(include_str!("exception.s"), FRAME_SIZE = const FRAME_SIZE);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExceptionKind {
+    Synchronous,
+    Irq,
+    Fiq,
+    SError,
+}
+
+/// Where the exception was taken from, which picks the group of vectors.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Origin {
+    CurrentElSp0,
+    CurrentElSpx,
+    LowerElAarch64,
+    LowerElAarch32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Vector {
+    pub kind: ExceptionKind,
+    pub origin: Origin,
+}
+
+impl Vector {
+    /// The vector at `index` in the table, counting 0x80 byte entries.
+    pub fn from_index(index: u64) -> Self {
+        let kind = match index % 4 {
+            0 => ExceptionKind::Synchronous,
+            1 => ExceptionKind::Irq,
+            2 => ExceptionKind::Fiq,
+            _ => ExceptionKind::SError,
+        };
+        let origin = match index / 4 {
+            0 => Origin::CurrentElSp0,
+            1 => Origin::CurrentElSpx,
+            2 => Origin::LowerElAarch64,
+            _ => Origin::LowerElAarch32,
+        };
+        Vector { kind, origin }
+    }
+}
+
+/// An exception syndrome register (ESR_ELx) value.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Syndrome(pub u64);
+
+impl Syndrome {
+    pub fn class(&self) -> u8 {
+        ((self.0 >> 26) & 0x3f) as u8
+    }
+
+    /// The class specific syndrome bits.
+    pub fn iss(&self) -> u32 {
+        (self.0 & 0x1ff_ffff) as u32
+    }
+
+    pub fn class_name(&self) -> &'static str {
+        match self.class() {
+            0x00 => "unknown reason (undefined instruction)",
+            0x01 => "trapped WFI/WFE",
+            0x07 => "trapped SIMD/FP access",
+            0x0e => "illegal execution state",
+            0x15 => "SVC",
+            0x16 => "HVC",
+            0x17 => "SMC",
+            0x18 => "trapped system register access",
+            0x20 => "instruction abort from a lower EL",
+            0x21 => "instruction abort",
+            0x22 => "PC alignment fault",
+            0x24 => "data abort from a lower EL",
+            0x25 => "data abort",
+            0x26 => "SP alignment fault",
+            0x2c => "floating point exception",
+            0x2f => "SError",
+            0x30 | 0x31 => "breakpoint",
+            0x32 | 0x33 => "software step",
+            0x34 | 0x35 => "watchpoint",
+            0x3c => "BRK instruction",
+            _ => "unrecognized exception class",
+        }
+    }
+
+    fn is_abort(&self) -> bool {
+        matches!(self.class(), 0x20 | 0x21 | 0x24 | 0x25)
+    }
+
+    /// Whether FAR holds the faulting address for this class.
+    pub fn has_fault_address(&self) -> bool {
+        self.is_abort() || matches!(self.class(), 0x22 | 0x34 | 0x35)
+    }
+
+    /// Decodes the fault status code of instruction and data aborts.
+    fn fault_status(&self) -> Option<(&'static str, Option<u32>)> {
+        if !self.is_abort() {
+            return None;
+        }
+        let status = self.iss() & 0x3f;
+        let level = Some(status & 0b11);
+        Some(match status {
+            0x00..=0x03 => ("address size fault", level),
+            0x04..=0x07 => ("translation fault", level),
+            0x09..=0x0b => ("access flag fault", level),
+            0x0d..=0x0f => ("permission fault", level),
+            0x10 => ("synchronous external abort", None),
+            0x21 => ("alignment fault", None),
+            0x30 => ("TLB conflict abort", None),
+            _ => ("fault", None),
+        })
+    }
+}
+
+impl fmt::Display for Syndrome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.class_name())?;
+        if let Some((status, level)) = self.fault_status() {
+            write!(f, ", {status}")?;
+            if let Some(level) = level {
+                write!(f, " at level {level}")?;
+            }
+            // WnR only means something for data aborts
+            if matches!(self.class(), 0x24 | 0x25) {
+                let access = if self.iss() & (1 << 6) != 0 {
+                    "write"
+                } else {
+                    "read"
+                };
+                write!(f, " on {access}")?;
+            }
+        }
+        match self.class() {
+            0x15..=0x17 | 0x3c => write!(f, " #{}", self.iss() & 0xffff),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Multi-line description of an exception for the crash console.
+pub struct CrashReport<'a> {
+    pub vector: Vector,
+    pub frame: &'a ExceptionFrame,
+}
+
+impl fmt::Display for CrashReport<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let frame = self.frame;
+        let syndrome = Syndrome(frame.esr);
+        writeln!(
+            f,
+            "EXCEPTION: {:?} from {:?} at EL{}",
+            self.vector.kind,
+            self.vector.origin,
+            current_el()
+        )?;
+        if self.vector.kind == ExceptionKind::Synchronous {
+            writeln!(f, "{syndrome}")?;
+            write!(
+                f,
+                "ESR {:#010x} (EC {:#04x}, ISS {:#09x})",
+                frame.esr,
+                syndrome.class(),
+                syndrome.iss()
+            )?;
+            if syndrome.has_fault_address() {
+                write!(f, " FAR {:#018x}", frame.far)?;
+            }
+            writeln!(f)?;
+        }
+        writeln!(
+            f,
+            "ELR {:#018x} SPSR {:#010x} SP {:#018x}",
+            frame.elr, frame.spsr, frame.sp
+        )?;
+        for (row, registers) in frame.x.chunks(3).enumerate() {
+            for (column, value) in registers.iter().enumerate() {
+                let separator = if column == 0 { "" } else { "  " };
+                write!(f, "{separator}x{:02} {value:#018x}", row * 3 + column)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// The exception level the core is running at.
+pub fn current_el() -> u8 {
+    #[cfg(target_arch = "aarch64")]
+    {
+        let value: u64;
+        // SAFETY: Reading CurrentEL has no side effects
+        unsafe { asm!("mrs {}, CurrentEL", out(reg) value) };
+        ((value >> 2) & 0b11) as u8
+    }
+
+    #[cfg(not(target_arch = "aarch64"))]
+    {
+        1
+    }
+}
+
+/// Unmasks IRQs on this core.
+pub fn enable_irqs() {
+    #[cfg(target_arch = "aarch64")]
+    // SAFETY: Only changes which exceptions can be taken, the vectors handle them all
+    unsafe {
+        asm!("msr daifclr, #2")
+    };
+}
+
+/// This core's interrupt mask from before `mask_irqs`, to hand back to `restore_irqs`.
+#[derive(Clone, Copy, Debug)]
+#[must_use]
+pub struct IrqState(#[cfg_attr(not(target_arch = "aarch64"), allow(dead_code))] u64);
+
+/// Masks IRQs on this core, returning the previous mask.
+pub fn mask_irqs() -> IrqState {
+    #[cfg(target_arch = "aarch64")]
+    {
+        let daif: u64;
+        // SAFETY: Masking interrupts has no other effect
+        unsafe { asm!("mrs {}, DAIF", "msr daifset, #2", out(reg) daif) };
+        IrqState(daif)
+    }
+
+    #[cfg(not(target_arch = "aarch64"))]
+    IrqState(0)
+}
+
+/// Puts back the interrupt mask `mask_irqs` saved.
+pub fn restore_irqs(state: IrqState) {
+    #[cfg(target_arch = "aarch64")]
+    // SAFETY: Restores a mask this core had before
+    unsafe {
+        asm!("msr DAIF, {}", in(reg) state.0)
+    };
+
+    #[cfg(not(target_arch = "aarch64"))]
+    let _ = state;
+}
+
+/// Runs `f` with IRQs masked on this core, then restores the previous mask.
+pub fn without_irqs<T>(f: impl FnOnce() -> T) -> T {
+    let state = mask_irqs();
+    let result = f();
+    restore_irqs(state);
+    result
+}
+
+/// Points the current exception level's VBAR at the vector table for it, routing IRQs
+/// there when running at EL2.
+pub fn install() {
+    #[cfg(target_arch = "aarch64")]
+    unsafe {
+        unsafe extern "C" {
+            static __exception_vectors_el1: u8;
+            static __exception_vectors_el2: u8;
+        }
+
+        if current_el() == 2 {
+            asm!("msr VBAR_EL2, {}", "isb", in(reg) &raw const __exception_vectors_el2);
+            // Without HCR_EL2.IMO, IRQs are only ever taken to EL1
+            asm!(
+                "mrs {0}, HCR_EL2",
+                "orr {0}, {0}, #(1 << 4)",
+                "msr HCR_EL2, {0}",
+                "isb",
+                out(reg) _
+            );
+        } else {
+            asm!("msr VBAR_EL1, {}", "isb", in(reg) &raw const __exception_vectors_el1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::string::ToString;
+
+    #[test]
+    fn test_frame_layout_matches_exception_s() {
+        // exception.s stores ELR/SPSR at 16 * 16 and ESR/FAR at 16 * 17
+        assert_eq!(FRAME_SIZE, 16 * 18);
+        assert_eq!(core::mem::offset_of!(ExceptionFrame, sp), 16 * 15 + 8);
+        assert_eq!(core::mem::offset_of!(ExceptionFrame, elr), 16 * 16);
+        assert_eq!(core::mem::offset_of!(ExceptionFrame, esr), 16 * 17);
+    }
+
+    #[test]
+    fn test_decodes_vectors_and_syndromes() {
+        assert_eq!(
+            Vector::from_index(5),
+            Vector {
+                kind: ExceptionKind::Irq,
+                origin: Origin::CurrentElSpx
+            }
+        );
+        assert_eq!(Vector::from_index(11).kind, ExceptionKind::SError);
+
+        let unaligned_store = Syndrome(0x9600_0061);
+        assert_eq!(unaligned_store.class(), 0x25);
+        assert_eq!(
+            unaligned_store.to_string(),
+            "data abort, alignment fault on write"
+        );
+        assert_eq!(
+            Syndrome(0x9600_0006).to_string(),
+            "data abort, translation fault at level 2 on read"
+        );
+        assert_eq!(
+            Syndrome(0x8600_000f).to_string(),
+            "instruction abort, permission fault at level 3"
+        );
+        assert_eq!(Syndrome(0xf200_0001).to_string(), "BRK instruction #1");
+        assert!(!Syndrome(0x0200_0000).has_fault_address());
+    }
+
+    #[test]
+    fn test_crash_report_dumps_registers() {
+        let mut frame = ExceptionFrame {
+            elr: 0x2001234,
+            esr: 0x9600_0045,
+            far: 0xdead_0000,
+            ..Default::default()
+        };
+        frame.x[30] = 0x2000abc;
+        let report = CrashReport {
+            vector: Vector::from_index(4),
+            frame: &frame,
+        }
+        .to_string();
+
+        let lines: std::vec::Vec<&str> = report.lines().collect();
+        assert_eq!(lines[0], "EXCEPTION: Synchronous from CurrentElSpx at EL1");
+        assert_eq!(
+            lines[1],
+            "data abort, translation fault at level 1 on write"
+        );
+        assert!(lines[2].ends_with("FAR 0x00000000dead0000"));
+        assert!(lines[3].starts_with("ELR 0x0000000002001234"));
+        assert_eq!(lines.len(), 4 + 11);
+        assert_eq!(lines[14], "x30 0x0000000002000abc");
+    }
+}
@@ -0,0 +1,93 @@
+/*
+ * Exception vector tables for EL1 and EL2, installed by exception::install.
+ *
+ * Every entry pushes an ExceptionFrame (see exception.rs) and calls
+ * _handle_exception(frame, vector). ELR and SPSR are restored from the frame on
+ * the way out, so handlers can change where execution resumes.
+ */
+
+.macro VECTOR el, index
+.balign 0x80
+	sub	sp, sp, #{FRAME_SIZE}
+	stp	x0, x1, [sp]
+	mov	x0, #\index
+	b	.exception_entry_el\el
+.endm
+
+.macro VECTOR_TABLE el
+.section .text.exception_vectors_el\el
+.balign 0x800
+.global __exception_vectors_el\el
+__exception_vectors_el\el:
+	VECTOR \el, 0
+	VECTOR \el, 1
+	VECTOR \el, 2
+	VECTOR \el, 3
+	VECTOR \el, 4
+	VECTOR \el, 5
+	VECTOR \el, 6
+	VECTOR \el, 7
+	VECTOR \el, 8
+	VECTOR \el, 9
+	VECTOR \el, 10
+	VECTOR \el, 11
+	VECTOR \el, 12
+	VECTOR \el, 13
+	VECTOR \el, 14
+	VECTOR \el, 15
+
+.exception_entry_el\el:
+	/* x0 holds the vector index, the original x0 and x1 are already saved */
+	stp	x2, x3, [sp, #16 * 1]
+	stp	x4, x5, [sp, #16 * 2]
+	stp	x6, x7, [sp, #16 * 3]
+	stp	x8, x9, [sp, #16 * 4]
+	stp	x10, x11, [sp, #16 * 5]
+	stp	x12, x13, [sp, #16 * 6]
+	stp	x14, x15, [sp, #16 * 7]
+	stp	x16, x17, [sp, #16 * 8]
+	stp	x18, x19, [sp, #16 * 9]
+	stp	x20, x21, [sp, #16 * 10]
+	stp	x22, x23, [sp, #16 * 11]
+	stp	x24, x25, [sp, #16 * 12]
+	stp	x26, x27, [sp, #16 * 13]
+	stp	x28, x29, [sp, #16 * 14]
+	/* The stack pointer from before the frame was pushed */
+	add	x1, sp, #{FRAME_SIZE}
+	stp	x30, x1, [sp, #16 * 15]
+	mrs	x1, ELR_EL\el
+	mrs	x2, SPSR_EL\el
+	stp	x1, x2, [sp, #16 * 16]
+	mrs	x1, ESR_EL\el
+	mrs	x2, FAR_EL\el
+	stp	x1, x2, [sp, #16 * 17]
+
+	mov	x1, x0
+	mov	x0, sp
+	bl	_handle_exception
+
+	ldp	x1, x2, [sp, #16 * 16]
+	msr	ELR_EL\el, x1
+	msr	SPSR_EL\el, x2
+	ldp	x0, x1, [sp]
+	ldp	x2, x3, [sp, #16 * 1]
+	ldp	x4, x5, [sp, #16 * 2]
+	ldp	x6, x7, [sp, #16 * 3]
+	ldp	x8, x9, [sp, #16 * 4]
+	ldp	x10, x11, [sp, #16 * 5]
+	ldp	x12, x13, [sp, #16 * 6]
+	ldp	x14, x15, [sp, #16 * 7]
+	ldp	x16, x17, [sp, #16 * 8]
+	ldp	x18, x19, [sp, #16 * 9]
+	ldp	x20, x21, [sp, #16 * 10]
+	ldp	x22, x23, [sp, #16 * 11]
+	ldp	x24, x25, [sp, #16 * 12]
+	ldp	x26, x27, [sp, #16 * 13]
+	ldp	x28, x29, [sp, #16 * 14]
+	ldr	x30, [sp, #16 * 15]
+	add	sp, sp, #{FRAME_SIZE}
+	eret
+.endm
+
+VECTOR_TABLE 1
+VECTOR_TABLE 2
@@ -0,0 +1,572 @@
+//! Read-only flattened device tree (DTB) parser.
+//!
+//! Follows version 17 of the devicetree specification's flattened format. Nodes are found
+//! by path, alias or compatible string, and `reg` addresses can be translated through the
+//! `ranges` of parent buses into CPU physical addresses.
+
+use core::fmt;
+use core::ops::Range;
+
+pub const MAGIC: u32 = 0xd00dfeed;
+pub const HEADER_SIZE: usize = 40;
+const LAST_COMPATIBLE_VERSION: u32 = 16;
+
+pub(crate) const TOKEN_BEGIN_NODE: u32 = 1;
+pub(crate) const TOKEN_END_NODE: u32 = 2;
+pub(crate) const TOKEN_PROP: u32 = 3;
+pub(crate) const TOKEN_NOP: u32 = 4;
+
+/// Deepest node nesting that is walked, real trees stay well below this
+pub const MAX_DEPTH: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FdtError {
+    BadMagic,
+    UnsupportedVersion(u32),
+    Truncated,
+}
+
+impl fmt::Display for FdtError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FdtError::BadMagic => write!(f, "not a device tree blob"),
+            FdtError::UnsupportedVersion(version) => {
+                write!(f, "unsupported device tree version {version}")
+            }
+            FdtError::Truncated => write!(f, "device tree blob is truncated"),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Fdt<'a> {
+    data: &'a [u8],
+    structs: &'a [u8],
+    strings: &'a [u8],
+}
+
+impl<'a> Fdt<'a> {
+    /// Checks the header of the blob at the start of `data`, which may extend past its end.
+    pub fn new(data: &'a [u8]) -> Result<Self, FdtError> {
+        let header = |field: usize| read_u32(data, field * 4).ok_or(FdtError::Truncated);
+        if header(0)? != MAGIC {
+            return Err(FdtError::BadMagic);
+        }
+        let last_compatible = header(6)?;
+        if last_compatible > LAST_COMPATIBLE_VERSION {
+            return Err(FdtError::UnsupportedVersion(last_compatible));
+        }
+
+        let data = data.get(..header(1)? as usize).ok_or(FdtError::Truncated)?;
+        let section = |offset: u32, size: u32| {
+            let offset = offset as usize;
+            data.get(offset..offset + size as usize)
+                .ok_or(FdtError::Truncated)
+        };
+        Ok(Fdt {
+            data,
+            structs: section(header(2)?, header(9)?)?,
+            strings: section(header(3)?, header(8)?)?,
+        })
+    }
+
+    /// Parses the blob at `addr`, such as the one the firmware passes in `x0`.
+    ///
+    /// # Safety
+    ///
+    /// `addr` must point at readable memory holding at least a device tree header, and the
+    /// whole blob it describes must stay valid and unchanged for `'a`.
+    pub unsafe fn from_addr(addr: usize) -> Result<Self, FdtError> {
+        // Firmware without a device tree leaves x0 zero
+        if addr == 0 {
+            return Err(FdtError::BadMagic);
+        }
+        let header = unsafe { core::slice::from_raw_parts(addr as *const u8, HEADER_SIZE) };
+        if read_u32(header, 0) != Some(MAGIC) {
+            return Err(FdtError::BadMagic);
+        }
+        let size = read_u32(header, 4).unwrap() as usize;
+        Self::new(unsafe { core::slice::from_raw_parts(addr as *const u8, size) })
+    }
+
+    /// The blob's bytes, as long as its header says it is.
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.data
+    }
+
+    pub fn root(&self) -> Node<'a> {
+        Node {
+            fdt: *self,
+            offset: 0,
+            depth: 0,
+            ancestors: [0; MAX_DEPTH],
+        }
+    }
+
+    /// Finds a node by absolute path, e.g. `/soc/serial@7e201000`, or by an alias as the
+    /// first component. Unit addresses may be left out when they aren't ambiguous.
+    pub fn find_node(&self, path: &str) -> Option<Node<'a>> {
+        let (mut node, rest) = match path.strip_prefix('/') {
+            Some(rest) => (self.root(), rest),
+            None => {
+                let (alias, rest) = path.split_once('/').unwrap_or((path, ""));
+                (self.find_node(self.alias(alias)?)?, rest)
+            }
+        };
+        for component in rest.split('/').filter(|component| !component.is_empty()) {
+            node = node.children().find(|child| {
+                let name = child.name();
+                name == component
+                    || (!component.contains('@') && name.split('@').next() == Some(component))
+            })?;
+        }
+        Some(node)
+    }
+
+    pub fn alias(&self, name: &str) -> Option<&'a str> {
+        self.find_node("/aliases")?.property(name)?.as_str()
+    }
+
+    /// The first node, in tree order, that lists `compatible` among its compatibles.
+    pub fn find_compatible(&self, compatible: &str) -> Option<Node<'a>> {
+        fn search<'a>(node: Node<'a>, compatible: &str) -> Option<Node<'a>> {
+            if node.is_compatible(compatible) {
+                return Some(node);
+            }
+            node.children().find_map(|child| search(child, compatible))
+        }
+        search(self.root(), compatible)
+    }
+
+    /// RAM ranges from the `/memory` nodes. Empty banks, such as the placeholder in the
+    /// upstream blob before the firmware fills it in, and banks running past the end of the
+    /// address space are skipped.
+    pub fn memory(&self) -> impl Iterator<Item = Range<u64>> + use<'a> {
+        self.root()
+            .children()
+            .filter(|node| {
+                node.property("device_type").and_then(|prop| prop.as_str()) == Some("memory")
+            })
+            .flat_map(|node| node.reg())
+            .filter_map(|(addr, size)| Some(addr..addr.checked_add(size?)?))
+            .filter(|bank| !bank.is_empty())
+    }
+
+    /// Ranges the `/memreserve/` block asks the OS to stay away from. Entries that would
+    /// overflow are skipped.
+    pub fn memory_reservations(&self) -> impl Iterator<Item = Range<u64>> + use<'a> {
+        let data = self.data;
+        let start = read_u32(data, 16).unwrap_or(0) as usize;
+        (start..data.len())
+            .step_by(16)
+            .map_while(move |offset| Some((read_u64(data, offset)?, read_u64(data, offset + 8)?)))
+            .take_while(|&(addr, size)| addr != 0 || size != 0)
+            .filter_map(|(addr, size)| Some(addr..addr.checked_add(size)?))
+    }
+
+    pub fn bootargs(&self) -> Option<&'a str> {
+        self.find_node("/chosen")?.property("bootargs")?.as_str()
+    }
+
+    /// The initramfs `/chosen` points at, such as a file the firmware loaded for
+    /// config.txt's `initramfs` directive.
+    pub fn initrd(&self) -> Option<Range<u64>> {
+        let chosen = self.find_node("/chosen")?;
+        let cell = |name| chosen.property(name)?.as_u64();
+        Some(cell("linux,initrd-start")?..cell("linux,initrd-end")?)
+    }
+
+    pub(crate) fn token(&self, offset: usize) -> Option<u32> {
+        read_u32(self.structs, offset)
+    }
+
+    fn string(&self, offset: usize) -> Option<&'a str> {
+        c_str(self.strings.get(offset..)?)
+    }
+
+    /// Skips NOPs, returning the offset of the next meaningful token.
+    pub(crate) fn skip_nops(&self, mut offset: usize) -> usize {
+        while self.token(offset) == Some(TOKEN_NOP) {
+            offset += 4;
+        }
+        offset
+    }
+
+    /// Offset of the first token after the node name at `offset`.
+    pub(crate) fn node_body(&self, offset: usize) -> usize {
+        let name_len = self
+            .structs
+            .get(offset + 4..)
+            .and_then(|name| name.iter().position(|&b| b == 0))
+            .unwrap_or(0);
+        (offset + 4 + name_len + 1).next_multiple_of(4)
+    }
+
+    /// Reads the property at `offset`, returning it and the offset after it.
+    pub(crate) fn property_at(&self, offset: usize) -> Option<(Property<'a>, usize)> {
+        if self.token(offset)? != TOKEN_PROP {
+            return None;
+        }
+        let len = self.token(offset + 4)? as usize;
+        let name = self.string(self.token(offset + 8)? as usize)?;
+        let value = self.structs.get(offset + 12..offset + 12 + len)?;
+        Some((
+            Property { name, value },
+            (offset + 12 + len).next_multiple_of(4),
+        ))
+    }
+
+    /// Offset just past the end of the node beginning at `offset`.
+    pub(crate) fn skip_node(&self, offset: usize) -> Option<usize> {
+        let mut depth = 0;
+        let mut offset = offset;
+        loop {
+            match self.token(offset)? {
+                TOKEN_BEGIN_NODE => {
+                    depth += 1;
+                    offset = self.node_body(offset);
+                }
+                TOKEN_END_NODE => {
+                    depth -= 1;
+                    offset += 4;
+                    if depth == 0 {
+                        return Some(offset);
+                    }
+                }
+                TOKEN_PROP => offset = self.property_at(offset)?.1,
+                TOKEN_NOP => offset += 4,
+                _ => return None,
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Node<'a> {
+    fdt: Fdt<'a>,
+    /// Offset of the node's BEGIN_NODE token in the structure block
+    offset: usize,
+    depth: usize,
+    ancestors: [usize; MAX_DEPTH],
+}
+
+impl<'a> Node<'a> {
+    /// Offset of the node's BEGIN_NODE token in the structure block.
+    pub(crate) fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Name including unit address, empty for the root.
+    pub fn name(&self) -> &'a str {
+        self.fdt
+            .structs
+            .get(self.offset + 4..)
+            .and_then(c_str)
+            .unwrap_or("")
+    }
+
+    pub fn properties(&self) -> impl Iterator<Item = Property<'a>> + use<'a> {
+        let fdt = self.fdt;
+        let mut offset = fdt.node_body(self.offset);
+        core::iter::from_fn(move || {
+            let (property, next) = fdt.property_at(fdt.skip_nops(offset))?;
+            offset = next;
+            Some(property)
+        })
+    }
+
+    pub fn property(&self, name: &str) -> Option<Property<'a>> {
+        self.properties().find(|property| property.name == name)
+    }
+
+    pub fn children(&self) -> impl Iterator<Item = Node<'a>> + use<'a> {
+        let parent = *self;
+        let fdt = self.fdt;
+        let mut offset = fdt.node_body(self.offset);
+        while let Some((_, next)) = fdt.property_at(fdt.skip_nops(offset)) {
+            offset = next;
+        }
+
+        core::iter::from_fn(move || {
+            offset = fdt.skip_nops(offset);
+            if fdt.token(offset)? != TOKEN_BEGIN_NODE || parent.depth + 1 >= MAX_DEPTH {
+                return None;
+            }
+            let mut child = Node {
+                fdt,
+                offset,
+                depth: parent.depth + 1,
+                ancestors: parent.ancestors,
+            };
+            child.ancestors[parent.depth] = parent.offset;
+            offset = fdt.skip_node(offset)?;
+            Some(child)
+        })
+    }
+
+    pub fn parent(&self) -> Option<Node<'a>> {
+        let depth = self.depth.checked_sub(1)?;
+        Some(Node {
+            fdt: self.fdt,
+            offset: self.ancestors[depth],
+            depth,
+            ancestors: self.ancestors,
+        })
+    }
+
+    pub fn is_compatible(&self, compatible: &str) -> bool {
+        self.property("compatible")
+            .is_some_and(|prop| prop.strings().any(|entry| entry == compatible))
+    }
+
+    /// Cells used for addresses in this node's children's `reg` properties.
+    pub fn address_cells(&self) -> usize {
+        self.property("#address-cells")
+            .and_then(|prop| prop.as_u32())
+            .unwrap_or(2) as usize
+    }
+
+    /// Cells used for sizes in this node's children's `reg` properties.
+    pub fn size_cells(&self) -> usize {
+        self.property("#size-cells")
+            .and_then(|prop| prop.as_u32())
+            .unwrap_or(1) as usize
+    }
+
+    /// `(address, size)` pairs from `reg`, as addresses on the parent bus. Size is `None`
+    /// when the parent uses no size cells.
+    pub fn reg(&self) -> impl Iterator<Item = (u64, Option<u64>)> + use<'a> {
+        let (address_cells, size_cells) = self.parent().map_or((2, 1), |parent| {
+            (parent.address_cells(), parent.size_cells())
+        });
+        let entry_size = (address_cells + size_cells) * 4;
+        let value = self.property("reg").map_or(&[][..], |prop| prop.value);
+
+        value.chunks_exact(entry_size.max(4)).map(move |entry| {
+            let address = read_cells(&entry[..address_cells * 4]);
+            let size = (size_cells > 0).then(|| read_cells(&entry[address_cells * 4..]));
+            (address, size)
+        })
+    }
+
+    /// The `index`th `reg` address translated to a CPU physical address.
+    pub fn reg_address(&self, index: usize) -> Option<u64> {
+        let (address, _) = self.reg().nth(index)?;
+        self.translate(address)
+    }
+
+    /// Translates an address on this node's parent bus through each bus's `ranges` up to
+    /// the root. Returns `None` if a bus has no `ranges` or none of them cover the address.
+    pub fn translate(&self, mut address: u64) -> Option<u64> {
+        let mut bus = self.parent()?;
+        while let Some(parent) = bus.parent() {
+            let ranges = bus.property("ranges")?.value;
+            // An empty ranges property means a one to one mapping
+            if !ranges.is_empty() {
+                let child_cells = bus.address_cells();
+                let parent_cells = parent.address_cells();
+                let entry_size = (child_cells + parent_cells + bus.size_cells()) * 4;
+                address = ranges.chunks_exact(entry_size).find_map(|entry| {
+                    let (child, rest) = entry.split_at(child_cells * 4);
+                    let (parent_addr, size) = rest.split_at(parent_cells * 4);
+                    let offset = address.checked_sub(read_cells(child))?;
+                    (offset < read_cells(size)).then(|| read_cells(parent_addr) + offset)
+                })?;
+            }
+            bus = parent;
+        }
+        Some(address)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Property<'a> {
+    pub name: &'a str,
+    pub value: &'a [u8],
+}
+
+impl<'a> Property<'a> {
+    pub fn as_u32(&self) -> Option<u32> {
+        (self.value.len() == 4).then(|| read_u32(self.value, 0).unwrap())
+    }
+
+    /// A one or two cell value.
+    pub fn as_u64(&self) -> Option<u64> {
+        matches!(self.value.len(), 4 | 8).then(|| read_cells(self.value))
+    }
+
+    /// The first string of a string or string list property.
+    pub fn as_str(&self) -> Option<&'a str> {
+        c_str(self.value)
+    }
+
+    /// Every string of a string list property.
+    pub fn strings(&self) -> impl Iterator<Item = &'a str> + use<'a> {
+        self.value
+            .split(|&b| b == 0)
+            .filter(|entry| !entry.is_empty())
+            .filter_map(|entry| core::str::from_utf8(entry).ok())
+    }
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(
+        data.get(offset..offset + 4)?.try_into().unwrap(),
+    ))
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_be_bytes(
+        data.get(offset..offset + 8)?.try_into().unwrap(),
+    ))
+}
+
+/// Reads big endian cells, keeping the low 64 bits.
+fn read_cells(cells: &[u8]) -> u64 {
+    cells.chunks_exact(4).fold(0, |value, cell| {
+        (value << 32) | u32::from_be_bytes(cell.try_into().unwrap()) as u64
+    })
+}
+
+fn c_str(data: &[u8]) -> Option<&str> {
+    let len = data.iter().position(|&b| b == 0)?;
+    core::str::from_utf8(&data[..len]).ok()
+}
+
+/// The firmware's own bcm2711-rpi-4-b.dtb, committed under tests/fixtures/dtb by
+/// fetch_firmware_dtb.sh.
+#[cfg(test)]
+pub(crate) fn firmware_dtb() -> std::vec::Vec<u8> {
+    let path = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/fixtures/dtb/bcm2711-rpi-4-b.dtb"
+    );
+    std::fs::read(path).unwrap_or_else(|err| {
+        panic!("{path}: {err}, run tests/fixtures/dtb/fetch_firmware_dtb.sh and commit it")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::vec;
+    use std::vec::Vec;
+
+    // Generated by tests/fixtures/dtb/make_dtb.py, see there for what it mirrors
+    const RPI4B_DTB: &[u8] = include_bytes!("../tests/fixtures/dtb/rpi4b.dtb");
+
+    /// Replaces the first occurrence of `from` in a copy of `dtb`.
+    fn patched(dtb: &[u8], from: &[u8], to: &[u8]) -> Vec<u8> {
+        let at = dtb.windows(from.len()).position(|w| w == from).unwrap();
+        let mut copy = dtb.to_vec();
+        copy[at..at + to.len()].copy_from_slice(to);
+        copy
+    }
+
+    #[test]
+    fn test_rejects_bad_headers() {
+        assert_eq!(Fdt::new(&[0u8; 8]).err(), Some(FdtError::BadMagic));
+        assert_eq!(
+            Fdt::new(&RPI4B_DTB[..RPI4B_DTB.len() - 1]).err(),
+            Some(FdtError::Truncated)
+        );
+    }
+
+    #[test]
+    fn test_walks_nodes_and_properties() {
+        let fdt = Fdt::new(RPI4B_DTB).unwrap();
+        let root = fdt.root();
+        assert_eq!(root.name(), "");
+        assert!(root.is_compatible("brcm,bcm2711"));
+        assert_eq!(
+            root.property("model").unwrap().as_str(),
+            Some("Raspberry Pi 4 Model B Rev 1.4")
+        );
+
+        let names: Vec<&str> = root.children().map(|node| node.name()).collect();
+        assert_eq!(
+            names,
+            ["aliases", "chosen", "memory@0", "cpus", "soc", "timer"]
+        );
+
+        let cpus: Vec<u64> = fdt
+            .find_node("/cpus")
+            .unwrap()
+            .children()
+            .map(|cpu| cpu.property("cpu-release-addr").unwrap().as_u64().unwrap())
+            .collect();
+        assert_eq!(cpus, [0xd8, 0xe0, 0xe8, 0xf0]);
+    }
+
+    #[test]
+    fn test_resolves_memory_and_bootargs() {
+        let fdt = Fdt::new(RPI4B_DTB).unwrap();
+
+        let memory: Vec<Range<u64>> = fdt.memory().collect();
+        assert_eq!(memory, [0..0x3b400000, 0x40000000..0xfc000000]);
+        let reserved: Vec<Range<u64>> = fdt.memory_reservations().collect();
+        assert_eq!(reserved, vec![0..0x1000]);
+
+        assert!(fdt.bootargs().unwrap().contains("console=ttyS0,115200"));
+    }
+
+    #[test]
+    fn test_reads_the_firmware_blob() {
+        let blob = firmware_dtb();
+        let fdt = Fdt::new(&blob).unwrap();
+        assert!(fdt.root().is_compatible("brcm,bcm2711"));
+        // Its /memory node is a placeholder the firmware fills in at boot
+        assert_eq!(fdt.memory().next(), None);
+
+        let mailbox = fdt.find_compatible("brcm,bcm2835-mbox").unwrap();
+        assert_eq!(mailbox.reg_address(0), Some(0xfe00b880));
+        let uart = fdt.find_node("serial1").unwrap();
+        assert!(uart.is_compatible("arm,pl011"));
+        assert_eq!(uart.reg_address(0), Some(0xfe201000));
+        let gic = fdt.find_compatible("arm,gic-400").unwrap();
+        assert_eq!(gic.reg_address(0), Some(0xff841000));
+    }
+
+    #[test]
+    fn test_skips_memory_ranges_that_overflow() {
+        // First bank 0..0x3b400000 moved to the top of the address space
+        let bank = [0, 0, 0, 0, 0, 0, 0, 0, 0x3b, 0x40, 0, 0];
+        let dtb = patched(RPI4B_DTB, &bank, &[0xFF; 8]);
+        // The reservation 0..0x1000 likewise
+        let dtb = patched(&dtb, &[0; 8], &[0xFF; 8]);
+        let fdt = Fdt::new(&dtb).unwrap();
+
+        let memory: Vec<Range<u64>> = fdt.memory().collect();
+        assert_eq!(memory, vec![0x40000000..0xfc000000]);
+        assert_eq!(fdt.memory_reservations().count(), 0);
+    }
+
+    #[test]
+    fn test_translates_peripheral_addresses() {
+        let fdt = Fdt::new(RPI4B_DTB).unwrap();
+
+        let mailbox = fdt.find_compatible("brcm,bcm2835-mbox").unwrap();
+        assert_eq!(mailbox.reg().next(), Some((0x7e00b880, Some(0x40))));
+        assert_eq!(mailbox.reg_address(0), Some(0xfe00b880));
+
+        // Through an alias, and by name without the unit address
+        let uart = fdt.find_node("serial1").unwrap();
+        assert!(uart.is_compatible("arm,pl011"));
+        assert_eq!(uart.reg_address(0), Some(0xfe201000));
+        assert_eq!(
+            fdt.find_node("/soc/gpio").unwrap().reg_address(0),
+            Some(0xfe200000)
+        );
+
+        // The GIC sits behind the soc's third range
+        let gic = fdt.find_compatible("arm,gic-400").unwrap();
+        assert_eq!(gic.reg_address(0), Some(0xff841000));
+        assert_eq!(gic.reg_address(1), Some(0xff842000));
+
+        // Nodes without ranges on the path can't be translated
+        let cpu = fdt.find_node("/cpus/cpu@1").unwrap();
+        assert_eq!(cpu.reg().next(), Some((1, None)));
+        assert_eq!(cpu.reg_address(0), None);
+    }
+}
@@ -0,0 +1,400 @@
+//! Edits a flattened device tree in place before handing it to another kernel.
+//!
+//! The blob sits at the start of a larger buffer and grows into the room after it: adding
+//! or resizing a property moves everything behind it along and updates the header's
+//! offsets and sizes, so the result is always a valid blob `Fdt` can read back.
+
+use core::fmt;
+use core::ops::Range;
+
+use crate::fdt::{Fdt, FdtError, TOKEN_BEGIN_NODE, TOKEN_END_NODE, TOKEN_PROP};
+
+// Header fields, as indices of big endian u32s
+const TOTAL_SIZE: usize = 1;
+const OFF_DT_STRUCT: usize = 2;
+const OFF_DT_STRINGS: usize = 3;
+const OFF_MEM_RSVMAP: usize = 4;
+const VERSION: usize = 5;
+const SIZE_DT_STRINGS: usize = 8;
+const SIZE_DT_STRUCT: usize = 9;
+
+// Older blobs have no size_dt_struct to keep up to date
+const MIN_VERSION: u32 = 17;
+const RESERVATION_SIZE: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FdtWriteError {
+    Fdt(FdtError),
+    NoSpace { needed: usize, available: usize },
+    NodeNotFound,
+    InvalidName,
+}
+
+impl fmt::Display for FdtWriteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FdtWriteError::Fdt(err) => write!(f, "{err}"),
+            FdtWriteError::NoSpace { needed, available } => {
+                write!(f, "device tree needs {needed} bytes, {available} available")
+            }
+            FdtWriteError::NodeNotFound => write!(f, "no such device tree node"),
+            FdtWriteError::InvalidName => write!(f, "invalid device tree node name"),
+        }
+    }
+}
+
+impl From<FdtError> for FdtWriteError {
+    fn from(err: FdtError) -> Self {
+        FdtWriteError::Fdt(err)
+    }
+}
+
+pub struct FdtWriter<'a> {
+    buf: &'a mut [u8],
+}
+
+impl<'a> FdtWriter<'a> {
+    /// Edits the blob at the start of `buf`, using the rest of `buf` as room to grow.
+    pub fn new(buf: &'a mut [u8]) -> Result<Self, FdtWriteError> {
+        Fdt::new(buf)?;
+        let writer = FdtWriter { buf };
+        let version = writer.header(VERSION) as u32;
+        if version < MIN_VERSION {
+            return Err(FdtError::UnsupportedVersion(version).into());
+        }
+        Ok(writer)
+    }
+
+    /// The blob as edited so far.
+    pub fn fdt(&self) -> Fdt<'_> {
+        Fdt::new(self.buf).expect("edits keep the blob valid")
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.header(TOTAL_SIZE)]
+    }
+
+    /// Sets property `name` of the node at `path`, adding it if the node doesn't have it.
+    pub fn set_property(
+        &mut self,
+        path: &str,
+        name: &str,
+        value: &[u8],
+    ) -> Result<(), FdtWriteError> {
+        self.write_property(path, name, value.len(), |out| out.copy_from_slice(value))
+    }
+
+    /// Sets a string property, adding the terminating NUL.
+    pub fn set_property_str(
+        &mut self,
+        path: &str,
+        name: &str,
+        value: &str,
+    ) -> Result<(), FdtWriteError> {
+        self.write_property(path, name, value.len() + 1, |out| {
+            out[..value.len()].copy_from_slice(value.as_bytes());
+            out[value.len()] = 0;
+        })
+    }
+
+    pub fn set_property_u32(
+        &mut self,
+        path: &str,
+        name: &str,
+        value: u32,
+    ) -> Result<(), FdtWriteError> {
+        self.set_property(path, name, &value.to_be_bytes())
+    }
+
+    /// Sets a two cell property.
+    pub fn set_property_u64(
+        &mut self,
+        path: &str,
+        name: &str,
+        value: u64,
+    ) -> Result<(), FdtWriteError> {
+        self.set_property(path, name, &value.to_be_bytes())
+    }
+
+    /// Removes property `name` from the node at `path`, if it has it.
+    pub fn remove_property(&mut self, path: &str, name: &str) -> Result<(), FdtWriteError> {
+        let fdt = self.fdt();
+        let node = fdt.find_node(path).ok_or(FdtWriteError::NodeNotFound)?;
+        if let Some((at, size)) = find_property(&fdt, node.offset(), name) {
+            let at = self.header(OFF_DT_STRUCT) + at;
+            self.splice(OFF_DT_STRUCT, at, size, 0)?;
+        }
+        Ok(())
+    }
+
+    /// Adds an empty node `name` under the node at `parent`, unless it already exists.
+    pub fn add_node(&mut self, parent: &str, name: &str) -> Result<(), FdtWriteError> {
+        if name.is_empty() || name.contains(['/', '\0']) {
+            return Err(FdtWriteError::InvalidName);
+        }
+        let fdt = self.fdt();
+        let parent = fdt.find_node(parent).ok_or(FdtWriteError::NodeNotFound)?;
+        if parent.children().any(|child| child.name() == name) {
+            return Ok(());
+        }
+        // New children go last, just before the parent's END_NODE
+        let end = fdt.skip_node(parent.offset()).ok_or(FdtError::Truncated)? - 4;
+
+        let name_size = (name.len() + 1).next_multiple_of(4);
+        let at = self.header(OFF_DT_STRUCT) + end;
+        self.splice(OFF_DT_STRUCT, at, 0, 8 + name_size)?;
+        let out = &mut self.buf[at..at + 8 + name_size];
+        out[..4].copy_from_slice(&TOKEN_BEGIN_NODE.to_be_bytes());
+        out[4..4 + name.len()].copy_from_slice(name.as_bytes());
+        out[4 + name.len()..4 + name_size].fill(0);
+        out[4 + name_size..].copy_from_slice(&TOKEN_END_NODE.to_be_bytes());
+        Ok(())
+    }
+
+    /// Adds a `/memreserve/` entry asking the OS to leave `range` alone.
+    pub fn add_memory_reservation(&mut self, range: Range<u64>) -> Result<(), FdtWriteError> {
+        let count = self.fdt().memory_reservations().count();
+        let at = self.header(OFF_MEM_RSVMAP) + count * RESERVATION_SIZE;
+        self.splice(OFF_MEM_RSVMAP, at, 0, RESERVATION_SIZE)?;
+        self.buf[at..at + 8].copy_from_slice(&range.start.to_be_bytes());
+        self.buf[at + 8..at + 16].copy_from_slice(&(range.end - range.start).to_be_bytes());
+        Ok(())
+    }
+
+    /// Makes room for a `len` byte property and has `fill` write its value.
+    fn write_property(
+        &mut self,
+        path: &str,
+        name: &str,
+        len: usize,
+        fill: impl FnOnce(&mut [u8]),
+    ) -> Result<(), FdtWriteError> {
+        // Adding the name can move the structure block, so look the node up after
+        let name_offset = self.string_offset(name)?;
+
+        let fdt = self.fdt();
+        let node = fdt.find_node(path).ok_or(FdtWriteError::NodeNotFound)?;
+        // New properties go first, straight after the node name
+        let (at, old_size) =
+            find_property(&fdt, node.offset(), name).unwrap_or((fdt.node_body(node.offset()), 0));
+
+        let size = 12 + len.next_multiple_of(4);
+        let at = self.header(OFF_DT_STRUCT) + at;
+        self.splice(OFF_DT_STRUCT, at, old_size, size)?;
+        let out = &mut self.buf[at..at + size];
+        out[..4].copy_from_slice(&TOKEN_PROP.to_be_bytes());
+        out[4..8].copy_from_slice(&(len as u32).to_be_bytes());
+        out[8..12].copy_from_slice(&(name_offset as u32).to_be_bytes());
+        fill(&mut out[12..12 + len]);
+        out[12 + len..].fill(0);
+        Ok(())
+    }
+
+    /// Offset of `name` in the strings block, appending it if it isn't there yet.
+    fn string_offset(&mut self, name: &str) -> Result<usize, FdtWriteError> {
+        let start = self.header(OFF_DT_STRINGS);
+        let size = self.header(SIZE_DT_STRINGS);
+        let strings = &self.buf[start..start + size];
+        let found = strings.windows(name.len() + 1).position(|candidate| {
+            candidate[..name.len()] == *name.as_bytes() && candidate[name.len()] == 0
+        });
+        if let Some(offset) = found {
+            return Ok(offset);
+        }
+
+        // Padded so any block after the strings stays aligned
+        let padded = (name.len() + 1).next_multiple_of(8);
+        self.splice(OFF_DT_STRINGS, start + size, 0, padded)?;
+        let out = &mut self.buf[start + size..start + size + padded];
+        out[..name.len()].copy_from_slice(name.as_bytes());
+        out[name.len()..].fill(0);
+        Ok(size)
+    }
+
+    /// Replaces `remove` bytes at `at` inside `section` with `insert` bytes for the caller
+    /// to fill, moving the rest of the blob and fixing up the header.
+    fn splice(
+        &mut self,
+        section: usize,
+        at: usize,
+        remove: usize,
+        insert: usize,
+    ) -> Result<(), FdtWriteError> {
+        let total = self.header(TOTAL_SIZE);
+        let needed = total - remove + insert;
+        if needed > self.buf.len() {
+            return Err(FdtWriteError::NoSpace {
+                needed,
+                available: self.buf.len(),
+            });
+        }
+        self.buf.copy_within(at + remove..total, at + insert);
+
+        for field in [OFF_DT_STRUCT, OFF_DT_STRINGS, OFF_MEM_RSVMAP] {
+            let offset = self.header(field);
+            if field != section && offset >= at {
+                self.set_header(field, offset - remove + insert);
+            }
+        }
+        let size_field = match section {
+            OFF_DT_STRUCT => Some(SIZE_DT_STRUCT),
+            OFF_DT_STRINGS => Some(SIZE_DT_STRINGS),
+            _ => None,
+        };
+        if let Some(field) = size_field {
+            self.set_header(field, self.header(field) - remove + insert);
+        }
+        self.set_header(TOTAL_SIZE, needed);
+        Ok(())
+    }
+
+    fn header(&self, field: usize) -> usize {
+        u32::from_be_bytes(self.buf[field * 4..field * 4 + 4].try_into().unwrap()) as usize
+    }
+
+    fn set_header(&mut self, field: usize, value: usize) {
+        self.buf[field * 4..field * 4 + 4].copy_from_slice(&(value as u32).to_be_bytes());
+    }
+}
+
+/// Structure block offset and size of property `name` of the node at `node`.
+fn find_property(fdt: &Fdt, node: usize, name: &str) -> Option<(usize, usize)> {
+    let mut offset = fdt.node_body(node);
+    loop {
+        let start = fdt.skip_nops(offset);
+        let (property, next) = fdt.property_at(start)?;
+        if property.name == name {
+            return Some((start, next - start));
+        }
+        offset = next;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::string::String;
+    use std::vec;
+    use std::vec::Vec;
+
+    const RPI4B_DTB: &[u8] = include_bytes!("../tests/fixtures/dtb/rpi4b.dtb");
+
+    fn with_room(room: usize) -> Vec<u8> {
+        let mut buf = RPI4B_DTB.to_vec();
+        buf.resize(RPI4B_DTB.len() + room, 0xAA);
+        buf
+    }
+
+    #[test]
+    fn test_edits_round_trip() {
+        let mut buf = with_room(0x1000);
+        let mut writer = FdtWriter::new(&mut buf).unwrap();
+
+        let bootargs = "console=ttyS0,115200 root=/dev/mmcblk0p2 rootwait quiet";
+        writer
+            .set_property_str("/chosen", "bootargs", bootargs)
+            .unwrap();
+        writer
+            .set_property_u64("/chosen", "linux,initrd-start", 0xa400000)
+            .unwrap();
+        writer
+            .set_property_u64("/chosen", "linux,initrd-end", 0xa4c0123)
+            .unwrap();
+        writer.add_node("/", "reserved-memory").unwrap();
+        writer
+            .set_property_u32("/reserved-memory", "#size-cells", 1)
+            .unwrap();
+        writer.add_memory_reservation(0x2000000..0x2100000).unwrap();
+
+        // Read back from a copy, as another kernel would
+        let blob = writer.as_bytes().to_vec();
+        let fdt = Fdt::new(&blob).unwrap();
+        assert_eq!(fdt.bootargs(), Some(bootargs));
+        let chosen = fdt.find_node("/chosen").unwrap();
+        let cell = |name| chosen.property(name).and_then(|prop| prop.as_u64());
+        assert_eq!(cell("linux,initrd-start"), Some(0xa400000));
+        assert_eq!(cell("linux,initrd-end"), Some(0xa4c0123));
+        assert_eq!(fdt.initrd(), Some(0xa400000..0xa4c0123));
+        assert_eq!(
+            fdt.find_node("/reserved-memory")
+                .unwrap()
+                .property("#size-cells")
+                .and_then(|prop| prop.as_u32()),
+            Some(1)
+        );
+        let reserved: Vec<Range<u64>> = fdt.memory_reservations().collect();
+        assert_eq!(reserved, vec![0..0x1000, 0x2000000..0x2100000]);
+
+        // Everything else is where it was
+        let original = Fdt::new(RPI4B_DTB).unwrap();
+        assert!(fdt.memory().eq(original.memory()));
+        assert_eq!(
+            fdt.find_node("serial1").unwrap().reg_address(0),
+            Some(0xfe201000)
+        );
+        let names = |fdt: &Fdt| {
+            let names: Vec<String> = fdt
+                .root()
+                .children()
+                .map(|node| node.name().into())
+                .collect();
+            names
+        };
+        assert_eq!(names(&fdt)[..names(&original).len()], names(&original)[..]);
+        assert_eq!(names(&fdt).last().unwrap(), "reserved-memory");
+    }
+
+    #[test]
+    fn test_shrinks_and_reuses_strings() {
+        let mut buf = with_room(0x100);
+        let mut writer = FdtWriter::new(&mut buf).unwrap();
+        let strings_size = writer.header(SIZE_DT_STRINGS);
+
+        writer
+            .set_property_str("/chosen", "bootargs", "quiet")
+            .unwrap();
+        assert!(writer.as_bytes().len() < RPI4B_DTB.len());
+        // "reg" is already in the strings block, and so is the tail of "#address-cells"
+        writer.set_property_u32("/chosen", "reg", 0).unwrap();
+        writer.set_property_u32("/chosen", "cells", 0).unwrap();
+        assert_eq!(writer.header(SIZE_DT_STRINGS), strings_size);
+
+        writer.remove_property("/chosen", "cells").unwrap();
+        writer.remove_property("/chosen", "cells").unwrap();
+        assert!(
+            writer
+                .fdt()
+                .find_node("/chosen")
+                .unwrap()
+                .property("cells")
+                .is_none()
+        );
+
+        writer.add_node("/chosen", "chosen").unwrap();
+        writer.add_node("/chosen", "chosen").unwrap();
+        let fdt = writer.fdt();
+        assert_eq!(fdt.bootargs(), Some("quiet"));
+        assert_eq!(fdt.find_node("/chosen").unwrap().children().count(), 1);
+        assert!(fdt.find_node("/chosen/chosen").is_some());
+    }
+
+    #[test]
+    fn test_reports_missing_nodes_and_room() {
+        let mut buf = with_room(8);
+        let mut writer = FdtWriter::new(&mut buf).unwrap();
+
+        assert_eq!(
+            writer.set_property_u32("/nope", "reg", 0),
+            Err(FdtWriteError::NodeNotFound)
+        );
+        assert_eq!(writer.add_node("/", "a/b"), Err(FdtWriteError::InvalidName));
+        let long = "x".repeat(0x400);
+        assert!(matches!(
+            writer.set_property_str("/chosen", "bootargs", &long),
+            Err(FdtWriteError::NoSpace { needed, available })
+                if needed > available && available == RPI4B_DTB.len() + 8
+        ));
+        assert_eq!(writer.as_bytes(), RPI4B_DTB);
+    }
+}
@@ -0,0 +1,332 @@
+//! Physical memory map and 4 KiB frame allocator.
+//!
+//! RAM reported by the device tree or the mailbox starts out free, then everything already
+//! in use is reserved: the boot stack, this image and the secondary core stacks, the areas
+//! kernels are loaded into, the DTB and the framebuffer. Frames are tracked in a bitmap with
+//! a bit set while the frame is free and a second one with a bit set while it is allocated,
+//! which tells handed out frames apart from reserved ones and memory that was never RAM. An
+//! empty allocator is all zeroes, so the global one costs nothing in the image.
+
+use core::fmt;
+use core::ops::Range;
+
+use crate::mmu::PAGE_SIZE;
+use crate::sync::SpinLock;
+
+pub const FRAME_SIZE: u64 = PAGE_SIZE;
+/// RAM above this isn't handed out, the largest Pi 4 has 8 GiB
+pub const MAX_MEMORY: u64 = 8 << 30;
+
+const BITS: usize = u64::BITS as usize;
+const WORDS: usize = (MAX_MEMORY / FRAME_SIZE) as usize / BITS;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameError {
+    OutOfMemory,
+    /// An address or alignment that isn't a whole number of frames
+    Unaligned,
+    /// Freeing a frame that wasn't handed out: already free, reserved, or not RAM at all
+    NotAllocated,
+    /// Asking for a run of no frames
+    NoFrames,
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::OutOfMemory => write!(f, "out of memory"),
+            FrameError::Unaligned => write!(f, "not frame aligned"),
+            FrameError::NotAllocated => write!(f, "frame not allocated"),
+            FrameError::NoFrames => write!(f, "no frames requested"),
+        }
+    }
+}
+
+/// Frames from address 0 up to `N` * 64 frames.
+pub struct FrameAllocator<const N: usize> {
+    free: [u64; N],
+    allocated: [u64; N],
+    free_frames: usize,
+    total_frames: usize,
+    // Word the last frame came from, where the next search starts
+    next: usize,
+}
+
+impl<const N: usize> Default for FrameAllocator<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> FrameAllocator<N> {
+    const CAPACITY: u64 = (N * BITS) as u64;
+
+    /// An allocator with no RAM.
+    pub const fn new() -> Self {
+        FrameAllocator {
+            free: [0; N],
+            allocated: [0; N],
+            free_frames: 0,
+            total_frames: 0,
+            next: 0,
+        }
+    }
+
+    fn is_free(&self, frame: u64) -> bool {
+        self.free[frame as usize / BITS] & (1 << (frame as usize % BITS)) != 0
+    }
+
+    fn is_allocated(&self, frame: u64) -> bool {
+        self.allocated[frame as usize / BITS] & (1 << (frame as usize % BITS)) != 0
+    }
+
+    fn set_free(&mut self, frame: u64, free: bool) {
+        let word = &mut self.free[frame as usize / BITS];
+        let bit = 1 << (frame as usize % BITS);
+        if free {
+            *word |= bit;
+            self.free_frames += 1;
+        } else {
+            *word &= !bit;
+            self.free_frames -= 1;
+        }
+    }
+
+    /// Moves a free frame to allocated, or back.
+    fn set_allocated(&mut self, frame: u64, allocated: bool) {
+        let word = &mut self.allocated[frame as usize / BITS];
+        let bit = 1 << (frame as usize % BITS);
+        if allocated {
+            *word |= bit;
+        } else {
+            *word &= !bit;
+        }
+        self.set_free(frame, !allocated);
+    }
+
+    /// Hands out the whole frames inside `range`. RAM added twice is only counted once, so
+    /// overlapping reports from the device tree and the mailbox can both be added.
+    pub fn add_ram(&mut self, range: Range<u64>) {
+        let end = (range.end / FRAME_SIZE).min(Self::CAPACITY);
+        for frame in range.start.div_ceil(FRAME_SIZE)..end {
+            if !self.is_free(frame) {
+                self.set_free(frame, true);
+                self.total_frames += 1;
+            }
+        }
+    }
+
+    /// Keeps every frame overlapping `range` from being handed out.
+    pub fn reserve(&mut self, range: Range<u64>) {
+        let end = range.end.div_ceil(FRAME_SIZE).min(Self::CAPACITY);
+        for frame in range.start / FRAME_SIZE..end {
+            if self.is_free(frame) {
+                self.set_free(frame, false);
+            }
+        }
+    }
+
+    /// The address of a free frame, now allocated.
+    pub fn allocate(&mut self) -> Result<u64, FrameError> {
+        for offset in 0..N {
+            let word = (self.next + offset) % N;
+            if self.free[word] != 0 {
+                let frame = (word * BITS) as u64 + self.free[word].trailing_zeros() as u64;
+                self.set_allocated(frame, true);
+                self.next = word;
+                return Ok(frame * FRAME_SIZE);
+            }
+        }
+        Err(FrameError::OutOfMemory)
+    }
+
+    /// `count` physically contiguous frames starting at a multiple of `align` bytes, for
+    /// buffers other bus masters use that don't fit in a frame. `count` must not be zero.
+    pub fn allocate_contiguous(
+        &mut self,
+        count: usize,
+        align: u64,
+    ) -> Result<Range<u64>, FrameError> {
+        if count == 0 {
+            return Err(FrameError::NoFrames);
+        }
+        if !align.is_power_of_two() || !align.is_multiple_of(FRAME_SIZE) {
+            return Err(FrameError::Unaligned);
+        }
+        let step = align / FRAME_SIZE;
+        let count = count as u64;
+        let mut start = 0;
+        while start + count <= Self::CAPACITY {
+            match (start..start + count)
+                .rev()
+                .find(|&frame| !self.is_free(frame))
+            {
+                Some(used) => start = (used + 1).next_multiple_of(step),
+                None => {
+                    for frame in start..start + count {
+                        self.set_allocated(frame, true);
+                    }
+                    return Ok(start * FRAME_SIZE..(start + count) * FRAME_SIZE);
+                }
+            }
+        }
+        Err(FrameError::OutOfMemory)
+    }
+
+    /// Returns the frame at `addr`, which must have come from `allocate` or
+    /// `allocate_contiguous`.
+    pub fn free(&mut self, addr: u64) -> Result<(), FrameError> {
+        if !addr.is_multiple_of(FRAME_SIZE) {
+            return Err(FrameError::Unaligned);
+        }
+        let frame = addr / FRAME_SIZE;
+        if frame >= Self::CAPACITY || !self.is_allocated(frame) {
+            return Err(FrameError::NotAllocated);
+        }
+        self.set_allocated(frame, false);
+        Ok(())
+    }
+
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    /// Frames of RAM, whether free, allocated or reserved.
+    pub fn total_frames(&self) -> usize {
+        self.total_frames
+    }
+}
+
+static FRAMES: SpinLock<FrameAllocator<WORDS>> = SpinLock::new(FrameAllocator::new());
+
+/// Adds `ram` to the global allocator, minus `reserved`. Returns the number of free frames.
+pub fn init(
+    ram: impl IntoIterator<Item = Range<u64>>,
+    reserved: impl IntoIterator<Item = Range<u64>>,
+) -> usize {
+    let mut frames = FRAMES.lock();
+    for bank in ram {
+        frames.add_ram(bank);
+    }
+    for range in reserved {
+        frames.reserve(range);
+    }
+    frames.free_frames()
+}
+
+pub fn allocate() -> Result<u64, FrameError> {
+    FRAMES.lock().allocate()
+}
+
+pub fn allocate_contiguous(count: usize, align: u64) -> Result<Range<u64>, FrameError> {
+    FRAMES.lock().allocate_contiguous(count, align)
+}
+
+pub fn free(addr: u64) -> Result<(), FrameError> {
+    FRAMES.lock().free(addr)
+}
+
+pub fn free_frames() -> usize {
+    FRAMES.lock().free_frames()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::BTreeSet;
+
+    // 256 frames, 1 MiB
+    type Allocator = FrameAllocator<4>;
+
+    #[test]
+    fn test_hands_out_ram_minus_reservations() {
+        let mut frames = Allocator::new();
+        assert_eq!(frames.allocate(), Err(FrameError::OutOfMemory));
+
+        // Partial frames at either end of the RAM aren't usable
+        frames.add_ram(0x800..0x10_0000);
+        frames.add_ram(0x1000..0x2000);
+        assert_eq!(frames.total_frames(), 255);
+        // Partial frames at either end of a reservation are lost
+        frames.reserve(0x1800..0x8_0800);
+        assert_eq!(frames.free_frames(), 255 - 128);
+
+        let mut allocated = BTreeSet::new();
+        while let Ok(addr) = frames.allocate() {
+            assert!(addr.is_multiple_of(FRAME_SIZE));
+            assert!((0x8_1000..0x10_0000).contains(&addr));
+            assert!(allocated.insert(addr));
+        }
+        assert_eq!(allocated.len(), 255 - 128);
+        assert_eq!(frames.free_frames(), 0);
+
+        assert_eq!(frames.free(0x8_1000), Ok(()));
+        assert_eq!(frames.allocate(), Ok(0x8_1000));
+    }
+
+    #[test]
+    fn test_rejects_bad_frees() {
+        let mut frames = Allocator::new();
+        frames.add_ram(0..0x10_0000);
+        let addr = frames.allocate().unwrap();
+        assert_eq!(frames.free(addr + 8), Err(FrameError::Unaligned));
+        assert_eq!(frames.free(addr), Ok(()));
+        assert_eq!(frames.free(addr), Err(FrameError::NotAllocated));
+        assert_eq!(frames.free(0x10_0000), Err(FrameError::NotAllocated));
+        assert_eq!(frames.free_frames(), 256);
+    }
+
+    #[test]
+    fn test_only_frees_frames_it_handed_out() {
+        let mut frames = Allocator::new();
+        frames.add_ram(0x1_0000..0x10_0000);
+        frames.reserve(0x1_0000..0x2_0000);
+        let free = frames.free_frames();
+
+        // Reserved frames and memory below the RAM stay out of the free pool
+        assert_eq!(frames.free(0x1_0000), Err(FrameError::NotAllocated));
+        assert_eq!(frames.free(0), Err(FrameError::NotAllocated));
+        assert_eq!(frames.free_frames(), free);
+
+        let run = frames.allocate_contiguous(4, FRAME_SIZE).unwrap();
+        assert_eq!(run, 0x2_0000..0x2_4000);
+        for addr in run.clone().step_by(FRAME_SIZE as usize) {
+            assert_eq!(frames.free(addr), Ok(()));
+        }
+        assert_eq!(frames.free(run.start), Err(FrameError::NotAllocated));
+        assert_eq!(frames.free_frames(), free);
+    }
+
+    #[test]
+    fn test_allocates_contiguous_aligned_runs() {
+        let mut frames = Allocator::new();
+        frames.add_ram(0..0x10_0000);
+        frames.reserve(0x1_0000..0x1_1000);
+        frames.reserve(0x2_3000..0x2_4000);
+
+        // Skips past the reserved frame at 64 KiB, then the one at 140 KiB
+        assert_eq!(frames.allocate_contiguous(16, 0x1_0000), Ok(0..0x1_0000));
+        assert_eq!(
+            frames.allocate_contiguous(32, 0x1_0000),
+            Ok(0x3_0000..0x5_0000)
+        );
+        assert_eq!(
+            frames.allocate_contiguous(2, FRAME_SIZE),
+            Ok(0x1_1000..0x1_3000)
+        );
+        assert_eq!(frames.free_frames(), 256 - 2 - 16 - 32 - 2);
+        assert_eq!(
+            frames.allocate_contiguous(1, 0x1800),
+            Err(FrameError::Unaligned)
+        );
+        assert_eq!(
+            frames.allocate_contiguous(256, FRAME_SIZE),
+            Err(FrameError::OutOfMemory)
+        );
+        assert_eq!(
+            frames.allocate_contiguous(0, FRAME_SIZE),
+            Err(FrameError::NoFrames)
+        );
+    }
+}
@@ -1,8 +1,11 @@
-use core::ptr::write_volatile;
+use core::ptr::{read_volatile, write_volatile};
 
+use crate::aa_font::{self, AaFont};
 use crate::mailbox::MailboxInterface;
+use crate::sync::SpinLock;
 
-const CHANNEL_FRAMEBUFFER: u8 = 8;
+/// Property tag channel, which framebuffer requests and every other VideoCore query use
+pub const CHANNEL_FRAMEBUFFER: u8 = 8;
 const WIDTH: u32 = 1920;
 const HEIGHT: u32 = 1080;
 
@@ -62,7 +65,7 @@ struct FrameBufferInitMailbox {
     end_tag: u32,
 }
 
-static mut FB_MAILBOX: FrameBufferInitMailbox = FrameBufferInitMailbox {
+static FB_MAILBOX: SpinLock<FrameBufferInitMailbox> = SpinLock::new(FrameBufferInitMailbox {
     // Size of the whole message in bytes
     size: size_of::<FrameBufferInitMailbox>() as u32,
     code: 0x00000000,
@@ -115,7 +118,18 @@ static mut FB_MAILBOX: FrameBufferInitMailbox = FrameBufferInitMailbox {
 
     // End tag
     end_tag: 0,
-};
+});
+
+/// Where the display `with_resolution` last set up lives
+#[derive(Clone, Copy)]
+struct Display {
+    addr: usize,
+    width: usize,
+    height: usize,
+    pitch: usize,
+}
+
+static DISPLAY: SpinLock<Option<Display>> = SpinLock::new(None);
 
 #[repr(C, align(16))]
 struct FrameBufferSetOffsetMailbox {
@@ -140,7 +154,17 @@ pub struct FrameBuffer<'a, M: MailboxInterface> {
 
 impl<'a, M: MailboxInterface> FrameBuffer<'a, M> {
     pub fn new(mailbox: &'a mut M) -> Option<Self> {
-        let mailbox_ptr = &raw mut FB_MAILBOX as *mut _ as *mut u32;
+        Self::with_resolution(mailbox, WIDTH, HEIGHT)
+    }
+
+    /// Sets the display up at `width` x `height`, double buffered.
+    pub fn with_resolution(mailbox: &'a mut M, width: u32, height: u32) -> Option<Self> {
+        let mut request = FB_MAILBOX.lock();
+        request.physical_width = width;
+        request.physical_height = height;
+        request.virtual_width = width;
+        request.virtual_height = height * 2;
+        let mailbox_ptr = &mut *request as *mut _ as *mut u32;
 
         if mailbox.call(CHANNEL_FRAMEBUFFER, mailbox_ptr) {
             let fb_mailbox = unsafe { &*(mailbox_ptr as *const FrameBufferInitMailbox) };
@@ -149,6 +173,13 @@ impl<'a, M: MailboxInterface> FrameBuffer<'a, M> {
             let pitch = fb_mailbox.pitch as usize;
             let width = fb_mailbox.virtual_width as usize;
             let height = fb_mailbox.virtual_height as usize;
+            drop(request);
+            *DISPLAY.lock() = Some(Display {
+                addr: ptr as usize,
+                width,
+                height,
+                pitch,
+            });
 
             let fb = FrameBuffer {
                 ptr,
@@ -167,6 +198,20 @@ impl<'a, M: MailboxInterface> FrameBuffer<'a, M> {
         None
     }
 
+    /// The display already set up by `with_resolution`, without asking the firmware for
+    /// a new one or clearing it.
+    pub fn current(mailbox: &'a mut M) -> Option<Self> {
+        let display = (*DISPLAY.lock())?;
+        Some(FrameBuffer {
+            ptr: display.addr as *mut u32,
+            width: display.width,
+            height: display.height,
+            pitch: display.pitch,
+            mailbox,
+            current_offset: 0,
+        })
+    }
+
     pub fn clear(&self, color: u32) {
         for y in 0..self.height {
             for x in 0..self.width {
@@ -214,24 +259,114 @@ impl<'a, M: MailboxInterface> FrameBuffer<'a, M> {
         }
     }
 
+    /// Draws `ch` anti-aliased at a fractional pixel `size`, with (x, y) being the top-left
+    /// corner of its line box. Returns the pen advance in 1/64 pixels.
+    pub fn draw_aa_glyph(
+        &self,
+        x: usize,
+        y: usize,
+        ch: u8,
+        color: u32,
+        size: f32,
+        font: &AaFont,
+    ) -> u32 {
+        let atlas = font.atlas_for(size);
+        let Some(glyph) = atlas.glyph(ch) else {
+            return 0;
+        };
+        let scale = aa_font::scale_for(atlas, size);
+        if scale <= 0 {
+            return 0;
+        }
+
+        // Glyph box in destination pixels, relative to the pen position on the baseline
+        let left = (glyph.x_min * scale) >> 8;
+        let top = -(((glyph.y_min + glyph.height as i32) * scale + 255) >> 8);
+        let width = (glyph.width as i32 * scale + 255) >> 8;
+        let height = (glyph.height as i32 * scale + 255) >> 8;
+        let baseline = y as i32 + ((atlas.ascent * scale + 128) >> 8);
+
+        for dy in 0..height {
+            // Map the destination pixel center back into the atlas, in 1/256 pixels
+            let src_y = ((dy << 8) + 128) * 256 / scale - 128;
+            for dx in 0..width {
+                let src_x = ((dx << 8) + 128) * 256 / scale - 128;
+                let alpha = atlas.sample(glyph, src_x, src_y);
+                let (px, py) = (x as i32 + left + dx, baseline + top + dy);
+                if alpha != 0 && px >= 0 && py >= 0 {
+                    self.blend_pixel(px as usize, py as usize, color, alpha);
+                }
+            }
+        }
+
+        (glyph.advance as u64 * scale as u64 / 256) as u32
+    }
+
+    /// Draws a line of anti-aliased text and returns its width in pixels.
+    pub fn draw_aa_text(
+        &self,
+        x: usize,
+        y: usize,
+        text: &str,
+        color: u32,
+        size: f32,
+        font: &AaFont,
+    ) -> usize {
+        // Pen position is tracked in 1/64 pixels so fractional advances don't accumulate error
+        let mut pen = 0u32;
+        for ch in text.bytes() {
+            pen += self.draw_aa_glyph(x + ((pen + 32) >> 6) as usize, y, ch, color, size, font);
+        }
+        ((pen + 32) >> 6) as usize
+    }
+
+    /// Mixes `color` over the existing pixel with `alpha` coverage (0 = transparent).
+    pub fn blend_pixel(&self, x: usize, y: usize, color: u32, alpha: u8) {
+        match alpha {
+            0 => {}
+            255 => self.draw_pixel(x, y, color),
+            _ => {
+                if let Some(current) = self.read_pixel(x, y) {
+                    self.draw_pixel(x, y, blend(current, color, alpha));
+                }
+            }
+        }
+    }
+
+    pub fn read_pixel(&self, x: usize, y: usize) -> Option<u32> {
+        let offset = self.pixel_offset(x, y)?;
+        assert!(!self.ptr.is_null(), "Frame buffer pointer is null!");
+        Some(unsafe { read_volatile(self.ptr.add(offset)) })
+    }
+
     pub fn draw_pixel(&self, x: usize, y: usize, color: u32) {
+        if let Some(offset) = self.pixel_offset(x, y) {
+            assert!(!self.ptr.is_null(), "Frame buffer pointer is null!");
+            unsafe {
+                write_volatile(self.ptr.add(offset), color);
+            }
+        }
+    }
+
+    fn pixel_offset(&self, x: usize, y: usize) -> Option<usize> {
         if x < self.width && y < self.height {
             let adjusted_y = y.saturating_add(self.current_offset as usize);
-            let offset = adjusted_y
+            adjusted_y
                 .checked_mul(self.pitch / 4)
-                .and_then(|row| row.checked_add(x));
-            if let Some(offset) = offset {
-                assert!(!self.ptr.is_null(), "Frame buffer pointer is null!");
-                unsafe {
-                    write_volatile(self.ptr.add(offset), color);
-                }
-            }
+                .and_then(|row| row.checked_add(x))
+        } else {
+            None
         }
     }
 
     pub fn swap_buffer(&mut self) {
         self.set_virtual_offset(self.current_offset);
-        self.current_offset = if self.current_offset == 0 { HEIGHT } else { 0 };
+        // The back buffer is the lower half of the virtual display
+        self.current_offset = if self.current_offset == 0 {
+            self.height as u32 / 2
+        } else {
+            0
+        };
     }
 
     fn set_virtual_offset(&self, y_offset: u32) {
@@ -256,18 +391,37 @@ impl<'a, M: MailboxInterface> FrameBuffer<'a, M> {
     }
 }
 
+/// Linearly interpolates each 8-bit channel from `dst` towards `src` by `alpha`/255.
+fn blend(dst: u32, src: u32, alpha: u8) -> u32 {
+    let alpha = alpha as u32;
+    let mut out = 0;
+    for shift in [0, 8, 16, 24] {
+        let d = (dst >> shift) & 0xFF;
+        let s = (src >> shift) & 0xFF;
+        let c = (s * alpha + d * (255 - alpha) + 127) / 255;
+        out |= c << shift;
+    }
+    out
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::cell::Cell;
+
+    use crate::aa_font::DEJAVU_SANS;
 
-    static PTR: AtomicUsize = AtomicUsize::new(0);
+    // Each test runs on its own thread, so a thread-local keeps parallel tests from
+    // handing each other's mock framebuffers to `FrameBuffer::new`
+    std::thread_local! {
+        static PTR: Cell<usize> = const { Cell::new(0) };
+    }
 
     impl<'a, M: MailboxInterface> FrameBuffer<'a, M> {
         #[cfg(test)]
         pub fn translate_ptr_for_cpu(_addr: u32) -> *mut u32 {
-            let addr = PTR.load(Ordering::Relaxed);
+            let addr = PTR.get();
             addr as *mut u32
         }
     }
@@ -297,8 +451,10 @@ mod tests {
             let fb_ptr_host = framebuffer.as_ptr() as usize;
             let fb_ptr = fb_ptr_host as u32; // will be truncated, but that's okay
 
-            let mut fb_mailbox = FrameBufferInitMailbox::default();
-            fb_mailbox.fb_ptr = fb_ptr;
+            let fb_mailbox = FrameBufferInitMailbox {
+                fb_ptr,
+                ..Default::default()
+            };
 
             Self {
                 fb_mailbox,
@@ -328,20 +484,20 @@ mod tests {
     #[test]
     fn test_draw_pixel_sets_expected_location() {
         let mut mock = MockMailbox::new();
-        PTR.store(mock.fb_ptr_host, Ordering::Relaxed);
+        PTR.set(mock.fb_ptr_host);
 
         let fb = FrameBuffer::new(&mut mock).unwrap();
 
         fb.draw_pixel(1, 1, 0xABCDEF);
 
-        let offset = 1 * WIDTH + 1;
+        let offset = WIDTH + 1;
         assert_eq!(mock.framebuffer[offset], 0xABCDEF);
     }
 
     #[test]
     fn test_clear_fills_entire_framebuffer() {
         let mut mock = MockMailbox::new();
-        PTR.store(mock.fb_ptr_host, Ordering::Relaxed);
+        PTR.set(mock.fb_ptr_host);
 
         let fb = FrameBuffer::new(&mut mock).unwrap();
         fb.clear(0x123456);
@@ -354,7 +510,7 @@ mod tests {
     #[test]
     fn test_draw_glyph_draws_scaled_pixels() {
         let mut mock = MockMailbox::new();
-        PTR.store(mock.fb_ptr_host, Ordering::Relaxed);
+        PTR.set(mock.fb_ptr_host);
 
         // Simple font: only one glyph, 'A' (65) with 8 rows (8x8 font), a pattern for test
         let mut font = [[0u8; 8]; 128];
@@ -390,4 +546,45 @@ mod tests {
 
         assert!(pixels_set > 0, "No pixels drawn for glyph");
     }
+
+    #[test]
+    fn test_blend_mixes_each_channel() {
+        assert_eq!(blend(0x000000, 0xFFFFFF, 0), 0x000000);
+        assert_eq!(blend(0x000000, 0xFFFFFF, 255), 0xFFFFFF);
+        assert_eq!(blend(0x000000, 0xFF8000, 128), 0x804000);
+        assert_eq!(blend(0x204060, 0x204060, 77), 0x204060);
+    }
+
+    #[test]
+    fn test_draw_aa_text_blends_partial_coverage() {
+        let mut mock = MockMailbox::new();
+        PTR.set(mock.fb_ptr_host);
+
+        let (x, y, size) = (20, 20, 27.5);
+        let (width, fb_width) = {
+            let fb = FrameBuffer::new(&mut mock).unwrap();
+            fb.clear(0);
+            let width = fb.draw_aa_text(x, y, "Hello", 0xFFFFFF, size, &DEJAVU_SANS);
+            (width, fb.width)
+        };
+        assert!(width > 0, "Text has no width");
+
+        // Anti-aliased edges produce gray pixels besides fully covered white ones
+        let mut solid = 0;
+        let mut partial = 0;
+        for py in y..y + size as usize {
+            for px in x..x + width {
+                match mock.framebuffer[py * fb_width + px] {
+                    0 => {}
+                    0xFFFFFF => solid += 1,
+                    pixel => {
+                        assert_eq!(pixel & 0xFF, (pixel >> 16) & 0xFF, "Gray expected");
+                        partial += 1;
+                    }
+                }
+            }
+        }
+        assert!(solid > 0, "No solid pixels drawn");
+        assert!(partial > 0, "No anti-aliased pixels drawn");
+    }
 }
@@ -0,0 +1,333 @@
+//! ARM GIC-400 interrupt controller and IRQ dispatch.
+//!
+//! The BCM2711 routes its peripheral interrupts through a GIC-400 as SPIs, numbered from
+//! 32 up. Handlers are plain functions registered in an `IrqTable`, which the exception
+//! handler asks to acknowledge and dispatch the pending interrupt.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::mmio::{Mmio, MmioInterface};
+use crate::platform::Platform;
+
+/// Distributor and CPU interface in the BCM2711's low peripheral mode
+pub const GICD_BASE: usize = 0xFF841000;
+pub const GICC_BASE: usize = 0xFF842000;
+
+/// Interrupt ID the CPU interface returns when nothing is pending
+pub const SPURIOUS_IRQ: u32 = 1023;
+/// Interrupts the GIC-400 supports at most, SGIs and PPIs included
+pub const MAX_IRQS: usize = 480;
+/// Priority `init` gives every interrupt, lower values are more urgent
+pub const DEFAULT_PRIORITY: u8 = 0xA0;
+
+// Distributor registers
+const GICD_CTLR: usize = 0x000;
+const GICD_TYPER: usize = 0x004;
+const GICD_ISENABLER: usize = 0x100;
+const GICD_ICENABLER: usize = 0x180;
+const GICD_ICPENDR: usize = 0x280;
+const GICD_IPRIORITYR: usize = 0x400;
+const GICD_ITARGETSR: usize = 0x800;
+const GICD_ICFGR: usize = 0xC00;
+
+// CPU interface registers
+const GICC_CTLR: usize = 0x000;
+const GICC_PMR: usize = 0x004;
+const GICC_BPR: usize = 0x008;
+const GICC_IAR: usize = 0x00C;
+const GICC_EOIR: usize = 0x010;
+
+const CTLR_ENABLE: u32 = 1;
+const IAR_ID_MASK: u32 = 0x3FF;
+// SGIs and PPIs are banked per core, SPIs start here
+const FIRST_SPI: u32 = 32;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Trigger {
+    Level,
+    Edge,
+}
+
+/// Handlers the exception vectors dispatch IRQs to.
+pub static IRQ_HANDLERS: IrqTable = IrqTable::new();
+
+pub struct Gic<D: MmioInterface, C: MmioInterface> {
+    dist: D,
+    cpu: C,
+}
+
+impl<D: MmioInterface, C: MmioInterface> Gic<D, C> {
+    pub const fn new(dist: D, cpu: C) -> Self {
+        Gic { dist, cpu }
+    }
+
+    /// Number of interrupt IDs the distributor implements.
+    pub fn irq_count(&self) -> u32 {
+        let lines = self.dist.read(GICD_TYPER) & 0x1F;
+        (32 * (lines + 1)).min(MAX_IRQS as u32)
+    }
+
+    /// Resets the distributor with every interrupt disabled, level triggered, at
+    /// `DEFAULT_PRIORITY` and targeting core 0, then enables it and this core's CPU
+    /// interface.
+    pub fn init(&self) {
+        self.dist.write(GICD_CTLR, 0);
+
+        let count = self.irq_count() as usize;
+        for word in 0..count / 32 {
+            self.dist.write(GICD_ICENABLER + word * 4, u32::MAX);
+            self.dist.write(GICD_ICPENDR + word * 4, u32::MAX);
+        }
+        let priorities = u32::from_ne_bytes([DEFAULT_PRIORITY; 4]);
+        for word in 0..count / 4 {
+            self.dist.write(GICD_IPRIORITYR + word * 4, priorities);
+        }
+        for word in FIRST_SPI as usize / 4..count / 4 {
+            self.dist.write(GICD_ITARGETSR + word * 4, 0x0101_0101);
+        }
+        for word in FIRST_SPI as usize / 16..count / 16 {
+            self.dist.write(GICD_ICFGR + word * 4, 0);
+        }
+        self.dist.write(GICD_CTLR, CTLR_ENABLE);
+
+        self.init_cpu();
+    }
+
+    /// Lets every priority through this core's CPU interface and enables it. Secondary
+    /// cores call this after core 0 has run `init`.
+    pub fn init_cpu(&self) {
+        self.cpu.write(GICC_PMR, 0xFF);
+        self.cpu.write(GICC_BPR, 0);
+        self.cpu.write(GICC_CTLR, CTLR_ENABLE);
+    }
+
+    pub fn enable(&self, irq: u32) {
+        let (word, bit) = bit_position(irq, 1);
+        self.dist.write(GICD_ISENABLER + word, 1 << bit);
+    }
+
+    pub fn disable(&self, irq: u32) {
+        let (word, bit) = bit_position(irq, 1);
+        self.dist.write(GICD_ICENABLER + word, 1 << bit);
+    }
+
+    pub fn set_priority(&self, irq: u32, priority: u8) {
+        let (word, shift) = bit_position(irq, 8);
+        self.dist.modify(
+            GICD_IPRIORITYR + word,
+            0xFF << shift,
+            (priority as u32) << shift,
+        );
+    }
+
+    /// Routes an SPI to the cores set in `cpu_mask`.
+    pub fn set_targets(&self, irq: u32, cpu_mask: u8) {
+        let (word, shift) = bit_position(irq, 8);
+        self.dist.modify(
+            GICD_ITARGETSR + word,
+            0xFF << shift,
+            (cpu_mask as u32) << shift,
+        );
+    }
+
+    pub fn set_trigger(&self, irq: u32, trigger: Trigger) {
+        let (word, shift) = bit_position(irq, 2);
+        let edge = match trigger {
+            Trigger::Level => 0,
+            Trigger::Edge => 0b10,
+        };
+        self.dist
+            .modify(GICD_ICFGR + word, 0b10 << shift, edge << shift);
+    }
+
+    /// Acknowledges the highest priority pending interrupt, returning the raw IAR value to
+    /// pass to `end_of_interrupt`, or `None` if nothing was pending.
+    pub fn acknowledge(&self) -> Option<u32> {
+        let iar = self.cpu.read(GICC_IAR);
+        (iar & IAR_ID_MASK != SPURIOUS_IRQ).then_some(iar)
+    }
+
+    pub fn end_of_interrupt(&self, iar: u32) {
+        self.cpu.write(GICC_EOIR, iar);
+    }
+}
+
+impl Gic<Mmio, Mmio> {
+    pub fn from_platform(platform: &Platform) -> Self {
+        Gic::new(Mmio::new(platform.gicd_base), Mmio::new(platform.gicc_base))
+    }
+}
+
+/// Byte offset of the register holding `irq`'s field in a bank of `bits` wide fields, and
+/// the field's shift within it.
+fn bit_position(irq: u32, bits: u32) -> (usize, u32) {
+    let per_word = 32 / bits;
+    (((irq / per_word) * 4) as usize, (irq % per_word) * bits)
+}
+
+/// Interrupt ID part of an IAR value.
+pub fn irq_id(iar: u32) -> u32 {
+    iar & IAR_ID_MASK
+}
+
+pub type IrqHandler = fn(irq: u32);
+
+/// What `IrqTable::dispatch` did with the pending interrupt.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Dispatch {
+    Handled(u32),
+    /// Nobody had registered for it, so it was disabled to stop it firing again
+    Unhandled(u32),
+    Spurious,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IrqError {
+    OutOfRange(u32),
+    AlreadyRegistered(u32),
+}
+
+/// Handlers by interrupt ID. Entries are atomics so handlers can be registered while
+/// interrupts are being dispatched.
+pub struct IrqTable {
+    handlers: [AtomicUsize; MAX_IRQS],
+}
+
+impl Default for IrqTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IrqTable {
+    pub const fn new() -> Self {
+        IrqTable {
+            handlers: [const { AtomicUsize::new(0) }; MAX_IRQS],
+        }
+    }
+
+    pub fn register(&self, irq: u32, handler: IrqHandler) -> Result<(), IrqError> {
+        let slot = self
+            .handlers
+            .get(irq as usize)
+            .ok_or(IrqError::OutOfRange(irq))?;
+        slot.compare_exchange(0, handler as usize, Ordering::AcqRel, Ordering::Acquire)
+            .map(|_| ())
+            .map_err(|_| IrqError::AlreadyRegistered(irq))
+    }
+
+    pub fn unregister(&self, irq: u32) {
+        if let Some(slot) = self.handlers.get(irq as usize) {
+            slot.store(0, Ordering::Release);
+        }
+    }
+
+    fn handler(&self, irq: u32) -> Option<IrqHandler> {
+        let handler = self.handlers.get(irq as usize)?.load(Ordering::Acquire);
+        // SAFETY: Non-zero entries were stored by `register` from an `IrqHandler`
+        (handler != 0).then(|| unsafe { core::mem::transmute::<usize, IrqHandler>(handler) })
+    }
+
+    /// Acknowledges the pending interrupt on `gic`, runs its handler and signals the end of
+    /// the interrupt.
+    pub fn dispatch<D: MmioInterface, C: MmioInterface>(&self, gic: &Gic<D, C>) -> Dispatch {
+        let Some(iar) = gic.acknowledge() else {
+            return Dispatch::Spurious;
+        };
+        let irq = irq_id(iar);
+        let result = match self.handler(irq) {
+            Some(handler) => {
+                handler(irq);
+                Dispatch::Handled(irq)
+            }
+            None => {
+                gic.disable(irq);
+                Dispatch::Unhandled(irq)
+            }
+        };
+        gic.end_of_interrupt(iar);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use core::sync::atomic::AtomicU32;
+
+    use crate::mmio::mock::MockMmio;
+
+    fn gic() -> Gic<MockMmio, MockMmio> {
+        let dist = MockMmio::new(0x1000);
+        // ITLinesNumber 7, 256 interrupts like the BCM2711
+        dist.set(GICD_TYPER, 7);
+        Gic::new(dist, MockMmio::new(0x100))
+    }
+
+    #[test]
+    fn test_init_and_configure_interrupts() {
+        let gic = gic();
+        gic.init();
+
+        assert_eq!(gic.irq_count(), 256);
+        assert_eq!(gic.dist.writes_to(GICD_CTLR), [0, CTLR_ENABLE]);
+        assert_eq!(gic.dist.get(GICD_ICENABLER + 7 * 4), u32::MAX);
+        assert_eq!(gic.dist.get(GICD_IPRIORITYR + 0xFC), 0xA0A0_A0A0);
+        assert_eq!(gic.dist.get(GICD_ITARGETSR + 0x20), 0x0101_0101);
+        assert_eq!(gic.cpu.get(GICC_PMR), 0xFF);
+        assert_eq!(gic.cpu.get(GICC_CTLR), CTLR_ENABLE);
+
+        // SPI 97: enable bit 1 of the fourth word, byte 1 of the priority/target words
+        gic.enable(97);
+        assert_eq!(gic.dist.writes_to(GICD_ISENABLER + 12), [1 << 1]);
+        gic.disable(97);
+        assert_eq!(
+            gic.dist.writes_to(GICD_ICENABLER + 12).last(),
+            Some(&(1 << 1))
+        );
+        gic.set_priority(97, 0x40);
+        assert_eq!(gic.dist.get(GICD_IPRIORITYR + 96), 0xA0A0_40A0);
+        gic.set_targets(97, 0b10);
+        assert_eq!(gic.dist.get(GICD_ITARGETSR + 96), 0x0101_0201);
+        gic.set_trigger(97, Trigger::Edge);
+        assert_eq!(gic.dist.get(GICD_ICFGR + 24), 0b10 << 2);
+        gic.set_trigger(97, Trigger::Level);
+        assert_eq!(gic.dist.get(GICD_ICFGR + 24), 0);
+    }
+
+    #[test]
+    fn test_dispatches_to_registered_handlers() {
+        static SEEN: AtomicU32 = AtomicU32::new(0);
+        fn record(irq: u32) {
+            SEEN.store(irq, Ordering::Relaxed);
+        }
+
+        let table = IrqTable::new();
+        table.register(30, record).unwrap();
+        assert_eq!(
+            table.register(30, record),
+            Err(IrqError::AlreadyRegistered(30))
+        );
+        assert_eq!(
+            table.register(MAX_IRQS as u32, record),
+            Err(IrqError::OutOfRange(MAX_IRQS as u32))
+        );
+
+        let gic = gic();
+        // The CPU ID bits of the IAR must make it back to EOIR untouched
+        gic.cpu
+            .queue_reads(GICC_IAR, &[(2 << 10) | 30, SPURIOUS_IRQ, 33]);
+
+        assert_eq!(table.dispatch(&gic), Dispatch::Handled(30));
+        assert_eq!(SEEN.load(Ordering::Relaxed), 30);
+        assert_eq!(table.dispatch(&gic), Dispatch::Spurious);
+        assert_eq!(table.dispatch(&gic), Dispatch::Unhandled(33));
+        assert_eq!(gic.dist.writes_to(GICD_ICENABLER + 4), [1 << 1]);
+        assert_eq!(gic.cpu.writes_to(GICC_EOIR), [(2 << 10) | 30, 33]);
+
+        table.unregister(30);
+        gic.cpu.queue_reads(GICC_IAR, &[30]);
+        assert_eq!(table.dispatch(&gic), Dispatch::Unhandled(30));
+    }
+}
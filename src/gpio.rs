@@ -0,0 +1,58 @@
+use crate::mmio::MmioInterface;
+
+pub const GPIO_BASE: usize = 0xFE200000;
+
+const GPFSEL0_OFFSET: usize = 0x00;
+const GPIO_PUP_PDN_CNTRL_REG0_OFFSET: usize = 0xE4;
+
+pub const PIN_COUNT: u32 = 58;
+
+/// Pin function select values for the GPFSELn registers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u32)]
+pub enum Function {
+    Input = 0b000,
+    Output = 0b001,
+    Alt0 = 0b100,
+    Alt1 = 0b101,
+    Alt2 = 0b110,
+    Alt3 = 0b111,
+    Alt4 = 0b011,
+    Alt5 = 0b010,
+}
+
+/// BCM2711 pull resistor settings. Unlike earlier Pis these are written directly rather
+/// than clocked in through GPPUD/GPPUDCLK.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u32)]
+pub enum Pull {
+    None = 0b00,
+    Up = 0b01,
+    Down = 0b10,
+}
+
+pub struct Gpio<R: MmioInterface> {
+    regs: R,
+}
+
+impl<R: MmioInterface> Gpio<R> {
+    pub const fn new(regs: R) -> Self {
+        Gpio { regs }
+    }
+
+    pub fn set_function(&self, pin: u32, function: Function) {
+        assert!(pin < PIN_COUNT, "GPIO pin out of range");
+        let offset = GPFSEL0_OFFSET + (pin / 10) as usize * 4;
+        let shift = (pin % 10) * 3;
+        self.regs
+            .modify(offset, 0b111 << shift, (function as u32) << shift);
+    }
+
+    pub fn set_pull(&self, pin: u32, pull: Pull) {
+        assert!(pin < PIN_COUNT, "GPIO pin out of range");
+        let offset = GPIO_PUP_PDN_CNTRL_REG0_OFFSET + (pin / 16) as usize * 4;
+        let shift = (pin % 16) * 2;
+        self.regs
+            .modify(offset, 0b11 << shift, (pull as u32) << shift);
+    }
+}
@@ -0,0 +1,214 @@
+//! BCM2711 BSC (Broadcom Serial Controller) I2C master.
+//!
+//! BSC1 is the controller wired to GPIO2/3 (header pins 3 and 5), where RTC and sensor
+//! HATs sit. Transfers are polled, each bounded so a missing or wedged device turns into
+//! an error instead of a hang.
+
+use core::fmt;
+
+use crate::{
+    gpio::{Function, Gpio, Pull},
+    mmio::MmioInterface,
+};
+
+/// BSC1 in the default low peripheral mode
+pub const I2C1_BASE: usize = 0xFE804000;
+pub const STANDARD_MODE_HZ: u32 = 100_000;
+
+const SDA_PIN: u32 = 2;
+const SCL_PIN: u32 = 3;
+
+const C_OFFSET: usize = 0x00;
+const S_OFFSET: usize = 0x04;
+const DLEN_OFFSET: usize = 0x08;
+const A_OFFSET: usize = 0x0C;
+const FIFO_OFFSET: usize = 0x10;
+const DIV_OFFSET: usize = 0x14;
+
+const C_I2CEN: u32 = 1 << 15;
+const C_ST: u32 = 1 << 7;
+const C_CLEAR: u32 = 0b01 << 4;
+const C_READ: u32 = 1 << 0;
+
+const S_CLKT: u32 = 1 << 9;
+const S_ERR: u32 = 1 << 8;
+const S_RXD: u32 = 1 << 5;
+const S_TXD: u32 = 1 << 4;
+const S_DONE: u32 = 1 << 1;
+
+/// Status polls before a transfer is given up on, well over a 16 byte transfer at 100 kHz
+const MAX_POLLS: u32 = 1_000_000;
+/// The FIFO and DLEN limit a single transfer
+const MAX_TRANSFER: usize = 0xFFFF;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum I2cError {
+    /// Nothing acknowledged the address or a data byte
+    Nack(u8),
+    /// The device held SCL low for too long
+    ClockStretchTimeout,
+    /// The transfer didn't finish in time
+    Timeout,
+    TooLong,
+}
+
+impl fmt::Display for I2cError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            I2cError::Nack(addr) => write!(f, "no acknowledge from device {addr:#04x}"),
+            I2cError::ClockStretchTimeout => write!(f, "clock stretch timeout"),
+            I2cError::Timeout => write!(f, "transfer timed out"),
+            I2cError::TooLong => write!(f, "transfer too long"),
+        }
+    }
+}
+
+/// Byte transfers to 7-bit addressed devices, implemented by `I2c` and by test doubles.
+pub trait I2cBus {
+    fn write(&mut self, addr: u8, bytes: &[u8]) -> Result<(), I2cError>;
+
+    fn read(&mut self, addr: u8, buf: &mut [u8]) -> Result<(), I2cError>;
+
+    /// Writes `bytes`, typically a register number, then reads `buf` back.
+    fn write_read(&mut self, addr: u8, bytes: &[u8], buf: &mut [u8]) -> Result<(), I2cError> {
+        self.write(addr, bytes)?;
+        self.read(addr, buf)
+    }
+}
+
+pub struct I2c<R: MmioInterface> {
+    regs: R,
+}
+
+impl<R: MmioInterface> I2c<R> {
+    pub const fn new(regs: R) -> Self {
+        I2c { regs }
+    }
+
+    /// Routes SDA/SCL to GPIO2/3 and sets the bus clock from the core clock.
+    pub fn init<G: MmioInterface>(&self, gpio: &Gpio<G>, core_clock_hz: u32, bus_hz: u32) {
+        for pin in [SDA_PIN, SCL_PIN] {
+            gpio.set_function(pin, Function::Alt0);
+            gpio.set_pull(pin, Pull::Up);
+        }
+        // The divisor is rounded down to an even number by the hardware
+        let divisor = core_clock_hz.div_ceil(bus_hz.max(1)).clamp(2, 0xFFFE);
+        self.regs.write(DIV_OFFSET, divisor);
+        self.regs.write(C_OFFSET, C_I2CEN | C_CLEAR);
+    }
+
+    /// Starts a transfer of `len` bytes and polls it to completion, handing each status
+    /// read to `service` to move data through the FIFO.
+    fn transfer(
+        &self,
+        addr: u8,
+        len: usize,
+        direction: u32,
+        mut service: impl FnMut(&R, u32),
+    ) -> Result<(), I2cError> {
+        if len > MAX_TRANSFER {
+            return Err(I2cError::TooLong);
+        }
+        self.regs.write(S_OFFSET, S_CLKT | S_ERR | S_DONE);
+        self.regs.write(A_OFFSET, addr as u32);
+        self.regs.write(DLEN_OFFSET, len as u32);
+        self.regs
+            .write(C_OFFSET, C_I2CEN | C_CLEAR | C_ST | direction);
+
+        for _ in 0..MAX_POLLS {
+            let status = self.regs.read(S_OFFSET);
+            if status & S_ERR != 0 {
+                self.regs.write(S_OFFSET, S_ERR | S_DONE);
+                return Err(I2cError::Nack(addr));
+            }
+            if status & S_CLKT != 0 {
+                self.regs.write(S_OFFSET, S_CLKT | S_DONE);
+                return Err(I2cError::ClockStretchTimeout);
+            }
+            service(&self.regs, status);
+            if status & S_DONE != 0 {
+                self.regs.write(S_OFFSET, S_DONE);
+                return Ok(());
+            }
+        }
+        self.regs.write(C_OFFSET, C_I2CEN | C_CLEAR);
+        Err(I2cError::Timeout)
+    }
+}
+
+impl<R: MmioInterface> I2cBus for I2c<R> {
+    fn write(&mut self, addr: u8, bytes: &[u8]) -> Result<(), I2cError> {
+        let mut pending = bytes.iter();
+        self.transfer(addr, bytes.len(), 0, |regs, status| {
+            if status & S_TXD != 0
+                && let Some(&byte) = pending.next()
+            {
+                regs.write(FIFO_OFFSET, byte as u32);
+            }
+        })
+    }
+
+    fn read(&mut self, addr: u8, buf: &mut [u8]) -> Result<(), I2cError> {
+        let mut filled = 0;
+        self.transfer(addr, buf.len(), C_READ, |regs, status| {
+            if status & S_RXD != 0 && filled < buf.len() {
+                buf[filled] = regs.read(FIFO_OFFSET) as u8;
+                filled += 1;
+            }
+        })?;
+        // The last bytes can still be in the FIFO when DONE is set
+        while filled < buf.len() && self.regs.read(S_OFFSET) & S_RXD != 0 {
+            buf[filled] = self.regs.read(FIFO_OFFSET) as u8;
+            filled += 1;
+        }
+        if filled == buf.len() {
+            Ok(())
+        } else {
+            Err(I2cError::Timeout)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mmio::mock::MockMmio;
+
+    #[test]
+    fn test_write_feeds_the_fifo() {
+        let mut i2c = I2c::new(MockMmio::new(0x20));
+        i2c.regs
+            .queue_reads(S_OFFSET, &[S_TXD, S_TXD, S_TXD, S_DONE]);
+
+        assert_eq!(i2c.write(0x68, &[0x00, 0x12, 0x34]), Ok(()));
+        assert_eq!(i2c.regs.get(A_OFFSET), 0x68);
+        assert_eq!(i2c.regs.get(DLEN_OFFSET), 3);
+        assert_eq!(i2c.regs.writes_to(C_OFFSET), [C_I2CEN | C_CLEAR | C_ST]);
+        assert_eq!(i2c.regs.writes_to(FIFO_OFFSET), [0x00, 0x12, 0x34]);
+    }
+
+    #[test]
+    fn test_read_drains_the_fifo_after_done() {
+        let mut i2c = I2c::new(MockMmio::new(0x20));
+        i2c.regs
+            .queue_reads(S_OFFSET, &[S_RXD, S_DONE | S_RXD, S_RXD, 0]);
+        i2c.regs.queue_reads(FIFO_OFFSET, &[0x59, 0x30, 0x12]);
+
+        let mut buf = [0; 3];
+        assert_eq!(i2c.read(0x68, &mut buf), Ok(()));
+        assert_eq!(buf, [0x59, 0x30, 0x12]);
+        assert_eq!(
+            i2c.regs.writes_to(C_OFFSET),
+            [C_I2CEN | C_CLEAR | C_ST | C_READ]
+        );
+    }
+
+    #[test]
+    fn test_reports_missing_devices() {
+        let mut i2c = I2c::new(MockMmio::new(0x20));
+        i2c.regs.queue_reads(S_OFFSET, &[S_ERR | S_DONE]);
+
+        let mut buf = [0; 1];
+        assert_eq!(i2c.read(0x50, &mut buf), Err(I2cError::Nack(0x50)));
+    }
+}
@@ -3,8 +3,39 @@
 #[cfg(test)]
 extern crate std;
 
+pub mod aa_font;
+pub mod boot_image;
+pub mod bundle;
+pub mod cache;
+pub mod chainload;
+pub mod clock;
+pub mod config;
+pub mod crc32;
+pub mod datetime;
+pub mod ds3231;
+pub mod elf;
+pub mod exception;
+pub mod fdt;
+pub mod fdt_writer;
 pub mod font8x8_basic;
+pub mod frame_allocator;
 pub mod frame_buffer;
+pub mod gic;
+pub mod gpio;
+pub mod i2c;
+pub mod linux;
 pub mod mailbox;
+pub mod mini_uart;
+pub mod mmio;
+pub mod mmu;
+pub mod pl011;
+pub mod platform;
+pub mod serial;
+pub mod sha256;
+pub mod smp;
+pub mod sync;
 pub mod text_buffer;
+pub mod text_layout;
 pub mod timer;
+pub mod timer_wheel;
+pub mod xmodem;
@@ -0,0 +1,487 @@
+//! Boots Linux arm64 `Image` kernels following Documentation/arch/arm64/booting.rst.
+
+use core::fmt;
+use core::ops::Range;
+
+use crate::elf::LoadMemory;
+use crate::fdt_writer::{FdtWriteError, FdtWriter};
+
+/// "ARM\x64", at offset 56 of the image header
+pub const IMAGE_MAGIC: u32 = 0x644d5241;
+pub const IMAGE_HEADER_SIZE: usize = 64;
+/// Big endian magic at the start of a flattened device tree
+pub const FDT_MAGIC: u32 = 0xd00dfeed;
+
+/// The kernel base must be 2 MiB aligned, and the DTB must not share a 2 MiB block with
+/// anything the kernel maps differently
+pub const KERNEL_ALIGN: u64 = 0x200000;
+pub const MAX_DTB_SIZE: usize = 0x200000;
+const INITRD_ALIGN: u64 = 0x1000;
+
+// Image header flags
+const FLAG_BIG_ENDIAN: u64 = 1 << 0;
+// Text offset of kernels older than 3.17, which leave image_size zero
+const LEGACY_TEXT_OFFSET: u64 = 0x80000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LinuxError {
+    NotAnImage,
+    BigEndian,
+    BadDeviceTree,
+    MissingDeviceTree,
+    DoesNotFit { needed: u64, available: u64 },
+}
+
+impl fmt::Display for LinuxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LinuxError::NotAnImage => write!(f, "not an arm64 Linux Image"),
+            LinuxError::BigEndian => write!(f, "big endian kernels are not supported"),
+            LinuxError::BadDeviceTree => write!(f, "invalid device tree blob"),
+            LinuxError::MissingDeviceTree => write!(f, "Linux needs a device tree blob"),
+            LinuxError::DoesNotFit { needed, available } => {
+                write!(f, "kernel needs {needed} bytes, {available} available")
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ImageHeader {
+    pub text_offset: u64,
+    /// Memory the kernel occupies from its load address, including BSS
+    pub image_size: u64,
+    pub flags: u64,
+}
+
+pub fn is_image(data: &[u8]) -> bool {
+    data.len() >= IMAGE_HEADER_SIZE
+        && u32::from_le_bytes(data[56..60].try_into().unwrap()) == IMAGE_MAGIC
+}
+
+impl ImageHeader {
+    pub fn parse(kernel: &[u8]) -> Result<Self, LinuxError> {
+        if !is_image(kernel) {
+            return Err(LinuxError::NotAnImage);
+        }
+        let read_u64 =
+            |offset: usize| u64::from_le_bytes(kernel[offset..offset + 8].try_into().unwrap());
+
+        let mut header = ImageHeader {
+            text_offset: read_u64(8),
+            image_size: read_u64(16),
+            flags: read_u64(24),
+        };
+        if header.image_size == 0 {
+            header.text_offset = LEGACY_TEXT_OFFSET;
+        }
+        if header.flags & FLAG_BIG_ENDIAN != 0 {
+            return Err(LinuxError::BigEndian);
+        }
+        // Without BSS information the file is all we know about
+        header.image_size = header.image_size.max(kernel.len() as u64);
+        Ok(header)
+    }
+}
+
+/// Checks the DTB header and returns the blob's total size.
+pub fn device_tree_size(dtb: &[u8]) -> Result<usize, LinuxError> {
+    let read_be = |offset: usize| {
+        dtb.get(offset..offset + 4)
+            .map(|bytes| u32::from_be_bytes(bytes.try_into().unwrap()))
+    };
+    if read_be(0) != Some(FDT_MAGIC) {
+        return Err(LinuxError::BadDeviceTree);
+    }
+    match read_be(4) {
+        Some(size) if (size as usize) <= dtb.len() && (size as usize) <= MAX_DTB_SIZE => {
+            Ok(size as usize)
+        }
+        _ => Err(LinuxError::BadDeviceTree),
+    }
+}
+
+/// Where each part of a Linux boot goes in physical memory.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Layout {
+    pub kernel: u64,
+    pub dtb: u64,
+    pub initrd: Option<Range<u64>>,
+}
+
+/// Places the kernel at the first 2 MiB aligned base in `memory` plus its text offset,
+/// followed by the DTB in its own 2 MiB block, which leaves it room to grow when
+/// `fixup_device_tree` edits it, and then the initrd.
+pub fn layout(
+    header: &ImageHeader,
+    dtb_size: usize,
+    initrd_size: Option<usize>,
+    memory: Range<u64>,
+) -> Result<Layout, LinuxError> {
+    let available = memory.end.saturating_sub(memory.start);
+    // The header is untrusted, so sizes that overflow just don't fit
+    let place = || {
+        let kernel = memory
+            .start
+            .checked_next_multiple_of(KERNEL_ALIGN)?
+            .checked_add(header.text_offset)?;
+        let dtb = kernel
+            .checked_add(header.image_size)?
+            .checked_next_multiple_of(KERNEL_ALIGN)?;
+        let mut end = dtb.checked_add(dtb_size.max(MAX_DTB_SIZE) as u64)?;
+        let initrd = match initrd_size {
+            Some(size) => {
+                let start = end.checked_next_multiple_of(INITRD_ALIGN)?;
+                end = start.checked_add(size as u64)?;
+                Some(start..end)
+            }
+            None => None,
+        };
+        Some((kernel, dtb, initrd, end))
+    };
+    let Some((kernel, dtb, initrd, end)) = place() else {
+        return Err(LinuxError::DoesNotFit {
+            needed: u64::MAX,
+            available,
+        });
+    };
+
+    if end > memory.end {
+        return Err(LinuxError::DoesNotFit {
+            needed: end - memory.start,
+            available,
+        });
+    }
+    Ok(Layout {
+        kernel,
+        dtb,
+        initrd,
+    })
+}
+
+/// Copies the kernel, DTB and optional initrd into `memory` at the first place `layout`
+/// finds for them in `window`, zeroing the kernel's BSS.
+pub fn load(
+    kernel: &[u8],
+    dtb: &[u8],
+    initrd: Option<&[u8]>,
+    memory: &mut impl LoadMemory,
+    window: Range<u64>,
+) -> Result<Layout, LinuxError> {
+    let header = ImageHeader::parse(kernel)?;
+    let dtb = &dtb[..device_tree_size(dtb)?];
+    let placed = layout(&header, dtb.len(), initrd.map(<[u8]>::len), window.clone())?;
+
+    let does_not_fit = LinuxError::DoesNotFit {
+        needed: header.image_size,
+        available: window.end - window.start,
+    };
+    let image = memory
+        .region(placed.kernel, header.image_size as usize)
+        .ok_or(does_not_fit)?;
+    let (text, bss) = image.split_at_mut(kernel.len());
+    text.copy_from_slice(kernel);
+    bss.fill(0);
+
+    memory
+        .region(placed.dtb, dtb.len())
+        .ok_or(does_not_fit)?
+        .copy_from_slice(dtb);
+    if let (Some(initrd), Some(range)) = (initrd, &placed.initrd) {
+        memory
+            .region(range.start, initrd.len())
+            .ok_or(does_not_fit)?
+            .copy_from_slice(initrd);
+    }
+
+    Ok(placed)
+}
+
+/// Changes `fixup_device_tree` makes besides pointing `/chosen` at the initramfs, if any.
+#[derive(Clone, Debug, Default)]
+pub struct DeviceTreeFixups<'a> {
+    /// Replaces `/chosen/bootargs`
+    pub bootargs: Option<&'a str>,
+    /// Replaces the first `/memory` bank, e.g. with the mailbox's ARM memory split
+    pub memory: Option<Range<u64>>,
+    /// Added to the `/memreserve/` block
+    pub reserved: &'a [Range<u64>],
+}
+
+/// Edits the DTB `load` placed at `layout.dtb`, passed in as its whole 2 MiB block, so
+/// Linux finds its initramfs, command line and memory.
+pub fn fixup_device_tree(
+    dtb: &mut [u8],
+    layout: &Layout,
+    fixups: &DeviceTreeFixups,
+) -> Result<(), FdtWriteError> {
+    let mut writer = FdtWriter::new(dtb)?;
+
+    writer.add_node("/", "chosen")?;
+    if let Some(bootargs) = fixups.bootargs {
+        writer.set_property_str("/chosen", "bootargs", bootargs)?;
+    }
+    if let Some(initrd) = &layout.initrd {
+        writer.set_property_u64("/chosen", "linux,initrd-start", initrd.start)?;
+        writer.set_property_u64("/chosen", "linux,initrd-end", initrd.end)?;
+    } else {
+        // The firmware's DTB may point at a file it loaded for us, not an initramfs
+        writer.remove_property("/chosen", "linux,initrd-start")?;
+        writer.remove_property("/chosen", "linux,initrd-end")?;
+    }
+    if let Some(memory) = &fixups.memory {
+        set_first_memory_bank(&mut writer, memory)?;
+    }
+    for range in fixups.reserved {
+        writer.add_memory_reservation(range.clone())?;
+    }
+    Ok(())
+}
+
+/// Rewrites the first entry of `/memory`'s `reg`, keeping any other banks. Device trees
+/// built for the Pi leave it zero for the firmware to fill in.
+fn set_first_memory_bank(writer: &mut FdtWriter, memory: &Range<u64>) -> Result<(), FdtWriteError> {
+    const MAX_REG_SIZE: usize = 256;
+
+    if writer.fdt().find_node("/memory").is_none() {
+        writer.add_node("/", "memory")?;
+        writer.set_property_str("/memory", "device_type", "memory")?;
+    }
+    let fdt = writer.fdt();
+    let root = fdt.root();
+    let (address_cells, size_cells) = (root.address_cells(), root.size_cells());
+    let entry_size = (address_cells + size_cells) * 4;
+    let old = fdt
+        .find_node("/memory")
+        .and_then(|node| node.property("reg"))
+        .map_or(&[][..], |prop| prop.value);
+
+    let mut reg = [0u8; MAX_REG_SIZE];
+    let len = old.len().clamp(entry_size, MAX_REG_SIZE);
+    let len = len - len % entry_size;
+    if len > entry_size {
+        reg[entry_size..len].copy_from_slice(&old[entry_size..len]);
+    }
+    write_cells(&mut reg[..address_cells * 4], memory.start);
+    write_cells(
+        &mut reg[address_cells * 4..entry_size],
+        memory.end - memory.start,
+    );
+    writer.set_property("/memory", "reg", &reg[..len])
+}
+
+/// Writes `value` as big endian cells filling `out`.
+fn write_cells(out: &mut [u8], value: u64) {
+    for (i, cell) in out.rchunks_exact_mut(4).enumerate() {
+        let cell_value = value.checked_shr(32 * i as u32).unwrap_or(0) as u32;
+        cell.copy_from_slice(&cell_value.to_be_bytes());
+    }
+}
+
+/// Enters a kernel loaded at `kernel` with the boot protocol's register state: `x0` holds
+/// the DTB address and `x1`..`x3` are zero, with interrupts masked.
+///
+/// # Safety
+///
+/// The kernel and DTB must be fully loaded and the MMU and data cache off, as the protocol
+/// requires.
+pub unsafe fn boot(kernel: u64, dtb: u64) -> ! {
+    #[cfg(target_arch = "aarch64")]
+    unsafe {
+        core::arch::asm!(
+            "msr daifset, #0xf",
+            "dsb sy",
+            "ic iallu",
+            "dsb sy",
+            "isb",
+            "br {entry}",
+            entry = in(reg) kernel,
+            in("x0") dtb,
+            // Zeroed as inputs rather than in the asm, so `entry` can't be given one of them
+            in("x1") 0u64,
+            in("x2") 0u64,
+            in("x3") 0u64,
+            options(noreturn)
+        )
+    }
+
+    #[cfg(not(target_arch = "aarch64"))]
+    panic!("Cannot boot a kernel at {kernel:#x} with DTB {dtb:#x} on this architecture");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::vec;
+    use std::vec::Vec;
+
+    fn image(text_offset: u64, image_size: u64, flags: u64, len: usize) -> Vec<u8> {
+        let mut kernel = vec![0u8; len];
+        kernel[8..16].copy_from_slice(&text_offset.to_le_bytes());
+        kernel[16..24].copy_from_slice(&image_size.to_le_bytes());
+        kernel[24..32].copy_from_slice(&flags.to_le_bytes());
+        kernel[56..60].copy_from_slice(&IMAGE_MAGIC.to_le_bytes());
+        kernel
+    }
+
+    #[test]
+    fn test_parses_image_header() {
+        let kernel = image(0, 0x2000000, 0b1010, 0x1000);
+        assert_eq!(
+            ImageHeader::parse(&kernel),
+            Ok(ImageHeader {
+                text_offset: 0,
+                image_size: 0x2000000,
+                flags: 0b1010
+            })
+        );
+
+        // Pre 3.17 kernels leave image_size zero and always use a 0x80000 text offset
+        let legacy = ImageHeader::parse(&image(0, 0, 0, 0x1000)).unwrap();
+        assert_eq!(legacy.text_offset, 0x80000);
+        assert_eq!(legacy.image_size, 0x1000);
+
+        assert_eq!(
+            ImageHeader::parse(&image(0, 0x1000, 1, 0x1000)),
+            Err(LinuxError::BigEndian)
+        );
+        assert_eq!(ImageHeader::parse(&[0u8; 64]), Err(LinuxError::NotAnImage));
+    }
+
+    #[test]
+    fn test_layout_aligns_kernel_dtb_and_initrd() {
+        let header = ImageHeader {
+            text_offset: 0,
+            image_size: 0x1_234_000,
+            flags: 0,
+        };
+        let placed = layout(&header, 0x8000, Some(0x10001), 0x8100000..0x10000000).unwrap();
+
+        assert_eq!(placed.kernel, 0x8200000);
+        assert_eq!(placed.dtb % KERNEL_ALIGN, 0);
+        assert!(placed.dtb >= placed.kernel + header.image_size);
+        let initrd = placed.initrd.unwrap();
+        assert!(initrd.start >= placed.dtb + 0x8000);
+        assert_eq!(initrd.start % INITRD_ALIGN, 0);
+        assert_eq!(initrd.end - initrd.start, 0x10001);
+
+        assert!(matches!(
+            layout(&header, 0x8000, None, 0x8000000..0x9000000),
+            Err(LinuxError::DoesNotFit { .. })
+        ));
+    }
+
+    #[test]
+    fn test_layout_rejects_overflowing_header_fields() {
+        let window = 0x8000000..0x30000000;
+        for (text_offset, image_size) in [(u64::MAX - 0x1000, 0x1000), (0, u64::MAX - 0x1000)] {
+            let header = ImageHeader {
+                text_offset,
+                image_size,
+                flags: 0,
+            };
+            assert!(matches!(
+                layout(&header, 0x8000, Some(0x1000), window.clone()),
+                Err(LinuxError::DoesNotFit { .. })
+            ));
+        }
+
+        let header = ImageHeader {
+            text_offset: 0,
+            image_size: 0x1000,
+            flags: 0,
+        };
+        assert!(matches!(
+            layout(&header, 0x8000, Some(usize::MAX), window),
+            Err(LinuxError::DoesNotFit { .. })
+        ));
+    }
+
+    #[test]
+    fn test_device_tree_size_checks_header() {
+        let mut dtb = vec![0u8; 0x100];
+        dtb[..4].copy_from_slice(&FDT_MAGIC.to_be_bytes());
+        dtb[4..8].copy_from_slice(&0x80u32.to_be_bytes());
+        assert_eq!(device_tree_size(&dtb), Ok(0x80));
+
+        dtb[4..8].copy_from_slice(&0x200u32.to_be_bytes());
+        assert_eq!(device_tree_size(&dtb), Err(LinuxError::BadDeviceTree));
+        assert_eq!(
+            device_tree_size(b"not a dtb"),
+            Err(LinuxError::BadDeviceTree)
+        );
+    }
+
+    #[test]
+    fn test_load_places_kernel_dtb_and_initrd() {
+        struct TestMemory(Vec<u8>);
+
+        impl LoadMemory for TestMemory {
+            fn region(&mut self, addr: u64, len: usize) -> Option<&mut [u8]> {
+                self.0.get_mut(addr as usize..addr as usize + len)
+            }
+        }
+
+        let mut kernel = image(0, 0x3000, 0, 0x1000);
+        kernel[0x100] = 0xAB;
+        let mut dtb = vec![0x5Au8; 0x40];
+        dtb[..4].copy_from_slice(&FDT_MAGIC.to_be_bytes());
+        dtb[4..8].copy_from_slice(&0x40u32.to_be_bytes());
+        let initrd = [0xC3u8; 100];
+
+        let mut memory = TestMemory(vec![0xEE; 0x800000]);
+        let placed = load(&kernel, &dtb, Some(&initrd), &mut memory, 0x1000..0x800000).unwrap();
+
+        let at = |addr: u64, len: usize| &memory.0[addr as usize..addr as usize + len];
+        assert_eq!(placed.kernel, 0x200000);
+        assert_eq!(at(placed.kernel, 0x1000), &kernel[..]);
+        assert!(at(placed.kernel + 0x1000, 0x2000).iter().all(|&b| b == 0));
+        assert_eq!(at(placed.dtb, 0x40), &dtb[..]);
+        assert_eq!(at(placed.initrd.clone().unwrap().start, 100), &initrd[..]);
+
+        assert_eq!(
+            load(&kernel, b"junk", None, &mut memory, 0x1000..0x800000),
+            Err(LinuxError::BadDeviceTree)
+        );
+    }
+
+    #[test]
+    fn test_fixup_device_tree_patches_chosen_memory_and_reservations() {
+        use crate::fdt::Fdt;
+
+        let firmware_dtb = include_bytes!("../tests/fixtures/dtb/rpi4b.dtb");
+        let mut dtb = firmware_dtb.to_vec();
+        dtb.resize(0x10000, 0);
+        let placed = Layout {
+            kernel: 0x8000000,
+            dtb: 0xa000000,
+            initrd: Some(0xa200000..0xa2c8000),
+        };
+        let bootloader = 0x2000000..0x2040000;
+        let fixups = DeviceTreeFixups {
+            bootargs: Some("console=ttyAMA0,115200 rdinit=/sbin/init"),
+            memory: Some(0..0x3c000000),
+            reserved: core::slice::from_ref(&bootloader),
+        };
+        fixup_device_tree(&mut dtb, &placed, &fixups).unwrap();
+
+        let fdt = Fdt::new(&dtb).unwrap();
+        assert_eq!(fdt.bootargs(), fixups.bootargs);
+        let chosen = fdt.find_node("/chosen").unwrap();
+        let cell = |name| chosen.property(name).and_then(|prop| prop.as_u64());
+        assert_eq!(cell("linux,initrd-start"), Some(0xa200000));
+        assert_eq!(cell("linux,initrd-end"), Some(0xa2c8000));
+        let memory: Vec<Range<u64>> = fdt.memory().collect();
+        assert_eq!(memory, [0..0x3c000000, 0x40000000..0xfc000000]);
+        let reserved: Vec<Range<u64>> = fdt.memory_reservations().collect();
+        assert_eq!(reserved, vec![0..0x1000, 0x2000000..0x2040000]);
+
+        // Without room to grow the edit fails rather than running off the end
+        let mut tight = firmware_dtb.to_vec();
+        assert!(matches!(
+            fixup_device_tree(&mut tight, &placed, &fixups),
+            Err(FdtWriteError::NoSpace { .. })
+        ));
+    }
+}
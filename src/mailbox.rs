@@ -1,11 +1,19 @@
+use core::ops::Range;
 use core::ptr::{read_volatile, write_volatile};
 
+use crate::cache;
+use crate::frame_buffer::CHANNEL_FRAMEBUFFER;
+use crate::sync::IrqSafeLock;
+
 const MAILBOX_READ_OFFSET: usize = 0x00;
 const MAILBOX_STATUS_OFFSET: usize = 0x18;
 const MAILBOX_WRITE_OFFSET: usize = 0x20;
 const MAILBOX_FULL: u32 = 1 << 31;
 const MAILBOX_EMPTY: u32 = 1 << 30;
 
+// Held for a whole call, so no other core or interrupt handler can take its response
+static MAILBOX_LOCK: IrqSafeLock<()> = IrqSafeLock::new(());
+
 pub struct Mailbox {
     base_addr: usize,
 }
@@ -21,8 +29,14 @@ impl Mailbox {
         }
     }
 
+    // Property buffers start with their size in bytes, which is all that is read here
+    #[allow(clippy::not_unsafe_ptr_arg_deref)]
     pub fn call(&self, channel: u8, buffer: *mut u32) -> bool {
         let msg = (buffer as usize & !0xF) | (channel as usize & 0xF);
+        let _lock = MAILBOX_LOCK.lock();
+        // The VideoCore reads and writes the buffer in memory, behind the data cache
+        let len = unsafe { read_volatile(buffer) } as usize;
+        cache::clean_dcache_range(buffer as usize, len);
 
         unsafe {
             while read_volatile((self.base_addr + MAILBOX_STATUS_OFFSET) as *const u32)
@@ -41,6 +55,7 @@ impl Mailbox {
                 {}
                 let resp = read_volatile((self.base_addr + MAILBOX_READ_OFFSET) as *const u32);
                 if resp as usize == msg {
+                    cache::clean_invalidate_dcache_range(buffer as usize, len);
                     return true;
                 }
             }
@@ -53,3 +68,132 @@ impl MailboxInterface for Mailbox {
         self.call(channel, buffer)
     }
 }
+
+const TAG_GET_ARM_MEMORY: u32 = 0x00010005;
+const TAG_GET_CLOCK_RATE: u32 = 0x00030002;
+const RESPONSE_SUCCESS: u32 = 0x80000000;
+
+pub const CLOCK_UART: u32 = 2;
+pub const CLOCK_CORE: u32 = 4;
+
+#[repr(C, align(16))]
+struct ClockRateMailbox {
+    size: u32,
+    code: u32,
+    tag_get_clock_rate: u32,
+    tag_bufsize: u32,
+    tag_len: u32,
+    clock_id: u32,
+    rate: u32,
+    end_tag: u32,
+}
+
+/// Queries the VideoCore for the current rate of `clock_id` in Hz.
+pub fn get_clock_rate<M: MailboxInterface>(mailbox: &M, clock_id: u32) -> Option<u32> {
+    let mut mb = ClockRateMailbox {
+        size: core::mem::size_of::<ClockRateMailbox>() as u32,
+        code: 0,
+        tag_get_clock_rate: TAG_GET_CLOCK_RATE,
+        tag_bufsize: 8,
+        tag_len: 4,
+        clock_id,
+        rate: 0,
+        end_tag: 0,
+    };
+
+    let ok = mailbox.call(CHANNEL_FRAMEBUFFER, &mut mb as *mut _ as *mut u32);
+    let code = unsafe { read_volatile(&mb.code) };
+    let rate = unsafe { read_volatile(&mb.rate) };
+    if ok && code == RESPONSE_SUCCESS && rate != 0 {
+        Some(rate)
+    } else {
+        None
+    }
+}
+
+#[repr(C, align(16))]
+struct ArmMemoryMailbox {
+    size: u32,
+    code: u32,
+    tag_get_arm_memory: u32,
+    tag_bufsize: u32,
+    tag_len: u32,
+    base: u32,
+    size_bytes: u32,
+    end_tag: u32,
+}
+
+/// Queries the VideoCore for the memory it leaves to the ARM cores below its own split.
+pub fn get_arm_memory<M: MailboxInterface>(mailbox: &M) -> Option<Range<u64>> {
+    let mut mb = ArmMemoryMailbox {
+        size: core::mem::size_of::<ArmMemoryMailbox>() as u32,
+        code: 0,
+        tag_get_arm_memory: TAG_GET_ARM_MEMORY,
+        tag_bufsize: 8,
+        tag_len: 0,
+        base: 0,
+        size_bytes: 0,
+        end_tag: 0,
+    };
+
+    let ok = mailbox.call(CHANNEL_FRAMEBUFFER, &mut mb as *mut _ as *mut u32);
+    let code = unsafe { read_volatile(&mb.code) };
+    let base = unsafe { read_volatile(&mb.base) } as u64;
+    let size = unsafe { read_volatile(&mb.size_bytes) } as u64;
+    if ok && code == RESPONSE_SUCCESS && size != 0 {
+        Some(base..base + size)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Answers GET_CLOCK_RATE with `rate`, like the firmware does
+    struct ClockMailbox {
+        rate: u32,
+    }
+
+    impl MailboxInterface for ClockMailbox {
+        fn call(&self, channel: u8, buffer: *mut u32) -> bool {
+            assert_eq!(channel, CHANNEL_FRAMEBUFFER);
+            unsafe {
+                let mb = &mut *(buffer as *mut ClockRateMailbox);
+                assert_eq!(mb.tag_get_clock_rate, TAG_GET_CLOCK_RATE);
+                mb.code = RESPONSE_SUCCESS;
+                mb.rate = self.rate;
+            }
+            true
+        }
+    }
+
+    #[test]
+    fn test_get_clock_rate_reads_response() {
+        let mailbox = ClockMailbox { rate: 48_000_000 };
+        assert_eq!(get_clock_rate(&mailbox, CLOCK_UART), Some(48_000_000));
+
+        let mailbox = ClockMailbox { rate: 0 };
+        assert_eq!(get_clock_rate(&mailbox, CLOCK_UART), None);
+    }
+
+    #[test]
+    fn test_get_arm_memory_reads_response() {
+        struct ArmMemory;
+
+        impl MailboxInterface for ArmMemory {
+            fn call(&self, _channel: u8, buffer: *mut u32) -> bool {
+                unsafe {
+                    let mb = &mut *(buffer as *mut ArmMemoryMailbox);
+                    assert_eq!(mb.tag_get_arm_memory, TAG_GET_ARM_MEMORY);
+                    mb.code = RESPONSE_SUCCESS;
+                    mb.size_bytes = 0x3b400000;
+                }
+                true
+            }
+        }
+
+        assert_eq!(get_arm_memory(&ArmMemory), Some(0..0x3b400000));
+    }
+}
@@ -2,11 +2,40 @@
 #![no_main]
 
 use core::arch::global_asm;
-use core::fmt::Write;
+use core::fmt::{Display, Write};
+use core::ops::Range;
 use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU32, Ordering};
+use core::time::Duration;
 
 use raspi4_rust_bootloader::{
-    frame_buffer::FrameBuffer, mailbox::Mailbox, text_buffer::TextBuffer, timer::Timer,
+    aa_font::DEJAVU_SANS,
+    boot_image,
+    bundle::{self, Bundle, Kind},
+    chainload,
+    clock::Instant,
+    config::{BootTarget, Config, ConfigError, RtcKind},
+    datetime::{self, DateTime, Timestamp, Uptime},
+    ds3231::Ds3231,
+    elf::{self, ElfError, ElfFile, LoadMemory, PhysicalMemory},
+    exception::{self, CrashReport, ExceptionFrame, ExceptionKind, Vector},
+    fdt::Fdt,
+    frame_allocator::{self, FRAME_SIZE},
+    frame_buffer::FrameBuffer,
+    gic::{Gic, IRQ_HANDLERS},
+    gpio::Gpio,
+    i2c::{I2c, STANDARD_MODE_HZ},
+    linux::{self, LinuxError},
+    mailbox::{self, CLOCK_CORE, Mailbox},
+    mini_uart::DEFAULT_CORE_CLOCK_HZ,
+    mmio::Mmio,
+    mmu,
+    platform::Platform,
+    serial::{self, Console, SerialInterface},
+    smp,
+    text_buffer::TextBuffer,
+    timer::{self, Timer},
+    xmodem,
 };
 
 #[unsafe(no_mangle)]
@@ -15,37 +44,674 @@ pub static BOOT_CORE_ID: u64 = 0;
 
 global_asm!(
     include_str!("boot.s"),
-    CONST_CORE_ID_MASK = const 0b11
+    CONST_CORE_ID_MASK = const 0b11,
+    CONST_STAY_AT_EL2 = const cfg!(feature = "el2") as u8,
+    // Reserved bits set, MMU, caches and alignment checks off, little endian
+    CONST_SCTLR_EL1 = const 0x30d0_0800u64,
+    CONST_SCTLR_EL2 = const 0x30c5_0830u64,
+    // EL2 and EL1 non-secure and AArch64, HVC on and SMC off, as the firmware's armstub sets
+    CONST_SCR_EL3 = const 0x5b1u64,
+    // The Pi 4's 54 MHz crystal, which the system counter runs at
+    CONST_CNTFRQ = const 54_000_000u64
 );
 
-const MAILBOX_BASE: usize = 0xFE00B880;
+// Where flat chainloaded kernels are placed, the same address the firmware loads kernel8.img at
+const KERNEL_LOAD_ADDR: usize = 0x80000;
+// Images are received here, above the relocated bootloader, and verified before being moved
+// into place. This keeps ELF segments free to land anywhere below the bootloader.
+const STAGING_ADDR: usize = 0x4000000;
+const STAGING_SIZE: usize = 0x4000000;
+// Linux goes above the staging area, up to well below where the VideoCore's memory starts,
+// or the firmware's DTB if that comes first
+const LINUX_LOAD_ADDR: usize = STAGING_ADDR + STAGING_SIZE;
+const LINUX_LOAD_END: usize = 0x30000000;
+// Text console size and where it sits on the screen
+const CONSOLE_ROWS: usize = 14;
+const CONSOLE_COLS: usize = 26;
+const CONSOLE_OFFSET: usize = 100;
+// Panics and crash reports use a smaller font so register dumps fit
+const CRASH_ROWS: usize = 56;
+const CRASH_COLS: usize = 110;
+const CRASH_FONT_SCALE: usize = 2;
+// Status line with the uptime and clock, above the console
+const STATUS_Y: usize = 40;
+const STATUS_HEIGHT: usize = 40;
+const STATUS_FONT_SIZE: f32 = 24.0;
 
-#[unsafe(no_mangle)]
-pub extern "C" fn _start_rust() -> ! {
-    let mut mailbox = Mailbox::new(MAILBOX_BASE);
-    let mut fb = FrameBuffer::new(&mut mailbox).expect("Failed to create frame buffer");
-    let mut tb = TextBuffer::<14, 26, Mailbox>::new(&mut fb, 100, 100, 8, 0x282828);
+// The baud rate the serial console was set up with, for the panic handler
+static SERIAL_BAUD: AtomicU32 = AtomicU32::new(Config::DEFAULT.serial_baud);
+
+unsafe extern "C" {
+    // Start of this image after boot.s relocated it, and the end of the secondary core
+    // stacks after its BSS, see link.ld
+    static __binary_nonzero_start: u8;
+    static __core_stacks_end: u8;
+}
+
+/// Writes a line to both the serial and framebuffer consoles, timestamped on serial.
+macro_rules! report {
+    ($serial:expr, $console:expr, $($arg:tt)*) => {{
+        let _ = write!($serial, "[{}] ", Timestamp);
+        let _ = writeln!($serial, $($arg)*);
+        let _ = writeln!($console, $($arg)*);
+    }};
+}
+
+/// Offers to receive a kernel over serial for `wait_s` seconds and boots it if one arrives
+/// and verifies. Linux kernels sent without a device tree boot with `firmware_dtb`.
+fn chainload(
+    serial: &mut Console,
+    console: &mut impl Write,
+    wait_s: u32,
+    firmware_dtb: Option<&Fdt>,
+) {
+    let mut timer = Timer::new(1000);
+    let Some(header) = chainload::wait_for_host(serial, wait_s, || timer.elapsed()) else {
+        let _ = writeln!(serial, "No chainload host, continuing");
+        return;
+    };
+
+    let staging = unsafe { core::slice::from_raw_parts_mut(STAGING_ADDR as *mut u8, STAGING_SIZE) };
+    let size = match chainload::receive(serial, header, staging, || timer.elapsed()) {
+        Ok(size) => size,
+        Err(err) => {
+            report!(serial, console, "Chainload failed: {err}");
+            return;
+        }
+    };
+    boot_received(&staging[..size], firmware_dtb, serial, console);
+}
+
+/// Receives a kernel into the staging area over YMODEM, from the serial console's `ymodem`
+/// command, and boots it like a chainloaded one.
+fn receive_ymodem(serial: &mut Console, console: &mut impl Write, firmware_dtb: Option<&Fdt>) {
+    let _ = writeln!(serial, "Waiting for a YMODEM upload...");
+    let staging = unsafe { core::slice::from_raw_parts_mut(STAGING_ADDR as *mut u8, STAGING_SIZE) };
     let mut timer = Timer::new(1000);
+    let file = match xmodem::receive_ymodem(serial, staging, || timer.elapsed()) {
+        Ok(file) => file,
+        Err(err) => {
+            report!(serial, console, "Upload failed: {err}");
+            return;
+        }
+    };
+    report!(
+        serial,
+        console,
+        "Received {} ({} bytes)",
+        file.name(),
+        file.size
+    );
+    boot_received(&staging[..file.size], firmware_dtb, serial, console);
+}
 
+/// Verifies a received image and boots the kernel in it, or reports why it can't.
+fn boot_received(
+    image: &[u8],
+    firmware_dtb: Option<&Fdt>,
+    serial: &mut Console,
+    console: &mut impl Write,
+) {
+    let payload = match boot_image::verify_image(image, boot_image::TRUSTED_KEY.as_ref()) {
+        Ok(payload) => &image[payload],
+        Err(err) => {
+            report!(serial, console, "Refusing to boot: {err}");
+            return;
+        }
+    };
+
+    if bundle::is_bundle(payload) {
+        match Bundle::parse(payload) {
+            Ok(bundle) => match bundle.get(Kind::Kernel) {
+                Some(kernel) => {
+                    let dtb = bundle.get(Kind::DeviceTree);
+                    let initrd = bundle.get(Kind::Initrd);
+                    boot_linux(kernel, dtb, initrd, firmware_dtb, serial, console)
+                }
+                None => report!(
+                    serial,
+                    console,
+                    "Refusing to boot: {}",
+                    LinuxError::NotAnImage
+                ),
+            },
+            Err(err) => report!(serial, console, "Refusing to boot: {err}"),
+        }
+        return;
+    }
+    if linux::is_image(payload) {
+        boot_linux(payload, None, None, firmware_dtb, serial, console);
+        return;
+    }
+
+    // Everything from the load address up to our relocated image is free
+    let load_end = &raw const __binary_nonzero_start as usize;
+    let entry = match load_kernel(payload, KERNEL_LOAD_ADDR..load_end) {
+        Ok(entry) => entry,
+        Err(err) => {
+            report!(serial, console, "Refusing to boot: {err}");
+            return;
+        }
+    };
+
+    report!(
+        serial,
+        console,
+        "Loaded {} bytes, jumping to {entry:#x}",
+        payload.len()
+    );
+    serial.flush();
+    timer::stop_ticks();
+    smp::park_secondary_cores();
+    mmu::disable();
+    unsafe { chainload::jump_to(entry) }
+}
+
+/// Moves a verified kernel into `window`, loading ELF files by their program headers and
+/// flat binaries at the start of the window. Returns the entry point.
+fn load_kernel(kernel: &[u8], window: Range<usize>) -> Result<usize, ElfError> {
+    let mut memory = unsafe { PhysicalMemory::new(window.start as u64..window.end as u64) };
+
+    if elf::is_elf(kernel) {
+        let entry = ElfFile::parse(kernel)?.load(&mut memory)?;
+        return Ok(entry as usize);
+    }
+
+    let dest =
+        memory
+            .region(window.start as u64, kernel.len())
+            .ok_or(ElfError::SegmentNotLoadable {
+                index: 0,
+                addr: window.start as u64,
+                size: kernel.len() as u64,
+            })?;
+    dest.copy_from_slice(kernel);
+    Ok(window.start)
+}
+
+/// Loads a Linux kernel with its DTB and optional initramfs above the staging area and boots
+/// it, or reports why it can't. Kernels sent without a DTB get `firmware_dtb`.
+fn boot_linux(
+    kernel: &[u8],
+    dtb: Option<&[u8]>,
+    initrd: Option<&[u8]>,
+    firmware_dtb: Option<&Fdt>,
+    serial: &mut Console,
+    console: &mut impl Write,
+) {
+    let mut load_end = LINUX_LOAD_END as u64;
+    if let Some(fdt) = firmware_dtb {
+        // Don't overwrite the firmware's DTB while copying, it may be the one being booted
+        let dtb_start = fdt.as_bytes().as_ptr() as u64;
+        if dtb_start > LINUX_LOAD_ADDR as u64 {
+            load_end = load_end.min(dtb_start);
+        }
+    }
+    let window = LINUX_LOAD_ADDR as u64..load_end;
+    let mut memory = unsafe { PhysicalMemory::new(window.clone()) };
+    let result = match dtb.or(firmware_dtb.map(Fdt::as_bytes)) {
+        Some(dtb) => linux::load(kernel, dtb, initrd, &mut memory, window),
+        None => Err(LinuxError::MissingDeviceTree),
+    };
+    let layout = match result {
+        Ok(layout) => layout,
+        Err(err) => {
+            report!(serial, console, "Refusing to boot: {err}");
+            return;
+        }
+    };
+
+    // Point the kernel at its initramfs, pass on the firmware's command line (cmdline.txt)
+    // and keep it off the bootloader, which stays resident
+    let bootloader = &raw const __binary_nonzero_start as u64..&raw const __core_stacks_end as u64;
+    let fixups = linux::DeviceTreeFixups {
+        bootargs: firmware_dtb.and_then(Fdt::bootargs),
+        memory: mailbox::get_arm_memory(&Mailbox::new(Platform::current().mailbox_base)),
+        reserved: &[bootloader],
+    };
+    let dtb = memory
+        .region(layout.dtb, linux::MAX_DTB_SIZE)
+        .expect("layout leaves the DTB a whole block");
+    if let Err(err) = linux::fixup_device_tree(dtb, &layout, &fixups) {
+        report!(serial, console, "Refusing to boot: {err}");
+        return;
+    }
+    // Writes the kernel, DTB and initramfs back to RAM, `linux::boot` never returns to drop it
+    drop(memory);
+
+    report!(
+        serial,
+        console,
+        "Booting Linux at {:#x}, DTB at {:#x}",
+        layout.kernel,
+        layout.dtb
+    );
+    if let Some(initrd) = &layout.initrd {
+        report!(
+            serial,
+            console,
+            "initramfs at {:#x}..{:#x}",
+            initrd.start,
+            initrd.end
+        );
+    }
+    serial.flush();
+    timer::stop_ticks();
+    smp::park_secondary_cores();
+    mmu::disable();
+    unsafe { linux::boot(layout.kernel, layout.dtb) }
+}
+
+/// Fixed-size text buffer for formatting without an allocator. Text that doesn't fit is
+/// dropped.
+struct LineBuffer<const N: usize> {
+    bytes: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> LineBuffer<N> {
+    const fn new() -> Self {
+        LineBuffer {
+            bytes: [0; N],
+            len: 0,
+        }
+    }
+
+    fn as_str(&self) -> &str {
+        // Only whole `str`s and ASCII bytes are ever added
+        core::str::from_utf8(&self.bytes[..self.len]).unwrap_or("")
+    }
+
+    fn clear(&mut self) {
+        self.len = 0;
+    }
+}
+
+impl<const N: usize> Write for LineBuffer<N> {
+    fn write_str(&mut self, text: &str) -> core::fmt::Result {
+        let text = text.as_bytes();
+        if text.len() > N - self.len {
+            return Err(core::fmt::Error);
+        }
+        self.bytes[self.len..self.len + text.len()].copy_from_slice(text);
+        self.len += text.len();
+        Ok(())
+    }
+}
+
+type Rtc = Ds3231<I2c<Mmio>>;
+
+/// Sets the wall clock from the RTC on the header's I2C pins, if there is one.
+fn start_rtc(mailbox: &Mailbox, platform: &Platform, serial: &mut Console) -> Rtc {
+    let i2c = I2c::new(Mmio::new(platform.i2c1_base));
+    let core_clock_hz =
+        mailbox::get_clock_rate(mailbox, CLOCK_CORE).unwrap_or(DEFAULT_CORE_CLOCK_HZ);
+    i2c.init(
+        &Gpio::new(Mmio::new(platform.gpio_base)),
+        core_clock_hz,
+        STANDARD_MODE_HZ,
+    );
+    let mut rtc = Ds3231::new(i2c);
+    match rtc.read_time() {
+        Ok(now) => {
+            datetime::set_wall_clock(now);
+            let _ = writeln!(serial, "Clock set from the RTC: {now}");
+        }
+        Err(err) => {
+            let _ = writeln!(serial, "No time from the RTC: {err}");
+        }
+    }
+    rtc
+}
+
+/// Runs a serial console command: `time` shows the clock, `time YYYY-MM-DD HH:MM:SS` sets
+/// it, and the RTC too if there is one. `ymodem` receives a kernel and boots it.
+fn run_command(
+    command: &str,
+    serial: &mut Console,
+    console: &mut impl Write,
+    rtc: Option<&mut Rtc>,
+    firmware_dtb: Option<&Fdt>,
+) {
+    let (name, argument) = command.split_once(' ').unwrap_or((command, ""));
+    match (name, argument.trim()) {
+        ("", _) => {}
+        ("ymodem", "") => receive_ymodem(serial, console, firmware_dtb),
+        ("time", "") => match datetime::wall_clock() {
+            Some(now) => {
+                let _ = writeln!(serial, "{now}");
+            }
+            None => {
+                let _ = writeln!(
+                    serial,
+                    "Clock not set, up {}",
+                    Uptime(Instant::now().since_start())
+                );
+            }
+        },
+        ("time", argument) => match argument.parse::<DateTime>() {
+            Ok(now) => {
+                datetime::set_wall_clock(now);
+                if let Some(Err(err)) = rtc.map(|rtc| rtc.set_time(&now)) {
+                    let _ = writeln!(serial, "Couldn't update the RTC: {err}");
+                }
+                let _ = writeln!(serial, "Clock set to {now}");
+            }
+            Err(err) => {
+                let _ = writeln!(serial, "{err}");
+            }
+        },
+        _ => {
+            let _ = writeln!(serial, "Unknown command {name:?}, try `time` or `ymodem`");
+        }
+    }
+}
+
+/// Redraws the status line with the uptime and, once it is set, the date and time.
+fn draw_status(fb: &FrameBuffer<Mailbox>, config: &Config) {
+    let mut text = LineBuffer::<64>::new();
+    let _ = write!(text, "Up {}", Uptime(Instant::now().since_start()));
+    if let Some(now) = datetime::wall_clock() {
+        let _ = write!(text, "    {now} UTC");
+    }
+    fb.clear_area(
+        (CONSOLE_OFFSET, fb.width),
+        (STATUS_Y, STATUS_Y + STATUS_HEIGHT),
+        config.background,
+    );
+    fb.draw_aa_text(
+        CONSOLE_OFFSET,
+        STATUS_Y,
+        text.as_str(),
+        config.foreground,
+        STATUS_FONT_SIZE,
+        &DEJAVU_SANS,
+    );
+}
+
+/// Reads the settings file the firmware loaded for config.txt's `initramfs` directive, then
+/// the `bootloader.` settings on the kernel command line.
+fn load_config<'a>(fdt: Option<&Fdt<'a>>, mut on_error: impl FnMut(ConfigError<'a>)) -> Config {
+    let mut config = Config::DEFAULT;
+    let Some(fdt) = fdt else {
+        return config;
+    };
+
+    if let Some(file) = fdt.initrd() {
+        // The firmware put it there for us and nothing has been loaded over it yet
+        let bytes = unsafe {
+            core::slice::from_raw_parts(file.start as *const u8, (file.end - file.start) as usize)
+        };
+        let text = core::str::from_utf8(bytes)
+            .unwrap_or_else(|err| core::str::from_utf8(&bytes[..err.valid_up_to()]).unwrap());
+        config.apply_file(text, &mut on_error);
+    }
+    if let Some(bootargs) = fdt.bootargs() {
+        config.apply_cmdline(bootargs, &mut on_error);
+    }
+    config
+}
+
+/// Has every secondary core check in with a message and lists the ones that answer.
+fn report_from_secondary_cores(serial: &mut Console) {
+    fn check_in(_: usize) {
+        let _ = smp::send(smp::BOOT_CORE, smp::current_core() as u64);
+    }
+
+    let asked = (1..smp::MAX_CORES)
+        .filter(|&core| smp::run_on(core, check_in, 0).is_ok())
+        .count();
+    let deadline = Instant::now() + Duration::from_millis(10);
+    let mut answered = 0;
+    while answered < asked && Instant::now() < deadline {
+        match smp::receive() {
+            Some(message) => {
+                let _ = writeln!(serial, "Core {} checked in", message.value);
+                answered += 1;
+            }
+            None => core::hint::spin_loop(),
+        }
+    }
+}
+
+/// Seeds the frame allocator with the RAM the device tree and the mailbox report, keeping
+/// out everything the bootloader and the kernels it loads use. Returns the free frames.
+fn init_frames(
+    fdt: Option<&Fdt>,
+    arm_memory: Option<Range<u64>>,
+    framebuffer: Range<u64>,
+) -> usize {
+    let ram = fdt.into_iter().flat_map(Fdt::memory).chain(arm_memory);
+    // The boot stack and spin table, kernels chainloaded below the bootloader, the
+    // bootloader with its stacks, and the staging and Linux load areas
+    let in_use = [
+        0..&raw const __core_stacks_end as u64,
+        STAGING_ADDR as u64..LINUX_LOAD_END as u64,
+        framebuffer,
+    ];
+    let dtb = fdt.into_iter().flat_map(|fdt| {
+        let bytes = fdt.as_bytes();
+        let start = bytes.as_ptr() as u64;
+        core::iter::once(start..start + bytes.len() as u64).chain(fdt.memory_reservations())
+    });
+    frame_allocator::init(ram, in_use.into_iter().chain(dtb))
+}
+
+/// Entered on cores 1-3 from boot.s once `smp::start_secondary_cores` releases them.
+#[unsafe(no_mangle)]
+pub extern "C" fn _start_secondary_rust(core: usize) -> ! {
+    smp::secondary_main(core)
+}
+
+/// Entered from boot.s with the address of the DTB the firmware passed in `x0`.
+#[unsafe(no_mangle)]
+pub extern "C" fn _start_rust(dtb_addr: usize) -> ! {
+    exception::install();
+    let fdt = unsafe { Fdt::from_addr(dtb_addr) }.ok();
+    let platform = fdt.as_ref().map_or(Platform::DEFAULT, Platform::from_fdt);
+    platform.make_current();
+    // Before anything takes a lock, as they need the caches on. RAM from the device tree
+    // that can't be mapped falls back to the default.
+    let mapped = match &fdt {
+        Some(fdt) if fdt.memory().next().is_some() => mmu::enable(fdt.memory()),
+        _ => mmu::enable([mmu::DEFAULT_RAM]),
+    };
+    // Errors are reported once the serial console is up at the configured rate
+    let config = load_config(fdt.as_ref(), |_| {});
+    if mapped.is_err()
+        && let Err(err) = mmu::enable([mmu::DEFAULT_RAM])
+    {
+        // No lock works without the MMU, so report without the console and stop here
+        let mut serial = serial::init_serial_without_mailbox(&platform, config.serial_baud);
+        let _ = writeln!(serial, "MMU could not be turned on, halting: {err}");
+        loop {
+            core::hint::spin_loop();
+        }
+    }
+    let gic = Gic::from_platform(&platform);
+    gic.init();
+    let ticking = timer::start_ticks(&gic);
+    exception::enable_irqs();
+    let cores = 1 + smp::start_secondary_cores(fdt.as_ref());
+    SERIAL_BAUD.store(config.serial_baud, Ordering::Relaxed);
+
+    let mut mailbox = Mailbox::new(platform.mailbox_base);
+    let mut serial = serial::init_console(&mailbox, &platform, config.serial_baud);
+    let _ = writeln!(serial, "raspi4_rust_bootloader: serial console up");
+    match &fdt {
+        Some(fdt) => {
+            let _ = writeln!(serial, "Device tree at {dtb_addr:#x}");
+            for bank in fdt.memory() {
+                let _ = writeln!(serial, "Memory {:#x}..{:#x}", bank.start, bank.end);
+            }
+            if let Some(bootargs) = fdt.bootargs() {
+                let _ = writeln!(serial, "bootargs: {bootargs}");
+            }
+        }
+        None => {
+            let _ = writeln!(
+                serial,
+                "No device tree at {dtb_addr:#x}, using default peripheral addresses"
+            );
+        }
+    }
+    if let Err(err) = mapped {
+        let _ = writeln!(
+            serial,
+            "Couldn't map the device tree's memory ({err}), mapped {:#x}..{:#x} instead",
+            mmu::DEFAULT_RAM.start,
+            mmu::DEFAULT_RAM.end
+        );
+    }
+    load_config(fdt.as_ref(), |err| {
+        let _ = writeln!(serial, "Ignoring config setting {err}");
+    });
+    if let Err(err) = ticking {
+        let _ = writeln!(
+            serial,
+            "Timer interrupt unavailable ({err:?}), sleeps will spin"
+        );
+    }
+    let _ = writeln!(serial, "{cores} of {} cores running", smp::MAX_CORES);
+    report_from_secondary_cores(&mut serial);
+    let mut rtc = match config.rtc {
+        RtcKind::Ds3231 => Some(start_rtc(&mailbox, &platform, &mut serial)),
+        RtcKind::None => None,
+    };
+    if boot_image::TRUSTED_KEY.is_some() {
+        let _ = writeln!(
+            serial,
+            "Only kernels signed with the embedded key will boot"
+        );
+    }
+
+    let arm_memory = mailbox::get_arm_memory(&mailbox);
+    let mut fb = FrameBuffer::with_resolution(&mut mailbox, config.width, config.height)
+        .expect("Failed to create frame buffer");
+    fb.clear(config.background);
+    let fb_memory = fb.ptr as u64..fb.ptr as u64 + (fb.pitch * fb.height) as u64;
+    let free = init_frames(fdt.as_ref(), arm_memory, fb_memory);
+    let _ = writeln!(
+        serial,
+        "{} MiB free in {} KiB frames",
+        (free as u64 * FRAME_SIZE) >> 20,
+        FRAME_SIZE >> 10
+    );
+    // Shrink the font until the console fits on screen
+    let glyphs_fit = |scale: usize| {
+        CONSOLE_OFFSET + CONSOLE_COLS * 8 * scale <= fb.width
+            && CONSOLE_OFFSET + CONSOLE_ROWS * 8 * scale <= fb.height / 2
+    };
+    let font_scale = (1..=config.font_scale)
+        .rev()
+        .find(|&scale| glyphs_fit(scale))
+        .unwrap_or(1);
+    let mut tb = TextBuffer::<CONSOLE_ROWS, CONSOLE_COLS, Mailbox>::new(
+        &mut fb,
+        CONSOLE_OFFSET,
+        CONSOLE_OFFSET,
+        font_scale,
+        config.background,
+    );
+    tb.set_font_color(config.foreground);
+    report!(serial, tb, "Running at EL{}", exception::current_el());
+    if config.boot_target == BootTarget::Chainload {
+        chainload(&mut serial, &mut tb, config.boot_delay_s, fdt.as_ref());
+    }
+
+    draw_status(tb.frame_buffer(), &config);
+    let _ = writeln!(
+        serial,
+        "Type `time` to show the clock, `time YYYY-MM-DD HH:MM:SS` to set it, `ymodem` to \
+         upload a kernel"
+    );
+    let mut seconds = Timer::new(1000);
+    let mut command = LineBuffer::<64>::new();
     let mut counter = 0;
     loop {
-        if timer.elapsed() {
+        if seconds.elapsed() {
             let _ = writeln!(tb, "{} seconds", counter);
             counter += 1;
+            draw_status(tb.frame_buffer(), &config);
+        }
+        while let Some(byte) = serial.try_read_byte() {
+            match byte {
+                b'\r' | b'\n' => {
+                    let _ = writeln!(serial);
+                    run_command(
+                        command.as_str().trim(),
+                        &mut serial,
+                        &mut tb,
+                        rtc.as_mut(),
+                        fdt.as_ref(),
+                    );
+                    command.clear();
+                }
+                // Echo what fits in the line, drop the rest
+                b' '..=b'~' if command.write_char(byte as char).is_ok() => {
+                    serial.write_byte(byte);
+                }
+                _ => {}
+            }
         }
+        timer::sleep_ms(10);
     }
 }
 
+/// Called by exception.s for every exception taken. IRQs go to their registered handlers,
+/// anything else is reported as a crash.
+#[unsafe(no_mangle)]
+extern "C" fn _handle_exception(frame: &mut ExceptionFrame, vector: u64) {
+    let vector = Vector::from_index(vector);
+    if vector.kind == ExceptionKind::Irq {
+        let gic = Gic::from_platform(&Platform::current());
+        // Unhandled interrupts are disabled by the dispatcher, so they can't storm
+        IRQ_HANDLERS.dispatch(&gic);
+        return;
+    }
+    halt_with(CrashReport { vector, frame })
+}
+
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    let mut mailbox = Mailbox::new(MAILBOX_BASE);
-    let mut fb = FrameBuffer::new(&mut mailbox).expect("Failed to create frame buffer");
-    let mut tb = TextBuffer::<14, 26, Mailbox>::new(&mut fb, 100, 100, 8, 0xFF0000);
-    let _ = write!(tb, "PANIC:");
-    if let Some(loc) = info.location() {
-        let _ = write!(tb, "{}:{}: ", loc.file(), loc.line());
+    match info.location() {
+        Some(loc) => halt_with(format_args!(
+            "PANIC:{}:{}: {}",
+            loc.file(),
+            loc.line(),
+            info.message()
+        )),
+        None => halt_with(format_args!("PANIC:{}", info.message())),
     }
-    let _ = write!(tb, "{}\n", info.message());
+}
 
-    loop {}
+/// Reports a fatal error on the serial console and the screen, then parks the core.
+fn halt_with(report: impl Display) -> ! {
+    let platform = Platform::current();
+    let mut mailbox = Mailbox::new(platform.mailbox_base);
+
+    // Report over serial first, it keeps working when the display doesn't
+    let baud = SERIAL_BAUD.load(Ordering::Relaxed);
+    let mut serial = serial::crash_console(&mailbox, &platform, baud);
+    let _ = writeln!(serial, "{report}");
+
+    // Draw over whatever is on screen, setting a display up only if there isn't one yet
+    let fb = match FrameBuffer::current(&mut mailbox) {
+        Some(fb) => Some(fb),
+        None => FrameBuffer::new(&mut mailbox),
+    };
+    if let Some(mut fb) = fb {
+        let mut tb = TextBuffer::<CRASH_ROWS, CRASH_COLS, Mailbox>::new(
+            &mut fb,
+            CONSOLE_OFFSET,
+            CONSOLE_OFFSET,
+            CRASH_FONT_SCALE,
+            0xFF0000,
+        );
+        let _ = writeln!(tb, "{report}");
+    }
+
+    loop {
+        core::hint::spin_loop();
+    }
 }
@@ -0,0 +1,212 @@
+use crate::{
+    gpio::{Function, Gpio, Pull},
+    mmio::MmioInterface,
+    serial::{SerialInterface, UnsupportedBaud},
+};
+
+pub const AUX_BASE: usize = 0xFE215000;
+
+/// VPU core clock on the Pi 4 when the firmware is told `enable_uart=1`, used when the
+/// mailbox query fails
+pub const DEFAULT_CORE_CLOCK_HZ: u32 = 500_000_000;
+
+const AUX_ENABLES_OFFSET: usize = 0x04;
+const AUX_MU_IO_OFFSET: usize = 0x40;
+const AUX_MU_IER_OFFSET: usize = 0x44;
+const AUX_MU_IIR_OFFSET: usize = 0x48;
+const AUX_MU_LCR_OFFSET: usize = 0x4C;
+const AUX_MU_MCR_OFFSET: usize = 0x50;
+const AUX_MU_LSR_OFFSET: usize = 0x54;
+const AUX_MU_CNTL_OFFSET: usize = 0x60;
+const AUX_MU_BAUD_OFFSET: usize = 0x68;
+
+const AUX_ENABLES_MINI_UART: u32 = 1 << 0;
+
+const IIR_CLEAR_FIFOS: u32 = 0b11 << 1;
+const LCR_DATA_8BIT: u32 = 0b11;
+
+const LSR_DATA_READY: u32 = 1 << 0;
+const LSR_TX_EMPTY: u32 = 1 << 5;
+const LSR_TX_IDLE: u32 = 1 << 6;
+
+const CNTL_RX_ENABLE: u32 = 1 << 0;
+const CNTL_TX_ENABLE: u32 = 1 << 1;
+
+const TXD1_PIN: u32 = 14;
+const RXD1_PIN: u32 = 15;
+
+/// AUX mini UART (UART1) on GPIO14/15.
+///
+/// Its baud rate generator runs off the VPU core clock, so the rate is only stable while the
+/// firmware keeps that clock fixed (`enable_uart=1` or `core_freq` in `config.txt`).
+pub struct MiniUart<R: MmioInterface> {
+    regs: R,
+}
+
+impl<R: MmioInterface> MiniUart<R> {
+    pub const fn new(regs: R) -> Self {
+        MiniUart { regs }
+    }
+
+    /// Routes TXD1/RXD1 to GPIO14/15 and programs 8N1 at `baud` from a `core_clock_hz` reference.
+    /// Leaves the UART untouched if `baud_register` can't produce `baud`.
+    pub fn init<G: MmioInterface>(
+        &mut self,
+        gpio: &Gpio<G>,
+        core_clock_hz: u32,
+        baud: u32,
+    ) -> Result<(), UnsupportedBaud> {
+        let baud_reg = baud_register(core_clock_hz, baud).ok_or(UnsupportedBaud {
+            baud,
+            clock_hz: core_clock_hz,
+        })?;
+
+        self.regs.modify(
+            AUX_ENABLES_OFFSET,
+            AUX_ENABLES_MINI_UART,
+            AUX_ENABLES_MINI_UART,
+        );
+        self.regs.write(AUX_MU_CNTL_OFFSET, 0);
+        self.regs.write(AUX_MU_IER_OFFSET, 0);
+        self.regs.write(AUX_MU_LCR_OFFSET, LCR_DATA_8BIT);
+        self.regs.write(AUX_MU_MCR_OFFSET, 0);
+        self.regs.write(AUX_MU_IIR_OFFSET, IIR_CLEAR_FIFOS);
+        self.regs.write(AUX_MU_BAUD_OFFSET, baud_reg);
+
+        gpio.set_function(TXD1_PIN, Function::Alt5);
+        gpio.set_function(RXD1_PIN, Function::Alt5);
+        gpio.set_pull(TXD1_PIN, Pull::None);
+        gpio.set_pull(RXD1_PIN, Pull::Up);
+
+        self.regs
+            .write(AUX_MU_CNTL_OFFSET, CNTL_RX_ENABLE | CNTL_TX_ENABLE);
+        Ok(())
+    }
+}
+
+/// Baud register value for `core_clock_hz / (8 * (reg + 1)) = baud`, rounded to the nearest rate,
+/// or `None` if `baud` is zero or needs a divisor outside the register's 16 bits.
+pub fn baud_register(core_clock_hz: u32, baud: u32) -> Option<u32> {
+    if baud == 0 {
+        return None;
+    }
+    let divisor = (core_clock_hz as u64 + baud as u64 * 4) / (baud as u64 * 8);
+    (1..=0x1_0000)
+        .contains(&divisor)
+        .then(|| (divisor - 1) as u32)
+}
+
+impl<R: MmioInterface> SerialInterface for MiniUart<R> {
+    fn write_byte(&mut self, byte: u8) {
+        while self.regs.read(AUX_MU_LSR_OFFSET) & LSR_TX_EMPTY == 0 {}
+        self.regs.write(AUX_MU_IO_OFFSET, byte as u32);
+    }
+
+    fn read_byte(&mut self) -> u8 {
+        loop {
+            if let Some(byte) = self.try_read_byte() {
+                return byte;
+            }
+        }
+    }
+
+    fn try_read_byte(&mut self) -> Option<u8> {
+        if self.regs.read(AUX_MU_LSR_OFFSET) & LSR_DATA_READY == 0 {
+            None
+        } else {
+            Some((self.regs.read(AUX_MU_IO_OFFSET) & 0xFF) as u8)
+        }
+    }
+
+    fn flush(&mut self) {
+        while self.regs.read(AUX_MU_LSR_OFFSET) & LSR_TX_IDLE == 0 {}
+    }
+}
+
+impl<R: MmioInterface> core::fmt::Write for MiniUart<R> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for byte in s.bytes() {
+            if byte == b'\n' {
+                self.write_byte(b'\r');
+            }
+            self.write_byte(byte);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use core::fmt::Write;
+
+    use crate::mmio::mock::MockMmio;
+
+    #[test]
+    fn test_baud_register_tracks_core_clock() {
+        // 500 MHz / (8 * 115200) = 542.5, rounded to a divisor of 543 and stored minus one
+        assert_eq!(baud_register(500_000_000, 115_200), Some(542));
+        // Same baud from the 250 MHz core clock of older firmware
+        assert_eq!(baud_register(250_000_000, 115_200), Some(270));
+    }
+
+    #[test]
+    fn test_baud_register_rejects_rates_out_of_range() {
+        assert_eq!(baud_register(500_000_000, 0), None);
+        // Slowest rate whose divisor still fits in 16 bits, and the one below it
+        assert_eq!(baud_register(500_000_000, 954), Some(65513));
+        assert_eq!(baud_register(500_000_000, 953), None);
+        assert_eq!(baud_register(500_000_000, 125_000_000), Some(0));
+        assert_eq!(baud_register(500_000_000, 125_000_001), None);
+
+        let regs = MockMmio::new(0x100);
+        let gpio_regs = MockMmio::new(0x100);
+        let mut uart = MiniUart::new(&regs);
+        assert_eq!(
+            uart.init(&Gpio::new(&gpio_regs), 500_000_000, 1),
+            Err(UnsupportedBaud {
+                baud: 1,
+                clock_hz: 500_000_000
+            })
+        );
+        assert!(regs.writes_to(AUX_MU_CNTL_OFFSET).is_empty());
+    }
+
+    #[test]
+    fn test_init_enables_aux_and_selects_alt5() {
+        let regs = MockMmio::new(0x100);
+        let gpio_regs = MockMmio::new(0x100);
+        let mut uart = MiniUart::new(&regs);
+        uart.init(&Gpio::new(&gpio_regs), 500_000_000, 115_200)
+            .unwrap();
+
+        assert_eq!(regs.get(AUX_ENABLES_OFFSET), AUX_ENABLES_MINI_UART);
+        assert_eq!(regs.get(AUX_MU_LCR_OFFSET), LCR_DATA_8BIT);
+        assert_eq!(regs.get(AUX_MU_BAUD_OFFSET), 542);
+        assert_eq!(
+            regs.writes_to(AUX_MU_CNTL_OFFSET),
+            [0, CNTL_RX_ENABLE | CNTL_TX_ENABLE]
+        );
+        assert_eq!(gpio_regs.get(0x04), (0b010 << 12) | (0b010 << 15));
+    }
+
+    #[test]
+    fn test_write_and_read_poll_line_status() {
+        let regs = MockMmio::new(0x100);
+        let mut uart = MiniUart::new(&regs);
+
+        regs.queue_reads(AUX_MU_LSR_OFFSET, &[0, LSR_TX_EMPTY]);
+        regs.set(AUX_MU_LSR_OFFSET, LSR_TX_EMPTY);
+        writeln!(uart, "hi").unwrap();
+        assert_eq!(
+            regs.writes_to(AUX_MU_IO_OFFSET),
+            [b'h' as u32, b'i' as u32, b'\r' as u32, b'\n' as u32]
+        );
+
+        regs.queue_reads(AUX_MU_LSR_OFFSET, &[0, LSR_DATA_READY]);
+        regs.set(AUX_MU_IO_OFFSET, b'y' as u32);
+        assert_eq!(uart.read_byte(), b'y');
+        assert_eq!(uart.try_read_byte(), None);
+    }
+}
@@ -0,0 +1,115 @@
+use core::ptr::{read_volatile, write_volatile};
+
+/// A block of 32-bit memory-mapped peripheral registers addressed by byte offset.
+pub trait MmioInterface {
+    fn read(&self, offset: usize) -> u32;
+    fn write(&self, offset: usize, value: u32);
+
+    fn modify(&self, offset: usize, mask: u32, value: u32) {
+        let current = self.read(offset);
+        self.write(offset, (current & !mask) | (value & mask));
+    }
+}
+
+pub struct Mmio {
+    base_addr: usize,
+}
+
+impl Mmio {
+    pub const fn new(base_address: usize) -> Self {
+        Mmio {
+            base_addr: base_address,
+        }
+    }
+}
+
+impl MmioInterface for Mmio {
+    fn read(&self, offset: usize) -> u32 {
+        unsafe { read_volatile((self.base_addr + offset) as *const u32) }
+    }
+
+    fn write(&self, offset: usize, value: u32) {
+        unsafe { write_volatile((self.base_addr + offset) as *mut u32, value) }
+    }
+}
+
+impl<R: MmioInterface> MmioInterface for &R {
+    fn read(&self, offset: usize) -> u32 {
+        (**self).read(offset)
+    }
+
+    fn write(&self, offset: usize, value: u32) {
+        (**self).write(offset, value)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod mock {
+    use super::MmioInterface;
+
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::vec::Vec;
+
+    /// Register block backed by plain memory that records every write. Reads of offsets
+    /// with queued values pop from the queue first, which lets tests script status and
+    /// data registers that change between reads.
+    pub struct MockMmio {
+        regs: RefCell<Vec<u32>>,
+        queued_reads: RefCell<Vec<(usize, VecDeque<u32>)>>,
+        pub writes: RefCell<Vec<(usize, u32)>>,
+    }
+
+    impl MockMmio {
+        pub fn new(size: usize) -> Self {
+            Self {
+                regs: RefCell::new(vec![0; size / 4]),
+                queued_reads: RefCell::new(Vec::new()),
+                writes: RefCell::new(Vec::new()),
+            }
+        }
+
+        pub fn set(&self, offset: usize, value: u32) {
+            self.regs.borrow_mut()[offset / 4] = value;
+        }
+
+        pub fn get(&self, offset: usize) -> u32 {
+            self.regs.borrow()[offset / 4]
+        }
+
+        pub fn queue_reads(&self, offset: usize, values: &[u32]) {
+            let mut queued = self.queued_reads.borrow_mut();
+            match queued.iter_mut().find(|(o, _)| *o == offset) {
+                Some((_, queue)) => queue.extend(values),
+                None => queued.push((offset, values.iter().copied().collect())),
+            }
+        }
+
+        /// Values written to `offset`, in order
+        pub fn writes_to(&self, offset: usize) -> Vec<u32> {
+            self.writes
+                .borrow()
+                .iter()
+                .filter(|(o, _)| *o == offset)
+                .map(|(_, v)| *v)
+                .collect()
+        }
+    }
+
+    impl MmioInterface for MockMmio {
+        fn read(&self, offset: usize) -> u32 {
+            let mut queued = self.queued_reads.borrow_mut();
+            if let Some((_, queue)) = queued.iter_mut().find(|(o, _)| *o == offset)
+                && let Some(value) = queue.pop_front()
+            {
+                return value;
+            }
+            self.get(offset)
+        }
+
+        fn write(&self, offset: usize, value: u32) {
+            self.writes.borrow_mut().push((offset, value));
+            self.set(offset, value);
+        }
+    }
+}
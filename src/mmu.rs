@@ -0,0 +1,569 @@
+//! Identity-mapped translation tables and the MMU.
+//!
+//! RAM is mapped as normal write-back cacheable memory, the peripheral windows as device
+//! memory, and the VideoCore's memory, where the framebuffer lives, as normal
+//! non-cacheable memory so pixel writes can be combined without hiding them from the
+//! display. Tables use the 4 KiB granule over a 39-bit address space, starting at level 1,
+//! and each range is mapped with the largest blocks that fit it.
+//!
+//! Memory the VideoCore or a kernel reads behind the caches' back needs the maintenance in
+//! `cache`; `disable` flushes everything before a kernel is entered with the MMU off.
+
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::ops::Range;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+#[cfg(target_arch = "aarch64")]
+use core::arch::asm;
+
+use crate::exception;
+
+pub const PAGE_SIZE: u64 = 4096;
+/// Addresses up to 512 GiB are translated, enough for the high peripheral window
+const ADDRESS_BITS: u32 = 39;
+const ENTRIES: usize = 512;
+const MAX_TABLES: usize = 16;
+
+/// ARM memory with the firmware's default 76 MB `gpu_mem`, for boards without a device tree
+pub const DEFAULT_RAM: Range<u64> = 0..0x3B40_0000;
+/// The VideoCore's memory runs from the end of the ARM's first bank to here
+const VIDEOCORE_END: u64 = 0x4000_0000;
+
+/// Peripherals in low peripheral mode, including the ARM local block with the GIC, and
+/// the same peripherals in high peripheral mode
+pub const PERIPHERAL_WINDOWS: [Range<u64>; 2] =
+    [0xFC00_0000..0x1_0000_0000, 0x4_7C00_0000..0x4_8000_0000];
+
+const DESC_VALID: u64 = 1 << 0;
+/// Table descriptor above level 3, page descriptor at level 3
+const DESC_TABLE: u64 = 1 << 1;
+const DESC_ATTR_SHIFT: u32 = 2;
+const DESC_INNER_SHAREABLE: u64 = 0b11 << 8;
+const DESC_ACCESSED: u64 = 1 << 10;
+/// PXN at EL1, RES0 at EL2
+const DESC_PXN: u64 = 1 << 53;
+/// UXN at EL1, XN at EL2
+const DESC_XN: u64 = 1 << 54;
+const DESC_ADDRESS: u64 = 0x0000_FFFF_FFFF_F000;
+
+/// System register values for turning the MMU on, only used on the target
+#[cfg_attr(not(target_arch = "aarch64"), allow(dead_code))]
+mod regs {
+    use super::ADDRESS_BITS;
+
+    /// MAIR attributes by `MemoryKind` index: write-back read/write-allocate, Device-nGnRE,
+    /// and non-cacheable
+    pub const MAIR: u64 = 0x44_04_FF;
+
+    pub const TCR_T0SZ: u64 = (64 - ADDRESS_BITS) as u64;
+    /// Walks are inner shareable and write-back cacheable, with the 4 KiB granule
+    pub const TCR_WALKS: u64 = TCR_T0SZ | (0b01 << 8) | (0b01 << 10) | (0b11 << 12);
+    /// No TTBR1 walks, 40-bit physical addresses
+    pub const TCR_EL1: u64 = TCR_WALKS | (1 << 23) | (0b010 << 32);
+    /// RES1 bits 23 and 31, 40-bit physical addresses
+    pub const TCR_EL2: u64 = TCR_WALKS | (1 << 23) | (1 << 31) | (0b010 << 16);
+
+    pub const SCTLR_MMU: u64 = 1 << 0;
+    pub const SCTLR_DCACHE: u64 = 1 << 2;
+    pub const SCTLR_ICACHE: u64 = 1 << 12;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MemoryKind {
+    /// Write-back cacheable RAM
+    Normal = 0,
+    /// Device-nGnRE registers, never executed or speculatively read
+    Device = 1,
+    /// Non-cacheable normal memory, for buffers another bus master reads such as the
+    /// framebuffer
+    WriteCombining = 2,
+}
+
+impl MemoryKind {
+    fn from_index(index: u64) -> Self {
+        match index {
+            0 => MemoryKind::Normal,
+            1 => MemoryKind::Device,
+            _ => MemoryKind::WriteCombining,
+        }
+    }
+}
+
+/// The translation regime the tables are for, which decides the execute-never bits.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Regime {
+    El1,
+    El2,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MmuError {
+    /// A range doesn't start and end on a page boundary
+    Unaligned,
+    /// A range goes past the translated address space
+    OutOfRange,
+    OutOfTables,
+}
+
+impl fmt::Display for MmuError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MmuError::Unaligned => write!(f, "range not page aligned"),
+            MmuError::OutOfRange => write!(f, "range beyond the translated address space"),
+            MmuError::OutOfTables => write!(f, "out of translation tables"),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+#[repr(C, align(4096))]
+struct PageTable([u64; ENTRIES]);
+
+/// Bytes covered by one entry at `level`.
+const fn entry_size(level: usize) -> u64 {
+    1 << (12 + 9 * (3 - level))
+}
+
+/// A pool of `N` tables, the first being the level 1 table `TTBR0` points at. Descriptors
+/// hold the tables' own addresses, so they must not move once something is mapped.
+pub struct TranslationTables<const N: usize> {
+    tables: [PageTable; N],
+    used: usize,
+    regime: Regime,
+}
+
+impl<const N: usize> TranslationTables<N> {
+    pub const fn new(regime: Regime) -> Self {
+        TranslationTables {
+            tables: [PageTable([0; ENTRIES]); N],
+            used: 1,
+            regime,
+        }
+    }
+
+    /// Unmaps everything.
+    pub fn clear(&mut self, regime: Regime) {
+        for table in &mut self.tables[..self.used] {
+            table.0 = [0; ENTRIES];
+        }
+        self.used = 1;
+        self.regime = regime;
+    }
+
+    pub fn root_address(&self) -> u64 {
+        self.tables.as_ptr() as u64
+    }
+
+    pub fn tables_used(&self) -> usize {
+        self.used
+    }
+
+    fn table_address(&self, index: usize) -> u64 {
+        self.root_address() + index as u64 * PAGE_SIZE
+    }
+
+    fn table_index(&self, descriptor: u64) -> usize {
+        ((descriptor & DESC_ADDRESS) - self.root_address()) as usize / PAGE_SIZE as usize
+    }
+
+    fn attributes(&self, kind: MemoryKind) -> u64 {
+        let attributes = DESC_ACCESSED | (kind as u64) << DESC_ATTR_SHIFT;
+        match (kind, self.regime) {
+            (MemoryKind::Device, Regime::El1) => attributes | DESC_XN | DESC_PXN,
+            (MemoryKind::Device, Regime::El2) => attributes | DESC_XN,
+            _ => attributes | DESC_INNER_SHAREABLE,
+        }
+    }
+
+    /// Identity-maps `range` as `kind`, replacing whatever part of it was mapped before.
+    pub fn map(&mut self, range: Range<u64>, kind: MemoryKind) -> Result<(), MmuError> {
+        if !range.start.is_multiple_of(PAGE_SIZE) || !range.end.is_multiple_of(PAGE_SIZE) {
+            return Err(MmuError::Unaligned);
+        }
+        if range.end > 1 << ADDRESS_BITS {
+            return Err(MmuError::OutOfRange);
+        }
+        let attributes = self.attributes(kind);
+        self.map_in(0, 1, range, attributes)
+    }
+
+    fn map_in(
+        &mut self,
+        table: usize,
+        level: usize,
+        range: Range<u64>,
+        attributes: u64,
+    ) -> Result<(), MmuError> {
+        let size = entry_size(level);
+        let mut address = range.start;
+        while address < range.end {
+            let index = (address / size) as usize % ENTRIES;
+            let entry_start = address & !(size - 1);
+            let chunk_end = range.end.min(entry_start + size);
+            if address == entry_start && chunk_end == entry_start + size {
+                // Whole entries become blocks, or pages at level 3
+                let kind = if level == 3 { DESC_TABLE } else { 0 };
+                self.tables[table].0[index] = address | attributes | kind | DESC_VALID;
+            } else {
+                let next = self.next_table(table, level, index)?;
+                self.map_in(next, level + 1, address..chunk_end, attributes)?;
+            }
+            address = chunk_end;
+        }
+        Ok(())
+    }
+
+    /// The table below entry `index`, creating it if needed. A block being mapped in
+    /// part is split into a table of smaller blocks with the same attributes.
+    fn next_table(&mut self, table: usize, level: usize, index: usize) -> Result<usize, MmuError> {
+        let entry = self.tables[table].0[index];
+        if entry & (DESC_VALID | DESC_TABLE) == DESC_VALID | DESC_TABLE {
+            return Ok(self.table_index(entry));
+        }
+        if self.used == N {
+            return Err(MmuError::OutOfTables);
+        }
+        let next = self.used;
+        self.used += 1;
+        if entry & DESC_VALID != 0 {
+            let size = entry_size(level + 1);
+            let kind = if level + 1 == 3 { DESC_TABLE } else { 0 };
+            let block = entry & DESC_ADDRESS;
+            let attributes = entry & !DESC_ADDRESS & !DESC_TABLE;
+            for (n, descriptor) in self.tables[next].0.iter_mut().enumerate() {
+                *descriptor = (block + n as u64 * size) | attributes | kind;
+            }
+        }
+        self.tables[table].0[index] = self.table_address(next) | DESC_TABLE | DESC_VALID;
+        Ok(next)
+    }
+
+    /// Where `address` is mapped to, and as what.
+    pub fn translate(&self, address: u64) -> Option<(u64, MemoryKind)> {
+        if address >= 1 << ADDRESS_BITS {
+            return None;
+        }
+        let mut table = 0;
+        for level in 1..=3 {
+            let size = entry_size(level);
+            let entry = self.tables[table].0[(address / size) as usize % ENTRIES];
+            if entry & DESC_VALID == 0 {
+                return None;
+            }
+            if level < 3 && entry & DESC_TABLE != 0 {
+                table = self.table_index(entry);
+                continue;
+            }
+            let output = (entry & DESC_ADDRESS & !(size - 1)) + address % size;
+            let kind = MemoryKind::from_index((entry >> DESC_ATTR_SHIFT) & 0b111);
+            return Some((output, kind));
+        }
+        None
+    }
+}
+
+struct TableCell(UnsafeCell<TranslationTables<MAX_TABLES>>);
+
+// SAFETY: Only `enable` writes the tables, once, on the boot core before the other cores
+// start, and after that only the table walkers read them
+unsafe impl Sync for TableCell {}
+
+// Not behind a lock as exclusive loads and stores, and so the locks in `sync`, only work
+// on the BCM2711 once the MMU is on
+static TABLES: TableCell = TableCell(UnsafeCell::new(TranslationTables::new(Regime::El1)));
+/// The level 1 table's address once the tables are built, for the other cores
+static ROOT: AtomicU64 = AtomicU64::new(0);
+
+fn page_aligned(range: Range<u64>) -> Range<u64> {
+    range.start & !(PAGE_SIZE - 1)..range.end.next_multiple_of(PAGE_SIZE)
+}
+
+impl<const N: usize> TranslationTables<N> {
+    /// Maps the board: `ram` as normal memory, the VideoCore's part of the first GiB above
+    /// the ARM's first bank as write-combining and the peripheral windows as device memory.
+    pub fn map_board(&mut self, ram: impl IntoIterator<Item = Range<u64>>) -> Result<(), MmuError> {
+        let mut videocore_start = None;
+        for bank in ram {
+            if bank.start == 0 {
+                videocore_start = Some(bank.end);
+            }
+            self.map(page_aligned(bank), MemoryKind::Normal)?;
+        }
+        if let Some(start) = videocore_start.filter(|&start| start < VIDEOCORE_END) {
+            self.map(
+                page_aligned(start..VIDEOCORE_END),
+                MemoryKind::WriteCombining,
+            )?;
+        }
+        for window in PERIPHERAL_WINDOWS {
+            self.map(window, MemoryKind::Device)?;
+        }
+        Ok(())
+    }
+}
+
+/// Maps the board with `map_board` and turns the MMU and caches on for this core. Must
+/// run on the boot core before anything takes a lock or starts the other cores.
+pub fn enable(ram: impl IntoIterator<Item = Range<u64>>) -> Result<(), MmuError> {
+    if ROOT.load(Ordering::Acquire) != 0 {
+        return Ok(());
+    }
+    let regime = match exception::current_el() {
+        2 => Regime::El2,
+        _ => Regime::El1,
+    };
+    // SAFETY: See `TableCell`, and ROOT is still 0 so nothing walks the tables yet
+    let tables = unsafe { &mut *TABLES.0.get() };
+    tables.clear(regime);
+    tables.map_board(ram)?;
+    // Built with the caches off, so the tables are already in memory for the walker
+    // and the secondary cores
+    ROOT.store(tables.root_address(), Ordering::Release);
+    enable_with(tables.root_address());
+    Ok(())
+}
+
+/// Turns the MMU on for a secondary core, with the tables the boot core built.
+pub fn enable_secondary() {
+    let root = ROOT.load(Ordering::Acquire);
+    if root != 0 {
+        enable_with(root);
+    }
+}
+
+fn enable_with(root: u64) {
+    #[cfg(target_arch = "aarch64")]
+    // SAFETY: The tables identity-map all of RAM, so execution carries on where it was
+    unsafe {
+        if exception::current_el() == 2 {
+            asm!(
+                "msr MAIR_EL2, {mair}",
+                "msr TCR_EL2, {tcr}",
+                "msr TTBR0_EL2, {root}",
+                "dsb ish",
+                "isb",
+                "tlbi alle2",
+                "ic iallu",
+                "dsb ish",
+                "isb",
+                "mrs {tmp}, SCTLR_EL2",
+                "orr {tmp}, {tmp}, {bits}",
+                "msr SCTLR_EL2, {tmp}",
+                "isb",
+                mair = in(reg) regs::MAIR,
+                tcr = in(reg) regs::TCR_EL2,
+                root = in(reg) root,
+                bits = in(reg) regs::SCTLR_MMU | regs::SCTLR_DCACHE | regs::SCTLR_ICACHE,
+                tmp = out(reg) _,
+            );
+        } else {
+            asm!(
+                "msr MAIR_EL1, {mair}",
+                "msr TCR_EL1, {tcr}",
+                "msr TTBR0_EL1, {root}",
+                "dsb ish",
+                "isb",
+                "tlbi vmalle1",
+                "ic iallu",
+                "dsb ish",
+                "isb",
+                "mrs {tmp}, SCTLR_EL1",
+                "orr {tmp}, {tmp}, {bits}",
+                "msr SCTLR_EL1, {tmp}",
+                "isb",
+                mair = in(reg) regs::MAIR,
+                tcr = in(reg) regs::TCR_EL1,
+                root = in(reg) root,
+                bits = in(reg) regs::SCTLR_MMU | regs::SCTLR_DCACHE | regs::SCTLR_ICACHE,
+                tmp = out(reg) _,
+            );
+        }
+    }
+
+    #[cfg(not(target_arch = "aarch64"))]
+    let _ = root;
+}
+
+/// Turns the MMU and data cache off for this core, writing every dirty line back first,
+/// as kernels and the firmware's spin table expect.
+pub fn disable() {
+    #[cfg(target_arch = "aarch64")]
+    // SAFETY: Identity mapping means addresses don't change. The caches are flushed after
+    // they stop allocating, from a routine that doesn't touch memory
+    unsafe {
+        if exception::current_el() == 2 {
+            asm!(
+                "mrs x12, SCTLR_EL2",
+                "bic x12, x12, {bits}",
+                "msr SCTLR_EL2, x12",
+                "isb",
+                // cache::clean_invalidate_dcache_all, without touching the stack
+                "bl __dcache_clean_invalidate_all",
+                "ic iallu",
+                "tlbi alle2",
+                "dsb sy",
+                "isb",
+                bits = in(reg) regs::SCTLR_MMU | regs::SCTLR_DCACHE,
+                // x12 and the flush routine's registers are all caller-saved
+                clobber_abi("C"),
+            );
+        } else {
+            asm!(
+                "mrs x12, SCTLR_EL1",
+                "bic x12, x12, {bits}",
+                "msr SCTLR_EL1, x12",
+                "isb",
+                // cache::clean_invalidate_dcache_all, without touching the stack
+                "bl __dcache_clean_invalidate_all",
+                "ic iallu",
+                "tlbi vmalle1",
+                "dsb sy",
+                "isb",
+                bits = in(reg) regs::SCTLR_MMU | regs::SCTLR_DCACHE,
+                // x12 and the flush routine's registers are all caller-saved
+                clobber_abi("C"),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::fdt::Fdt;
+
+    const GIB: u64 = 1 << 30;
+
+    #[test]
+    fn test_maps_with_the_largest_blocks() {
+        let mut tables = TranslationTables::<4>::new(Regime::El1);
+        // The 4 GB board's memory banks: a level 2 table for the first GiB, 1 GiB
+        // blocks, and another level 2 table shared with the peripherals
+        tables.map(0..0x3B40_0000, MemoryKind::Normal).unwrap();
+        tables
+            .map(0x4000_0000..0xFC00_0000, MemoryKind::Normal)
+            .unwrap();
+        tables
+            .map(PERIPHERAL_WINDOWS[0].clone(), MemoryKind::Device)
+            .unwrap();
+        assert_eq!(tables.tables_used(), 3);
+
+        assert_eq!(
+            tables.translate(0x8_0123),
+            Some((0x8_0123, MemoryKind::Normal))
+        );
+        assert_eq!(tables.translate(0x3B40_0000), None);
+        assert_eq!(
+            tables.translate(GIB + 0x1234),
+            Some((GIB + 0x1234, MemoryKind::Normal))
+        );
+        assert_eq!(
+            tables.translate(0xFE20_1000),
+            Some((0xFE20_1000, MemoryKind::Device))
+        );
+        assert_eq!(tables.translate(0x1_0000_0000), None);
+        assert_eq!(tables.translate(1 << ADDRESS_BITS), None);
+
+        // Device memory is never executable, RAM always shareable
+        let device = tables.tables[2].0[(0xFE20_0000 / entry_size(2)) as usize % ENTRIES];
+        assert_eq!(device & (DESC_XN | DESC_PXN), DESC_XN | DESC_PXN);
+        assert_eq!(
+            tables.tables[0].0[1] & DESC_INNER_SHAREABLE,
+            DESC_INNER_SHAREABLE
+        );
+    }
+
+    #[test]
+    fn test_splits_blocks_for_smaller_mappings() {
+        let mut tables = TranslationTables::<4>::new(Regime::El2);
+        tables.map(0..GIB, MemoryKind::Normal).unwrap();
+        assert_eq!(tables.tables_used(), 1);
+
+        // A framebuffer that isn't 2 MiB aligned needs level 3 pages
+        let framebuffer = 0x3E00_1000..0x3E00_3000;
+        tables
+            .map(framebuffer.clone(), MemoryKind::WriteCombining)
+            .unwrap();
+        assert_eq!(tables.tables_used(), 3);
+        for (address, kind) in [
+            (0x3E00_0FFF, MemoryKind::Normal),
+            (0x3E00_1000, MemoryKind::WriteCombining),
+            (0x3E00_2ABC, MemoryKind::WriteCombining),
+            (0x3E00_3000, MemoryKind::Normal),
+            (0x3E20_0000, MemoryKind::Normal),
+            (0x3FFF_F000, MemoryKind::Normal),
+            (0, MemoryKind::Normal),
+        ] {
+            assert_eq!(
+                tables.translate(address),
+                Some((address, kind)),
+                "{address:#x}"
+            );
+        }
+
+        tables.clear(Regime::El2);
+        assert_eq!(tables.translate(0), None);
+        assert_eq!(tables.tables_used(), 1);
+    }
+
+    #[test]
+    fn test_maps_the_board_from_its_device_tree() {
+        let fdt = Fdt::new(include_bytes!("../tests/fixtures/dtb/rpi4b.dtb")).unwrap();
+        let mut tables = TranslationTables::<8>::new(Regime::El1);
+        tables.map_board(fdt.memory()).unwrap();
+        // The root and level 2 tables for the first GiB, the last below 4 GiB and the high
+        // peripherals
+        assert_eq!(tables.tables_used(), 4);
+        for (address, kind) in [
+            (0x3B3F_F000, Some(MemoryKind::Normal)),
+            (0x3E80_0000, Some(MemoryKind::WriteCombining)),
+            (0x3FFF_FFFF, Some(MemoryKind::WriteCombining)),
+            (0xFBFF_FFFF, Some(MemoryKind::Normal)),
+            (0xFF84_1000, Some(MemoryKind::Device)),
+            (0x4_7E20_1000, Some(MemoryKind::Device)),
+            (0x1_0000_0000, None),
+        ] {
+            assert_eq!(
+                tables.translate(address),
+                kind.map(|kind| (address, kind)),
+                "{address:#x}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_maps_peripherals_from_the_firmware_blob() {
+        let blob = crate::fdt::firmware_dtb();
+        let fdt = Fdt::new(&blob).unwrap();
+        // No RAM until the firmware fills /memory in, so `enable` would use DEFAULT_RAM
+        assert_eq!(fdt.memory().next(), None);
+        let mut tables = TranslationTables::<8>::new(Regime::El1);
+        tables.map_board([DEFAULT_RAM]).unwrap();
+        let mailbox = fdt.find_compatible("brcm,bcm2835-mbox").unwrap();
+        let mailbox = mailbox.reg_address(0).unwrap();
+        assert_eq!(
+            tables.translate(mailbox),
+            Some((mailbox, MemoryKind::Device))
+        );
+    }
+
+    #[test]
+    fn test_rejects_what_it_cannot_map() {
+        let mut tables = TranslationTables::<2>::new(Regime::El1);
+        assert_eq!(
+            tables.map(0x1000..0x1800, MemoryKind::Normal),
+            Err(MmuError::Unaligned)
+        );
+        assert_eq!(
+            tables.map(0..(1 << ADDRESS_BITS) + PAGE_SIZE, MemoryKind::Normal),
+            Err(MmuError::OutOfRange)
+        );
+        // A page needs a level 2 and a level 3 table below the root
+        assert_eq!(
+            tables.map(0..PAGE_SIZE, MemoryKind::Normal),
+            Err(MmuError::OutOfTables)
+        );
+    }
+}
@@ -0,0 +1,206 @@
+use crate::{
+    gpio::{Function, Gpio, Pull},
+    mmio::MmioInterface,
+    serial::{SerialInterface, UnsupportedBaud},
+};
+
+pub const PL011_BASE: usize = 0xFE201000;
+
+/// UART reference clock the firmware configures by default, used when the mailbox query fails
+pub const DEFAULT_UART_CLOCK_HZ: u32 = 48_000_000;
+
+const DR_OFFSET: usize = 0x00;
+const FR_OFFSET: usize = 0x18;
+const IBRD_OFFSET: usize = 0x24;
+const FBRD_OFFSET: usize = 0x28;
+const LCRH_OFFSET: usize = 0x2C;
+const CR_OFFSET: usize = 0x30;
+const IMSC_OFFSET: usize = 0x38;
+const ICR_OFFSET: usize = 0x44;
+
+const FR_BUSY: u32 = 1 << 3;
+const FR_RXFE: u32 = 1 << 4;
+const FR_TXFF: u32 = 1 << 5;
+
+const LCRH_FEN: u32 = 1 << 4;
+const LCRH_WLEN_8: u32 = 0b11 << 5;
+
+const CR_UARTEN: u32 = 1 << 0;
+const CR_TXE: u32 = 1 << 8;
+const CR_RXE: u32 = 1 << 9;
+
+const TXD0_PIN: u32 = 14;
+const RXD0_PIN: u32 = 15;
+
+/// ARM PrimeCell UART (UART0) on GPIO14/15.
+pub struct Pl011<R: MmioInterface> {
+    regs: R,
+}
+
+impl<R: MmioInterface> Pl011<R> {
+    pub const fn new(regs: R) -> Self {
+        Pl011 { regs }
+    }
+
+    /// Routes TXD0/RXD0 to GPIO14/15 and programs 8N1 at `baud` from a `clock_hz` reference.
+    /// Leaves the UART untouched if `baud_divisor` can't produce `baud`.
+    pub fn init<G: MmioInterface>(
+        &mut self,
+        gpio: &Gpio<G>,
+        clock_hz: u32,
+        baud: u32,
+    ) -> Result<(), UnsupportedBaud> {
+        let (ibrd, fbrd) =
+            baud_divisor(clock_hz, baud).ok_or(UnsupportedBaud { baud, clock_hz })?;
+
+        // The UART must be disabled and drained before reprogramming it
+        self.regs.write(CR_OFFSET, 0);
+        while self.regs.read(FR_OFFSET) & FR_BUSY != 0 {}
+
+        gpio.set_function(TXD0_PIN, Function::Alt0);
+        gpio.set_function(RXD0_PIN, Function::Alt0);
+        gpio.set_pull(TXD0_PIN, Pull::None);
+        gpio.set_pull(RXD0_PIN, Pull::Up);
+
+        self.regs.write(ICR_OFFSET, 0x7FF);
+        self.regs.write(IBRD_OFFSET, ibrd);
+        self.regs.write(FBRD_OFFSET, fbrd);
+        // LCRH must be written after the divisors for them to latch
+        self.regs.write(LCRH_OFFSET, LCRH_WLEN_8 | LCRH_FEN);
+        self.regs.write(IMSC_OFFSET, 0);
+        self.regs.write(CR_OFFSET, CR_UARTEN | CR_TXE | CR_RXE);
+        Ok(())
+    }
+}
+
+/// Splits `clock_hz / (16 * baud)` into the integer and 6-bit fractional divisor registers,
+/// or `None` if `baud` is zero or the divisor falls outside the 1 to 65535 the UART takes.
+pub fn baud_divisor(clock_hz: u32, baud: u32) -> Option<(u32, u32)> {
+    if baud == 0 {
+        return None;
+    }
+    // 64 * clock / (16 * baud), rounded to the nearest fractional step
+    let divisor = (clock_hz as u64 * 4 + baud as u64 / 2) / baud as u64;
+    (64..=0xFFFF << 6)
+        .contains(&divisor)
+        .then_some(((divisor >> 6) as u32, (divisor & 0x3F) as u32))
+}
+
+impl<R: MmioInterface> SerialInterface for Pl011<R> {
+    fn write_byte(&mut self, byte: u8) {
+        while self.regs.read(FR_OFFSET) & FR_TXFF != 0 {}
+        self.regs.write(DR_OFFSET, byte as u32);
+    }
+
+    fn read_byte(&mut self) -> u8 {
+        loop {
+            if let Some(byte) = self.try_read_byte() {
+                return byte;
+            }
+        }
+    }
+
+    fn try_read_byte(&mut self) -> Option<u8> {
+        if self.regs.read(FR_OFFSET) & FR_RXFE != 0 {
+            None
+        } else {
+            Some((self.regs.read(DR_OFFSET) & 0xFF) as u8)
+        }
+    }
+
+    fn flush(&mut self) {
+        while self.regs.read(FR_OFFSET) & FR_BUSY != 0 {}
+    }
+}
+
+impl<R: MmioInterface> core::fmt::Write for Pl011<R> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for byte in s.bytes() {
+            if byte == b'\n' {
+                self.write_byte(b'\r');
+            }
+            self.write_byte(byte);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use core::fmt::Write;
+
+    use crate::mmio::mock::MockMmio;
+
+    #[test]
+    fn test_baud_divisor_matches_datasheet_example() {
+        // 48 MHz at 115200 baud is 26.0416..., i.e. IBRD 26 and FBRD round(0.0416 * 64) = 3
+        assert_eq!(baud_divisor(48_000_000, 115_200), Some((26, 3)));
+        assert_eq!(baud_divisor(48_000_000, 921_600), Some((3, 16)));
+    }
+
+    #[test]
+    fn test_baud_divisor_rejects_rates_out_of_range() {
+        assert_eq!(baud_divisor(48_000_000, 0), None);
+        // IBRD would be 3,000,000, far beyond its 16 bits
+        assert_eq!(baud_divisor(48_000_000, 1), None);
+        assert_eq!(baud_divisor(48_000_000, 45), None);
+        assert_eq!(baud_divisor(48_000_000, 46), Some((65217, 25)));
+        assert_eq!(baud_divisor(48_000_000, 3_000_000), Some((1, 0)));
+        assert_eq!(baud_divisor(48_000_000, 4_000_000), None);
+
+        let regs = MockMmio::new(0x100);
+        let gpio_regs = MockMmio::new(0x100);
+        let mut uart = Pl011::new(&regs);
+        assert_eq!(
+            uart.init(&Gpio::new(&gpio_regs), 48_000_000, 0),
+            Err(UnsupportedBaud {
+                baud: 0,
+                clock_hz: 48_000_000
+            })
+        );
+        assert!(regs.writes_to(CR_OFFSET).is_empty());
+    }
+
+    #[test]
+    fn test_init_configures_gpio_and_line() {
+        let regs = MockMmio::new(0x100);
+        let gpio_regs = MockMmio::new(0x100);
+        let mut uart = Pl011::new(&regs);
+        uart.init(&Gpio::new(&gpio_regs), 48_000_000, 115_200)
+            .unwrap();
+
+        assert_eq!(regs.get(IBRD_OFFSET), 26);
+        assert_eq!(regs.get(FBRD_OFFSET), 3);
+        assert_eq!(regs.get(LCRH_OFFSET), LCRH_WLEN_8 | LCRH_FEN);
+        assert_eq!(regs.get(CR_OFFSET), CR_UARTEN | CR_TXE | CR_RXE);
+        // The UART is disabled first and only enabled at the very end
+        assert_eq!(regs.writes_to(CR_OFFSET), [0, CR_UARTEN | CR_TXE | CR_RXE]);
+
+        // GPFSEL1 holds pins 10-19: ALT0 for 14 and 15
+        assert_eq!(gpio_regs.get(0x04), (0b100 << 12) | (0b100 << 15));
+        // Pull-up on RXD0 only
+        assert_eq!(gpio_regs.get(0xE4), 0b01 << 30);
+    }
+
+    #[test]
+    fn test_write_translates_newlines_and_read_waits_for_data() {
+        let regs = MockMmio::new(0x100);
+        let mut uart = Pl011::new(&regs);
+
+        regs.queue_reads(FR_OFFSET, &[FR_TXFF, FR_TXFF]);
+        writeln!(uart, "ok").unwrap();
+        assert_eq!(
+            regs.writes_to(DR_OFFSET),
+            [b'o' as u32, b'k' as u32, b'\r' as u32, b'\n' as u32]
+        );
+
+        regs.queue_reads(FR_OFFSET, &[FR_RXFE, FR_RXFE, 0]);
+        regs.set(DR_OFFSET, 0x100 | b'x' as u32);
+        assert_eq!(uart.read_byte(), b'x');
+
+        regs.set(FR_OFFSET, FR_RXFE);
+        assert_eq!(uart.try_read_byte(), None);
+    }
+}
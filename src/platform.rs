@@ -0,0 +1,126 @@
+//! Peripheral base addresses, discovered from the device tree when there is one.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::fdt::Fdt;
+use crate::gic::{GICC_BASE, GICD_BASE};
+use crate::gpio::GPIO_BASE;
+use crate::i2c::I2C1_BASE;
+use crate::mini_uart::AUX_BASE;
+use crate::pl011::PL011_BASE;
+
+pub const MAILBOX_BASE: usize = 0xFE00B880;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Platform {
+    pub mailbox_base: usize,
+    pub gpio_base: usize,
+    pub pl011_base: usize,
+    pub aux_base: usize,
+    pub gicd_base: usize,
+    pub gicc_base: usize,
+    pub i2c1_base: usize,
+}
+
+// The platform in use, for code like the panic handler that can't be handed one
+static MAILBOX: AtomicUsize = AtomicUsize::new(MAILBOX_BASE);
+static GPIO: AtomicUsize = AtomicUsize::new(GPIO_BASE);
+static PL011: AtomicUsize = AtomicUsize::new(PL011_BASE);
+static AUX: AtomicUsize = AtomicUsize::new(AUX_BASE);
+static GICD: AtomicUsize = AtomicUsize::new(GICD_BASE);
+static GICC: AtomicUsize = AtomicUsize::new(GICC_BASE);
+static I2C1: AtomicUsize = AtomicUsize::new(I2C1_BASE);
+
+impl Platform {
+    /// The BCM2711's addresses in the default low peripheral mode.
+    pub const DEFAULT: Platform = Platform {
+        mailbox_base: MAILBOX_BASE,
+        gpio_base: GPIO_BASE,
+        pl011_base: PL011_BASE,
+        aux_base: AUX_BASE,
+        gicd_base: GICD_BASE,
+        gicc_base: GICC_BASE,
+        i2c1_base: I2C1_BASE,
+    };
+
+    /// Looks each peripheral up in `fdt`, keeping the default for any it doesn't describe.
+    pub fn from_fdt(fdt: &Fdt) -> Self {
+        let find = |compatible: &str, default: usize| {
+            fdt.find_compatible(compatible)
+                .and_then(|node| node.reg_address(0))
+                .map_or(default, |addr| addr as usize)
+        };
+
+        // The PL011 the firmware routes to the header pins is behind a serial alias
+        let pl011 = ["serial0", "serial1"]
+            .into_iter()
+            .filter_map(|alias| fdt.find_node(alias))
+            .find(|node| node.is_compatible("arm,pl011"))
+            .or_else(|| fdt.find_compatible("arm,pl011"))
+            .and_then(|node| node.reg_address(0))
+            .map_or(PL011_BASE, |addr| addr as usize);
+
+        // The GIC's first reg entry is the distributor, the second the CPU interface
+        let gic = fdt.find_compatible("arm,gic-400");
+        let gic_reg = |index: usize, default: usize| {
+            gic.as_ref()
+                .and_then(|node| node.reg_address(index))
+                .map_or(default, |addr| addr as usize)
+        };
+
+        Platform {
+            mailbox_base: find("brcm,bcm2835-mbox", MAILBOX_BASE),
+            gpio_base: find("brcm,bcm2711-gpio", GPIO_BASE),
+            pl011_base: pl011,
+            aux_base: find("brcm,bcm2835-aux", AUX_BASE),
+            gicd_base: gic_reg(0, GICD_BASE),
+            gicc_base: gic_reg(1, GICC_BASE),
+            // BSC1 is the controller on the header's I2C pins
+            i2c1_base: fdt
+                .find_node("i2c1")
+                .and_then(|node| node.reg_address(0))
+                .map_or(I2C1_BASE, |addr| addr as usize),
+        }
+    }
+
+    /// The platform last made current, or `DEFAULT`.
+    pub fn current() -> Self {
+        Platform {
+            mailbox_base: MAILBOX.load(Ordering::Relaxed),
+            gpio_base: GPIO.load(Ordering::Relaxed),
+            pl011_base: PL011.load(Ordering::Relaxed),
+            aux_base: AUX.load(Ordering::Relaxed),
+            gicd_base: GICD.load(Ordering::Relaxed),
+            gicc_base: GICC.load(Ordering::Relaxed),
+            i2c1_base: I2C1.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn make_current(&self) {
+        MAILBOX.store(self.mailbox_base, Ordering::Relaxed);
+        GPIO.store(self.gpio_base, Ordering::Relaxed);
+        PL011.store(self.pl011_base, Ordering::Relaxed);
+        AUX.store(self.aux_base, Ordering::Relaxed);
+        GICD.store(self.gicd_base, Ordering::Relaxed);
+        GICC.store(self.gicc_base, Ordering::Relaxed);
+        I2C1.store(self.i2c1_base, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_discovers_rpi4_peripherals() {
+        let fdt = Fdt::new(include_bytes!("../tests/fixtures/dtb/rpi4b.dtb")).unwrap();
+        assert_eq!(Platform::from_fdt(&fdt), Platform::DEFAULT);
+    }
+
+    #[test]
+    fn test_discovers_peripherals_from_the_firmware_blob() {
+        let blob = crate::fdt::firmware_dtb();
+        let fdt = Fdt::new(&blob).unwrap();
+        assert_eq!(Platform::from_fdt(&fdt), Platform::DEFAULT);
+    }
+}
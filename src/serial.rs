@@ -0,0 +1,216 @@
+use core::fmt;
+
+use crate::{
+    gpio::Gpio,
+    mailbox::{self, MailboxInterface},
+    mmio::Mmio,
+    platform::Platform,
+    sync::{Once, SpinLock},
+};
+
+#[cfg(feature = "mini-uart")]
+use crate::{
+    mailbox::CLOCK_CORE,
+    mini_uart::{DEFAULT_CORE_CLOCK_HZ, MiniUart},
+};
+#[cfg(not(feature = "mini-uart"))]
+use crate::{
+    mailbox::CLOCK_UART,
+    pl011::{DEFAULT_UART_CLOCK_HZ, Pl011},
+};
+
+/// Byte-level access to a UART, shared by every serial backend.
+pub trait SerialInterface {
+    /// Blocks until there is room in the transmit FIFO, then queues `byte`
+    fn write_byte(&mut self, byte: u8);
+
+    /// Blocks until a byte has been received
+    fn read_byte(&mut self) -> u8;
+
+    fn try_read_byte(&mut self) -> Option<u8>;
+
+    /// Blocks until every queued byte has left the transmitter
+    fn flush(&mut self);
+
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.write_byte(byte);
+        }
+    }
+}
+
+/// A baud rate the UART can't derive from its reference clock.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UnsupportedBaud {
+    pub baud: u32,
+    pub clock_hz: u32,
+}
+
+impl fmt::Display for UnsupportedBaud {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} baud is out of range for a {} Hz UART clock",
+            self.baud, self.clock_hz
+        )
+    }
+}
+
+/// Serial backend selected at build time. PL011 (UART0) is the default; the `mini-uart`
+/// feature switches to the AUX mini UART for boards where Bluetooth owns the PL011.
+#[cfg(not(feature = "mini-uart"))]
+pub type Serial = Pl011<Mmio>;
+#[cfg(feature = "mini-uart")]
+pub type Serial = MiniUart<Mmio>;
+
+/// Mailbox clock the build-time selected UART's baud rate derives from, and the rate the
+/// firmware sets it to by default
+#[cfg(not(feature = "mini-uart"))]
+const UART_CLOCK: (u32, u32) = (CLOCK_UART, DEFAULT_UART_CLOCK_HZ);
+#[cfg(feature = "mini-uart")]
+const UART_CLOCK: (u32, u32) = (CLOCK_CORE, DEFAULT_CORE_CLOCK_HZ);
+
+/// Brings up the build-time selected UART on GPIO14/15, deriving its baud divisor from the
+/// clock the mailbox reports. A `baud` the clock can't produce falls back to the default.
+pub fn init_serial<M: MailboxInterface>(mailbox: &M, platform: &Platform, baud: u32) -> Serial {
+    let (clock_id, default_hz) = UART_CLOCK;
+    let clock_hz = mailbox::get_clock_rate(mailbox, clock_id).unwrap_or(default_hz);
+    init_uart(platform, clock_hz, baud)
+}
+
+/// Like `init_serial`, but assumes the firmware's default clock rather than asking the
+/// mailbox, which takes a lock. Takes no locks, so it works with the MMU off.
+pub fn init_serial_without_mailbox(platform: &Platform, baud: u32) -> Serial {
+    init_uart(platform, UART_CLOCK.1, baud)
+}
+
+#[cfg(not(feature = "mini-uart"))]
+fn init_uart(platform: &Platform, clock_hz: u32, baud: u32) -> Serial {
+    let gpio = Gpio::new(Mmio::new(platform.gpio_base));
+    let mut serial = Pl011::new(Mmio::new(platform.pl011_base));
+    if serial.init(&gpio, clock_hz, baud).is_err() {
+        let _ = serial.init(&gpio, clock_hz, crate::config::Config::DEFAULT.serial_baud);
+    }
+    serial
+}
+
+#[cfg(feature = "mini-uart")]
+fn init_uart(platform: &Platform, clock_hz: u32, baud: u32) -> Serial {
+    let gpio = Gpio::new(Mmio::new(platform.gpio_base));
+    let mut serial = MiniUart::new(Mmio::new(platform.aux_base));
+    if serial.init(&gpio, clock_hz, baud).is_err() {
+        let _ = serial.init(&gpio, clock_hz, crate::config::Config::DEFAULT.serial_baud);
+    }
+    serial
+}
+
+static CONSOLE: Once<SpinLock<Serial>> = Once::new();
+
+/// Polls for the console lock before a crash report takes it anyway
+const CRASH_LOCK_POLLS: u32 = 1_000_000;
+
+/// Handle to the serial console every core shares. Each call takes the console's lock,
+/// so a `write!` from one core comes out whole.
+pub struct Console(&'static SpinLock<Serial>);
+
+/// Brings up the serial console, or returns it if it is already up.
+pub fn init_console<M: MailboxInterface>(mailbox: &M, platform: &Platform, baud: u32) -> Console {
+    Console(CONSOLE.call_once(|| SpinLock::new(init_serial(mailbox, platform, baud))))
+}
+
+/// The serial console for reporting a crash. Whoever holds it gets a moment to finish,
+/// after which it is taken from them, as they may be the code that crashed.
+pub fn crash_console<M: MailboxInterface>(mailbox: &M, platform: &Platform, baud: u32) -> Console {
+    let console = init_console(mailbox, platform, baud);
+    for _ in 0..CRASH_LOCK_POLLS {
+        if !console.0.is_locked() {
+            return console;
+        }
+        core::hint::spin_loop();
+    }
+    // SAFETY: The holder is stuck or crashed, and the worst it can do if it carries on is
+    // interleave its output with the report
+    unsafe { console.0.force_unlock() };
+    console
+}
+
+impl SerialInterface for Console {
+    fn write_byte(&mut self, byte: u8) {
+        self.0.lock().write_byte(byte);
+    }
+
+    // Polls rather than blocking with the lock held, which would hold up other cores'
+    // output until a byte arrives
+    fn read_byte(&mut self) -> u8 {
+        loop {
+            if let Some(byte) = self.try_read_byte() {
+                return byte;
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    fn try_read_byte(&mut self) -> Option<u8> {
+        self.0.lock().try_read_byte()
+    }
+
+    fn flush(&mut self) {
+        self.0.lock().flush();
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        self.0.lock().write_bytes(bytes);
+    }
+}
+
+impl fmt::Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0.lock().write_str(s)
+    }
+
+    fn write_fmt(&mut self, args: fmt::Arguments<'_>) -> fmt::Result {
+        self.0.lock().write_fmt(args)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod mock {
+    use super::SerialInterface;
+
+    use std::collections::VecDeque;
+    use std::vec::Vec;
+
+    /// Serial port fed from a scripted receive queue that records everything transmitted.
+    #[derive(Default)]
+    pub struct MockSerial {
+        pub rx: VecDeque<u8>,
+        pub tx: Vec<u8>,
+    }
+
+    impl MockSerial {
+        pub fn with_input(input: &[u8]) -> Self {
+            Self {
+                rx: input.iter().copied().collect(),
+                tx: Vec::new(),
+            }
+        }
+    }
+
+    impl SerialInterface for MockSerial {
+        fn write_byte(&mut self, byte: u8) {
+            self.tx.push(byte);
+        }
+
+        fn read_byte(&mut self) -> u8 {
+            self.rx
+                .pop_front()
+                .expect("Read past the end of the scripted input")
+        }
+
+        fn try_read_byte(&mut self) -> Option<u8> {
+            self.rx.pop_front()
+        }
+
+        fn flush(&mut self) {}
+    }
+}
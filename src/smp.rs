@@ -0,0 +1,485 @@
+//! Secondary core bring-up, work dispatch and inter-core messages.
+//!
+//! The firmware's armstub holds cores 1-3 in a loop polling a spin table, one 64-bit
+//! entry point per core. `start_secondary_cores` writes `_start_secondary` there, and each
+//! core sets up its stack in boot.s and enters `secondary_main`, where it runs jobs queued
+//! with `run_on` until it is parked again. Parked cores poll their spin table entry just
+//! like the armstub, from a copy of the loop next to it, so a kernel booted afterwards can
+//! start them the usual way and load itself anywhere, even over the bootloader.
+
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use core::time::Duration;
+
+#[cfg(target_arch = "aarch64")]
+use core::arch::asm;
+
+use crate::cache;
+use crate::clock::Instant;
+use crate::exception;
+use crate::fdt::Fdt;
+use crate::gic::Gic;
+use crate::mmu;
+use crate::platform::Platform;
+
+pub const MAX_CORES: usize = 4;
+pub const BOOT_CORE: usize = 0;
+
+/// The armstub's spin table, entry n is at `SPIN_TABLE_BASE + 8 * n`
+const SPIN_TABLE_BASE: usize = 0xD8;
+/// Where parked cores poll from: in the first page with the armstub and its spin table,
+/// which the firmware reserves, past the end of both
+#[cfg(target_arch = "aarch64")]
+const PARK_ADDR: usize = 0xF00;
+const START_TIMEOUT: Duration = Duration::from_millis(100);
+
+const JOB_QUEUE_LEN: usize = 8;
+const INBOX_LEN: usize = 16;
+
+/// Bounded lock-free queue that any core can push to and pop from (Dmitry Vyukov's
+/// MPMC design). Each slot's sequence number says whose turn it is, so producers and
+/// consumers only contend on the head and tail counters.
+pub struct MessageQueue<T: Copy, const N: usize> {
+    slots: [Slot<T>; N],
+    head: AtomicUsize,
+    tail: AtomicUsize,
+}
+
+struct Slot<T> {
+    /// Sequence number less the slot's index, so every slot starts at 0
+    stamp: AtomicUsize,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+// SAFETY: A slot's value is only accessed by the one producer or consumer that claimed it
+// through the sequence numbers
+unsafe impl<T: Copy + Send, const N: usize> Sync for MessageQueue<T, N> {}
+
+impl<T: Copy, const N: usize> Default for MessageQueue<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Copy, const N: usize> MessageQueue<T, N> {
+    pub const fn new() -> Self {
+        MessageQueue {
+            slots: [const {
+                Slot {
+                    stamp: AtomicUsize::new(0),
+                    value: UnsafeCell::new(MaybeUninit::uninit()),
+                }
+            }; N],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    fn sequence(&self, index: usize) -> usize {
+        self.slots[index]
+            .stamp
+            .load(Ordering::Acquire)
+            .wrapping_add(index)
+    }
+
+    fn set_sequence(&self, index: usize, sequence: usize) {
+        self.slots[index]
+            .stamp
+            .store(sequence.wrapping_sub(index), Ordering::Release);
+    }
+
+    /// Queues `value`, or hands it back if the queue is full.
+    pub fn push(&self, value: T) -> Result<(), T> {
+        let mut position = self.tail.load(Ordering::Relaxed);
+        loop {
+            let index = position % N;
+            let lag = self.sequence(index).wrapping_sub(position) as isize;
+            if lag == 0 {
+                match self.tail.compare_exchange_weak(
+                    position,
+                    position.wrapping_add(1),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        // SAFETY: Winning the exchange gives this producer the slot
+                        unsafe { (*self.slots[index].value.get()).write(value) };
+                        self.set_sequence(index, position.wrapping_add(1));
+                        return Ok(());
+                    }
+                    Err(current) => position = current,
+                }
+            } else if lag < 0 {
+                // The slot still holds a value from a lap ago
+                return Err(value);
+            } else {
+                position = self.tail.load(Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub fn pop(&self) -> Option<T> {
+        let mut position = self.head.load(Ordering::Relaxed);
+        loop {
+            let index = position % N;
+            let lag = self.sequence(index).wrapping_sub(position.wrapping_add(1)) as isize;
+            if lag == 0 {
+                match self.head.compare_exchange_weak(
+                    position,
+                    position.wrapping_add(1),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        // SAFETY: The producer published this slot before bumping its sequence
+                        let value = unsafe { (*self.slots[index].value.get()).assume_init() };
+                        self.set_sequence(index, position.wrapping_add(N));
+                        return Some(value);
+                    }
+                    Err(current) => position = current,
+                }
+            } else if lag < 0 {
+                return None;
+            } else {
+                position = self.head.load(Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// A value sent from one core to another.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Message {
+    pub from: usize,
+    pub value: u64,
+}
+
+#[derive(Clone, Copy)]
+struct Job {
+    work: fn(usize),
+    arg: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SmpError {
+    NoSuchCore(usize),
+    NotOnline(usize),
+    QueueFull(usize),
+}
+
+impl fmt::Display for SmpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SmpError::NoSuchCore(core) => write!(f, "there is no core {core}"),
+            SmpError::NotOnline(core) => write!(f, "core {core} is not running"),
+            SmpError::QueueFull(core) => write!(f, "core {core} has too much queued"),
+        }
+    }
+}
+
+const OFFLINE: u32 = 0;
+const ONLINE: u32 = 1;
+const PARKED: u32 = 2;
+
+struct Core {
+    state: AtomicU32,
+    release_addr: AtomicUsize,
+    jobs: MessageQueue<Job, JOB_QUEUE_LEN>,
+    inbox: MessageQueue<Message, INBOX_LEN>,
+}
+
+static CORES: [Core; MAX_CORES] = [const {
+    Core {
+        state: AtomicU32::new(OFFLINE),
+        release_addr: AtomicUsize::new(0),
+        jobs: MessageQueue::new(),
+        inbox: MessageQueue::new(),
+    }
+}; MAX_CORES];
+static PARK: AtomicBool = AtomicBool::new(false);
+
+/// The core this runs on.
+pub fn current_core() -> usize {
+    #[cfg(target_arch = "aarch64")]
+    {
+        let mpidr: u64;
+        // SAFETY: Reading MPIDR_EL1 has no side effects
+        unsafe { asm!("mrs {}, MPIDR_EL1", out(reg) mpidr) };
+        (mpidr & 0b11) as usize
+    }
+
+    #[cfg(not(target_arch = "aarch64"))]
+    BOOT_CORE
+}
+
+/// The spin table entry `core` polls, from its `cpu-release-addr` in the device tree.
+pub fn release_address(fdt: Option<&Fdt>, core: usize) -> usize {
+    fdt.and_then(|fdt| {
+        fdt.find_node("/cpus")?
+            .children()
+            .find(|cpu| cpu.reg().next().map(|(id, _)| id) == Some(core as u64))?
+            .property("cpu-release-addr")?
+            .as_u64()
+    })
+    .map_or(SPIN_TABLE_BASE + 8 * core, |addr| addr as usize)
+}
+
+fn send_event() {
+    #[cfg(target_arch = "aarch64")]
+    // SAFETY: Only wakes cores waiting in `wfe`
+    unsafe {
+        asm!("dsb sy", "sev")
+    };
+}
+
+fn wait_for_event() {
+    #[cfg(target_arch = "aarch64")]
+    // SAFETY: Only pauses the core until an event or interrupt
+    unsafe {
+        asm!("wfe")
+    };
+}
+
+/// Releases cores 1-3 from the firmware's spin table into `secondary_main`, returning how
+/// many came up.
+pub fn start_secondary_cores(fdt: Option<&Fdt>) -> usize {
+    CORES[BOOT_CORE].state.store(ONLINE, Ordering::Release);
+    PARK.store(false, Ordering::Release);
+
+    let mut started = 0;
+    for (core, secondary) in CORES.iter().enumerate().skip(1) {
+        let release_addr = release_address(fdt, core);
+        secondary
+            .release_addr
+            .store(release_addr, Ordering::Relaxed);
+        #[cfg(target_arch = "aarch64")]
+        unsafe {
+            unsafe extern "C" {
+                fn _start_secondary();
+            }
+            let entry = _start_secondary as *const () as u64;
+            // SAFETY: The spin table sits in reserved memory below the boot core's stack
+            core::ptr::write_volatile(release_addr as *mut u64, entry);
+        }
+        // The core polls with its caches off
+        cache::clean_dcache_range(release_addr, 8);
+        send_event();
+
+        let deadline = Instant::now() + START_TIMEOUT;
+        while !is_online(core) && Instant::now() < deadline {
+            core::hint::spin_loop();
+        }
+        if is_online(core) {
+            started += 1;
+        }
+        // Parking polls the entry again, so it must not point back here
+        #[cfg(target_arch = "aarch64")]
+        unsafe {
+            core::ptr::write_volatile(release_addr as *mut u64, 0)
+        };
+        cache::clean_dcache_range(release_addr, 8);
+    }
+    started
+}
+
+/// Entered on cores 1-3 from boot.s. Runs queued jobs until the core is parked.
+pub fn secondary_main(core: usize) -> ! {
+    // Shared state is only coherent between cores once their caches are on
+    mmu::enable_secondary();
+    exception::install();
+    Gic::from_platform(&Platform::current()).init_cpu();
+    let this = &CORES[core];
+    this.state.store(ONLINE, Ordering::Release);
+
+    loop {
+        if let Some(job) = this.jobs.pop() {
+            (job.work)(job.arg);
+        } else if PARK.load(Ordering::Acquire) {
+            this.state.store(PARKED, Ordering::Release);
+            park(this.release_addr.load(Ordering::Relaxed));
+        } else {
+            wait_for_event();
+        }
+    }
+}
+
+fn park(release_addr: usize) -> ! {
+    #[cfg(target_arch = "aarch64")]
+    unsafe {
+        asm!("msr daifset, #0xf");
+        // The spin table protocol starts kernels on cores with the MMU off
+        mmu::disable();
+        // SAFETY: `park_secondary_cores` copied the loop there before asking to park
+        let park: extern "C" fn(usize) -> ! = core::mem::transmute(PARK_ADDR);
+        park(release_addr)
+    }
+
+    #[cfg(not(target_arch = "aarch64"))]
+    panic!("Cannot park core polling {release_addr:#x} on this architecture");
+}
+
+/// Sends the secondary cores back to polling the spin table, for a kernel to start them.
+/// Waits for jobs that are already queued to finish.
+pub fn park_secondary_cores() {
+    install_park_loop();
+    PARK.store(true, Ordering::Release);
+    send_event();
+    for secondary in &CORES[1..] {
+        let deadline = Instant::now() + START_TIMEOUT;
+        while secondary.state.load(Ordering::Acquire) == ONLINE && Instant::now() < deadline {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// Copies the park loop from boot.s to `PARK_ADDR`, out of the way of whatever gets loaded
+/// over the bootloader. No core runs it yet, the ones we started are busy in `secondary_main`.
+fn install_park_loop() {
+    #[cfg(target_arch = "aarch64")]
+    unsafe {
+        unsafe extern "C" {
+            static __secondary_park_start: u8;
+            static __secondary_park_end: u8;
+        }
+        let start = &raw const __secondary_park_start;
+        let len = (&raw const __secondary_park_end).offset_from(start) as usize;
+        // SAFETY: The firmware reserves the first page, and the loop fits below its end
+        core::ptr::copy_nonoverlapping(start, PARK_ADDR as *mut u8, len);
+        // Parked cores fetch it with their caches off
+        cache::clean_dcache_range(PARK_ADDR, len);
+    }
+}
+
+pub fn is_online(core: usize) -> bool {
+    CORES
+        .get(core)
+        .is_some_and(|core| core.state.load(Ordering::Acquire) == ONLINE)
+}
+
+/// Runs `work(arg)` on `core`, straight away if that's the calling core.
+pub fn run_on(core: usize, work: fn(usize), arg: usize) -> Result<(), SmpError> {
+    if core == current_core() {
+        work(arg);
+        return Ok(());
+    }
+    let target = CORES.get(core).ok_or(SmpError::NoSuchCore(core))?;
+    if !is_online(core) {
+        return Err(SmpError::NotOnline(core));
+    }
+    target
+        .jobs
+        .push(Job { work, arg })
+        .map_err(|_| SmpError::QueueFull(core))?;
+    send_event();
+    Ok(())
+}
+
+/// Sends `value` to `core`'s inbox.
+pub fn send(core: usize, value: u64) -> Result<(), SmpError> {
+    let target = CORES.get(core).ok_or(SmpError::NoSuchCore(core))?;
+    target
+        .inbox
+        .push(Message {
+            from: current_core(),
+            value,
+        })
+        .map_err(|_| SmpError::QueueFull(core))?;
+    send_event();
+    Ok(())
+}
+
+/// The oldest message sent to this core, if any.
+pub fn receive() -> Option<Message> {
+    CORES[current_core()].inbox.pop()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+    use std::thread;
+    use std::vec::Vec;
+
+    #[test]
+    fn test_queue_is_fifo_and_bounded() {
+        let queue = MessageQueue::<u32, 4>::new();
+        assert_eq!(queue.pop(), None);
+        // Several laps round the slots
+        for round in 0..3 {
+            for value in 0..4 {
+                queue.push(round * 10 + value).unwrap();
+            }
+            assert_eq!(queue.push(99), Err(99));
+            for value in 0..4 {
+                assert_eq!(queue.pop(), Some(round * 10 + value));
+            }
+            assert_eq!(queue.pop(), None);
+        }
+    }
+
+    #[test]
+    fn test_queue_delivers_everything_across_threads() {
+        const PRODUCERS: u64 = 4;
+        const PER_PRODUCER: u64 = 2_000;
+        let queue = Arc::new(MessageQueue::<Message, 8>::new());
+
+        let producers: Vec<_> = (0..PRODUCERS)
+            .map(|from| {
+                let queue = Arc::clone(&queue);
+                thread::spawn(move || {
+                    for value in 0..PER_PRODUCER {
+                        let mut message = Message {
+                            from: from as usize,
+                            value,
+                        };
+                        while let Err(rejected) = queue.push(message) {
+                            message = rejected;
+                            thread::yield_now();
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        // Each producer's messages must arrive complete and in order
+        let mut next = [0; PRODUCERS as usize];
+        let mut received = 0;
+        while received < PRODUCERS * PER_PRODUCER {
+            match queue.pop() {
+                Some(message) => {
+                    assert_eq!(message.value, next[message.from]);
+                    next[message.from] += 1;
+                    received += 1;
+                }
+                None => thread::yield_now(),
+            }
+        }
+        for producer in producers {
+            producer.join().unwrap();
+        }
+        assert_eq!(queue.pop(), None);
+    }
+
+    #[test]
+    fn test_reads_release_addresses_from_the_device_tree() {
+        let fdt = Fdt::new(include_bytes!("../tests/fixtures/dtb/rpi4b.dtb")).unwrap();
+        let addresses: Vec<usize> = (1..MAX_CORES)
+            .map(|core| release_address(Some(&fdt), core))
+            .collect();
+        assert_eq!(addresses, [0xE0, 0xE8, 0xF0]);
+        assert_eq!(release_address(None, 3), 0xF0);
+    }
+
+    #[test]
+    fn test_reads_release_addresses_from_the_firmware_blob() {
+        let blob = crate::fdt::firmware_dtb();
+        let fdt = Fdt::new(&blob).unwrap();
+        let addresses: Vec<usize> = (1..MAX_CORES)
+            .map(|core| release_address(Some(&fdt), core))
+            .collect();
+        assert_eq!(addresses, [0xE0, 0xE8, 0xF0]);
+    }
+}
@@ -0,0 +1,334 @@
+//! Locks and one-time initialization for state shared between cores and interrupt handlers.
+//!
+//! `SpinLock` is a ticket lock, so cores get the lock in the order they asked for it.
+//! `IrqSafeLock` also masks IRQs on the holding core, for data an interrupt handler
+//! touches: otherwise a handler spinning on a lock its own core holds never returns.
+
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::mem::MaybeUninit;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicU8, AtomicU32, Ordering};
+
+use crate::exception::{self, IrqState};
+
+pub struct SpinLock<T> {
+    next_ticket: AtomicU32,
+    now_serving: AtomicU32,
+    value: UnsafeCell<T>,
+}
+
+// SAFETY: The ticket counters give one holder at a time access to the value
+unsafe impl<T: Send> Sync for SpinLock<T> {}
+unsafe impl<T: Send> Send for SpinLock<T> {}
+
+impl<T> SpinLock<T> {
+    pub const fn new(value: T) -> Self {
+        SpinLock {
+            next_ticket: AtomicU32::new(0),
+            now_serving: AtomicU32::new(0),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn lock(&self) -> SpinLockGuard<'_, T> {
+        let ticket = self.next_ticket.fetch_add(1, Ordering::Relaxed);
+        while self.now_serving.load(Ordering::Acquire) != ticket {
+            core::hint::spin_loop();
+        }
+        SpinLockGuard { lock: self }
+    }
+
+    /// Takes the lock only if nobody holds or is waiting for it.
+    pub fn try_lock(&self) -> Option<SpinLockGuard<'_, T>> {
+        let serving = self.now_serving.load(Ordering::Relaxed);
+        self.next_ticket
+            .compare_exchange(
+                serving,
+                serving.wrapping_add(1),
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            )
+            .ok()
+            .map(|_| SpinLockGuard { lock: self })
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.next_ticket.load(Ordering::Relaxed) != self.now_serving.load(Ordering::Relaxed)
+    }
+
+    /// Releases the lock on behalf of whoever holds it.
+    ///
+    /// # Safety
+    ///
+    /// The holder must never touch the value again, e.g. because it crashed.
+    pub unsafe fn force_unlock(&self) {
+        if self.is_locked() {
+            self.now_serving.fetch_add(1, Ordering::Release);
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+}
+
+impl<T: Default> Default for SpinLock<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T> fmt::Debug for SpinLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SpinLock")
+            .field("locked", &self.is_locked())
+            .finish_non_exhaustive()
+    }
+}
+
+#[must_use]
+pub struct SpinLockGuard<'a, T> {
+    lock: &'a SpinLock<T>,
+}
+
+impl<T> Deref for SpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: The guard holds the lock
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> DerefMut for SpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: The guard holds the lock
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for SpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.now_serving.fetch_add(1, Ordering::Release);
+    }
+}
+
+/// A `SpinLock` that masks IRQs on the holding core until it is released.
+#[derive(Debug, Default)]
+pub struct IrqSafeLock<T> {
+    inner: SpinLock<T>,
+}
+
+impl<T> IrqSafeLock<T> {
+    pub const fn new(value: T) -> Self {
+        IrqSafeLock {
+            inner: SpinLock::new(value),
+        }
+    }
+
+    pub fn lock(&self) -> IrqSafeLockGuard<'_, T> {
+        let irqs = MaskedIrqs(exception::mask_irqs());
+        IrqSafeLockGuard {
+            guard: self.inner.lock(),
+            _irqs: irqs,
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<IrqSafeLockGuard<'_, T>> {
+        let irqs = MaskedIrqs(exception::mask_irqs());
+        Some(IrqSafeLockGuard {
+            guard: self.inner.try_lock()?,
+            _irqs: irqs,
+        })
+    }
+}
+
+/// Restores the interrupt mask when dropped.
+struct MaskedIrqs(IrqState);
+
+impl Drop for MaskedIrqs {
+    fn drop(&mut self) {
+        exception::restore_irqs(self.0);
+    }
+}
+
+#[must_use]
+pub struct IrqSafeLockGuard<'a, T> {
+    // Fields drop in order, so the lock is released before IRQs are unmasked
+    guard: SpinLockGuard<'a, T>,
+    _irqs: MaskedIrqs,
+}
+
+impl<T> Deref for IrqSafeLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for IrqSafeLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+const INCOMPLETE: u8 = 0;
+const RUNNING: u8 = 1;
+const COMPLETE: u8 = 2;
+
+/// A value that is set up once, by whichever core gets there first.
+pub struct Once<T> {
+    state: AtomicU8,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+// SAFETY: The value is written once, before COMPLETE is published, and only read after
+unsafe impl<T: Send + Sync> Sync for Once<T> {}
+unsafe impl<T: Send> Send for Once<T> {}
+
+impl<T> Default for Once<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Once<T> {
+    pub const fn new() -> Self {
+        Once {
+            state: AtomicU8::new(INCOMPLETE),
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    /// The value, set up by `init` if this is the first call. Callers racing the first
+    /// one wait for it to finish.
+    pub fn call_once(&self, init: impl FnOnce() -> T) -> &T {
+        if self
+            .state
+            .compare_exchange(INCOMPLETE, RUNNING, Ordering::Acquire, Ordering::Acquire)
+            .is_ok()
+        {
+            // SAFETY: Winning the exchange makes this the only writer
+            unsafe { (*self.value.get()).write(init()) };
+            self.state.store(COMPLETE, Ordering::Release);
+        }
+        while self.state.load(Ordering::Acquire) != COMPLETE {
+            core::hint::spin_loop();
+        }
+        // SAFETY: COMPLETE is only stored once the value is written
+        unsafe { (*self.value.get()).assume_init_ref() }
+    }
+
+    pub fn get(&self) -> Option<&T> {
+        (self.state.load(Ordering::Acquire) == COMPLETE)
+            // SAFETY: As in `call_once`
+            .then(|| unsafe { (*self.value.get()).assume_init_ref() })
+    }
+
+    pub fn is_completed(&self) -> bool {
+        self.state.load(Ordering::Acquire) == COMPLETE
+    }
+}
+
+impl<T> Drop for Once<T> {
+    fn drop(&mut self) {
+        if *self.state.get_mut() == COMPLETE {
+            // SAFETY: The value was written and nothing can borrow it any more
+            unsafe { self.value.get_mut().assume_init_drop() };
+        }
+    }
+}
+
+/// A value computed by `init` on first use, for statics that can't be built in a const.
+pub struct Lazy<T, F = fn() -> T> {
+    once: Once<T>,
+    init: F,
+}
+
+impl<T, F: Fn() -> T> Lazy<T, F> {
+    pub const fn new(init: F) -> Self {
+        Lazy {
+            once: Once::new(),
+            init,
+        }
+    }
+
+    pub fn force(this: &Self) -> &T {
+        this.once.call_once(&this.init)
+    }
+}
+
+impl<T, F: Fn() -> T> Deref for Lazy<T, F> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        Lazy::force(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicUsize;
+    use std::thread;
+    use std::vec::Vec;
+
+    #[test]
+    fn test_spin_lock_excludes_other_threads() {
+        let counter = Arc::new(SpinLock::new(0u64));
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let counter = Arc::clone(&counter);
+                thread::spawn(move || {
+                    // A plain read-modify-write, so any overlap would lose updates
+                    for _ in 0..10_000 {
+                        *counter.lock() += 1;
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(*counter.lock(), 40_000);
+    }
+
+    #[test]
+    fn test_try_lock_and_force_unlock() {
+        let lock = IrqSafeLock::new(1);
+        let guard = lock.lock();
+        assert!(lock.try_lock().is_none());
+        drop(guard);
+        *lock.try_lock().unwrap() = 2;
+
+        let spin = SpinLock::new(());
+        core::mem::forget(spin.lock());
+        assert!(spin.is_locked());
+        unsafe { spin.force_unlock() };
+        assert!(spin.try_lock().is_some());
+        assert_eq!(*lock.lock(), 2);
+    }
+
+    #[test]
+    fn test_once_runs_init_once() {
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+        static VALUE: Lazy<usize> = Lazy::new(|| CALLS.fetch_add(1, Ordering::Relaxed) + 42);
+
+        let once = Arc::new(Once::new());
+        assert_eq!(once.get(), None);
+        let threads: Vec<_> = (0..4)
+            .map(|n| {
+                let once = Arc::clone(&once);
+                thread::spawn(move || *once.call_once(|| n) + *VALUE)
+            })
+            .collect();
+        let results: Vec<_> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+        let first = *once.get().unwrap();
+        assert!(results.iter().all(|&result| result == first + 42));
+        assert_eq!(CALLS.load(Ordering::Relaxed), 1);
+    }
+}
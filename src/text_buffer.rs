@@ -38,6 +38,16 @@ impl<'a, const ROWS: usize, const COLS: usize, M: MailboxInterface> TextBuffer<'
         }
     }
 
+    /// Overrides the font color, which defaults to the inverse of the background.
+    pub fn set_font_color(&mut self, color: u32) {
+        self.font_color = color;
+    }
+
+    /// The framebuffer underneath, for drawing outside the text area.
+    pub fn frame_buffer(&self) -> &FrameBuffer<'a, M> {
+        self.fb
+    }
+
     fn draw_char_at(&mut self, row: usize, col: usize, ch: char) {
         let x = col * self.glyph_size + self.offset_x;
         let y = row * self.glyph_size + self.offset_y;
@@ -0,0 +1,302 @@
+use crate::{
+    aa_font::{self, AaFont},
+    frame_buffer::FrameBuffer,
+    mailbox::MailboxInterface,
+};
+
+/// A font at a fixed size that text can be measured and drawn with.
+pub trait Font {
+    /// Pen advance of `ch` in 1/64 pixels
+    fn advance(&self, ch: char) -> u32;
+
+    /// Distance between consecutive baselines in pixels
+    fn line_height(&self) -> usize;
+
+    fn draw_glyph<M: MailboxInterface>(
+        &self,
+        fb: &FrameBuffer<'_, M>,
+        x: usize,
+        y: usize,
+        ch: char,
+        color: u32,
+    );
+}
+
+/// Proportional anti-aliased font at a fractional pixel size.
+pub struct ScaledFont<'f> {
+    pub font: &'f AaFont,
+    pub size: f32,
+}
+
+impl Font for ScaledFont<'_> {
+    fn advance(&self, ch: char) -> u32 {
+        let atlas = self.font.atlas_for(self.size);
+        match u8::try_from(ch).ok().and_then(|ch| atlas.glyph(ch)) {
+            Some(glyph) => {
+                (glyph.advance as u64 * aa_font::scale_for(atlas, self.size) as u64 / 256) as u32
+            }
+            None => 0,
+        }
+    }
+
+    fn line_height(&self) -> usize {
+        let atlas = self.font.atlas_for(self.size);
+        (atlas.line_height as usize * aa_font::scale_for(atlas, self.size) as usize + 128) / 256
+    }
+
+    fn draw_glyph<M: MailboxInterface>(
+        &self,
+        fb: &FrameBuffer<'_, M>,
+        x: usize,
+        y: usize,
+        ch: char,
+        color: u32,
+    ) {
+        if let Ok(ch) = u8::try_from(ch) {
+            fb.draw_aa_glyph(x, y, ch, color, self.size, self.font);
+        }
+    }
+}
+
+/// Monospace 8 pixel wide bitmap font such as `FONT8X8_BASIC`, scaled by an integer factor.
+pub struct MonoFont<'f, const GLYPH_HEIGHT: usize> {
+    pub glyphs: &'f [[u8; GLYPH_HEIGHT]; 128],
+    pub scale: usize,
+}
+
+impl<const GLYPH_HEIGHT: usize> Font for MonoFont<'_, GLYPH_HEIGHT> {
+    // Characters outside the font still take up a cell, drawn blank
+    fn advance(&self, _ch: char) -> u32 {
+        (8 * self.scale * 64) as u32
+    }
+
+    fn line_height(&self) -> usize {
+        GLYPH_HEIGHT * self.scale
+    }
+
+    fn draw_glyph<M: MailboxInterface>(
+        &self,
+        fb: &FrameBuffer<'_, M>,
+        x: usize,
+        y: usize,
+        ch: char,
+        color: u32,
+    ) {
+        if let Ok(ch) = u8::try_from(ch) {
+            fb.draw_glyph(x, y, ch, color, self.scale, self.glyphs);
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Align {
+    Left,
+    Center,
+    Right,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+/// Width of a single line of text in pixels.
+pub fn line_width<F: Font>(font: &F, line: &str) -> usize {
+    let advance: u32 = line.chars().map(|ch| font.advance(ch)).sum();
+    ((advance + 32) >> 6) as usize
+}
+
+/// Size of `text` in pixels as (width, height), honouring explicit line breaks.
+pub fn measure<F: Font>(font: &F, text: &str) -> (usize, usize) {
+    let mut width = 0;
+    let mut lines = 0;
+    for line in text.split('\n') {
+        width = width.max(line_width(font, line));
+        lines += 1;
+    }
+    (width, lines * font.line_height())
+}
+
+/// Splits `text` into lines no wider than `max_width` pixels, breaking at spaces where
+/// possible and inside words only when a single word doesn't fit. Spaces before a line's
+/// first word are never a break, so wrapping doesn't produce empty lines.
+pub fn wrap<'t, F: Font>(font: &'t F, text: &'t str, max_width: usize) -> WrappedLines<'t, F> {
+    WrappedLines {
+        font,
+        rest: text,
+        max_width,
+        done: false,
+    }
+}
+
+pub struct WrappedLines<'t, F: Font> {
+    font: &'t F,
+    rest: &'t str,
+    max_width: usize,
+    done: bool,
+}
+
+impl<'t, F: Font> Iterator for WrappedLines<'t, F> {
+    type Item = &'t str;
+
+    fn next(&mut self) -> Option<&'t str> {
+        if self.done {
+            return None;
+        }
+
+        let bytes = self.rest.as_bytes();
+        let max_advance = (self.max_width as u32) << 6;
+        let mut advance = 0;
+        let mut last_space = None;
+        let mut seen_word = false;
+        let mut end = bytes.len();
+
+        for (i, ch) in self.rest.char_indices() {
+            if ch == '\n' {
+                end = i;
+                break;
+            }
+            if ch == ' ' {
+                if seen_word {
+                    last_space = Some(i);
+                }
+            } else if advance + self.font.advance(ch) > max_advance {
+                end = match last_space {
+                    Some(space) => space,
+                    // Always emit at least one character so an overly narrow rect still advances
+                    None if !seen_word => i + ch.len_utf8(),
+                    None => i,
+                };
+                break;
+            } else {
+                seen_word = true;
+            }
+            advance += self.font.advance(ch);
+        }
+
+        let line = &self.rest[..end];
+        let mut next = end;
+        if next < bytes.len() && (bytes[next] == b'\n' || bytes[next] == b' ') {
+            next += 1;
+        }
+        if next >= bytes.len() && end == bytes.len() {
+            self.done = true;
+        }
+        self.rest = &self.rest[next..];
+
+        Some(line.trim_end_matches(' '))
+    }
+}
+
+/// Draws `text` word-wrapped inside `rect`, aligning every line horizontally. Lines that
+/// would overflow the bottom of `rect` are dropped. Returns the height used in pixels.
+pub fn draw_text<F: Font, M: MailboxInterface>(
+    fb: &FrameBuffer<'_, M>,
+    font: &F,
+    rect: Rect,
+    text: &str,
+    color: u32,
+    align: Align,
+) -> usize {
+    let line_height = font.line_height();
+    let mut y = rect.y;
+
+    for line in wrap(font, text, rect.width) {
+        if y + line_height > rect.y + rect.height {
+            break;
+        }
+
+        let mut pen = (rect.x + line_offset(line_width(font, line), rect.width, align)) << 6;
+        for ch in line.chars() {
+            font.draw_glyph(fb, (pen + 32) >> 6, y, ch, color);
+            pen += font.advance(ch) as usize;
+        }
+        y += line_height;
+    }
+
+    y - rect.y
+}
+
+/// Horizontal offset of a line `width` pixels wide inside a box `available` pixels wide.
+pub fn line_offset(width: usize, available: usize, align: Align) -> usize {
+    let slack = available.saturating_sub(width);
+    match align {
+        Align::Left => 0,
+        Align::Center => slack / 2,
+        Align::Right => slack,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::vec::Vec;
+
+    use crate::aa_font::DEJAVU_SANS;
+
+    /// Proportional stand-in where every glyph is as wide as its digit, or 10 pixels
+    struct FixtureFont;
+
+    impl Font for FixtureFont {
+        fn advance(&self, ch: char) -> u32 {
+            ch.to_digit(10).unwrap_or(10) << 6
+        }
+
+        fn line_height(&self) -> usize {
+            12
+        }
+
+        fn draw_glyph<M: MailboxInterface>(
+            &self,
+            _fb: &FrameBuffer<'_, M>,
+            _x: usize,
+            _y: usize,
+            _ch: char,
+            _color: u32,
+        ) {
+        }
+    }
+
+    #[test]
+    fn test_measure_uses_per_glyph_advance() {
+        assert_eq!(measure(&FixtureFont, "123"), (6, 12));
+        assert_eq!(measure(&FixtureFont, "ab\n9"), (20, 24));
+
+        let font = ScaledFont {
+            font: &DEJAVU_SANS,
+            size: 24.0,
+        };
+        assert!(line_width(&font, "iii") < line_width(&font, "WWW"));
+        // One glyph per character, however many bytes it takes, in measuring and wrapping alike
+        assert_eq!(line_width(&FixtureFont, "é1"), 11);
+        let lines: Vec<_> = wrap(&FixtureFont, "éé ab", 25).collect();
+        assert_eq!(lines, ["éé", "ab"]);
+    }
+
+    #[test]
+    fn test_wrap_breaks_at_spaces_and_long_words() {
+        let lines: Vec<_> = wrap(&FixtureFont, "ab cd ef", 50).collect();
+        assert_eq!(lines, ["ab cd", "ef"]);
+
+        let lines: Vec<_> = wrap(&FixtureFont, "abcdef\n\nxy", 30).collect();
+        assert_eq!(lines, ["abc", "def", "", "xy"]);
+
+        // Leading spaces are kept but aren't a break, which would leave an empty line
+        let lines: Vec<_> = wrap(&FixtureFont, " abc d", 25).collect();
+        assert_eq!(lines, [" a", "bc", "d"]);
+        let lines: Vec<_> = wrap(&FixtureFont, "  a", 5).collect();
+        assert_eq!(lines, ["  a"]);
+    }
+
+    #[test]
+    fn test_line_offset_aligns_within_rect() {
+        assert_eq!(line_offset(40, 100, Align::Left), 0);
+        assert_eq!(line_offset(40, 100, Align::Center), 30);
+        assert_eq!(line_offset(40, 100, Align::Right), 60);
+        assert_eq!(line_offset(140, 100, Align::Right), 0);
+    }
+}
@@ -1,65 +1,211 @@
+//! System counter timing: the polled `Timer`, and a tick interrupt from the EL1 physical
+//! timer that drives a `TimerWheel` of callbacks and lets `sleep_ms`/`delay_us` wait with
+//! `wfi`.
+
+#[cfg(target_arch = "aarch64")]
 use core::arch::asm;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+use crate::clock::{Clock, CounterSource, Instant, SystemCounter, duration_to_ticks};
+
+use crate::gic::{Gic, IRQ_HANDLERS, IrqError};
+use crate::mmio::MmioInterface;
+use crate::sync::IrqSafeLock;
+use crate::timer_wheel::{TimerCallback, TimerId, TimerWheel, WheelFull};
+
+/// PPI raised by the EL1 physical timer
+pub const TIMER_IRQ: u32 = 30;
+/// Rate of the tick interrupt, and so the resolution of scheduled callbacks
+pub const TICK_HZ: u64 = 1000;
+
+const WHEEL_SLOTS: usize = 256;
+const MAX_TIMERS: usize = 32;
+
+type Wheel = TimerWheel<WHEEL_SLOTS, MAX_TIMERS>;
+
+// IRQ-safe as the tick handler runs the wheel
+static WHEEL: IrqSafeLock<Wheel> = IrqSafeLock::new(Wheel::new());
+/// Counter ticks per wheel tick, 0 until `start_ticks` has run
+static TICK_INTERVAL: AtomicU64 = AtomicU64::new(0);
+/// Counter value at wheel tick 0
+static TICK_START: AtomicU64 = AtomicU64::new(0);
 
-pub struct Timer {
-    period: u64,
-    ticks_per_us: u64,
-    last_trigger: u64,
+fn with_wheel<T>(f: impl FnOnce(&mut Wheel) -> T) -> T {
+    f(&mut WHEEL.lock())
+}
+
+/// Polled periodic timer: `elapsed` returns true once per period.
+pub struct Timer<S: CounterSource = SystemCounter> {
+    clock: Clock<S>,
+    period: Duration,
+    last_trigger: Instant,
 }
 
 impl Timer {
     pub fn new(period_ms: u64) -> Self {
+        Self::with_source(SystemCounter, period_ms)
+    }
+}
+
+impl<S: CounterSource> Timer<S> {
+    pub fn with_source(source: S, period_ms: u64) -> Self {
+        let clock = Clock::new(source);
         Self {
-            period: period_ms * 1_000,
-            last_trigger: Self::read_cntpct_el0(),
-            ticks_per_us: Self::read_cntfrq_el0() / 1_000_000,
+            last_trigger: clock.now(),
+            clock,
+            period: Duration::from_millis(period_ms),
         }
     }
 
     pub fn elapsed(&mut self) -> bool {
-        let current_tick = Self::read_cntpct_el0();
-        if current_tick - self.last_trigger > self.ticks_per_us * self.period {
-            self.last_trigger = current_tick;
+        let now = self.clock.now();
+        if now - self.last_trigger > self.period {
+            self.last_trigger = now;
             true
         } else {
             false
         }
     }
+}
 
-    /// Reads the system counter frequency in Hz (ticks per second)
-    fn read_cntfrq_el0() -> u64 {
-        #[cfg(target_arch = "aarch64")]
-        {
-            let value: u64;
-            // SAFETY: Reading from cntfrq_el0 is side-effect free and always safe in EL0
-            unsafe {
-                asm!("mrs {}, cntfrq_el0", out(reg) value);
-            }
-            value
-        }
+/// Starts the 1 ms tick interrupt that runs scheduled callbacks and wakes sleepers.
+pub fn start_ticks<D: MmioInterface, C: MmioInterface>(gic: &Gic<D, C>) -> Result<(), IrqError> {
+    IRQ_HANDLERS.register(TIMER_IRQ, on_tick)?;
+    let interval = SystemCounter.frequency() / TICK_HZ;
+    let start = SystemCounter.ticks();
+    TICK_START.store(start, Ordering::Relaxed);
+    TICK_INTERVAL.store(interval, Ordering::Release);
+    set_timer_compare(start + interval);
+    set_timer_enabled(true);
+    gic.enable(TIMER_IRQ);
+    Ok(())
+}
 
-        #[cfg(not(target_arch = "aarch64"))]
-        {
-            // Provide dummy value for tests or non-ARM platforms
-            54_000_000
-        }
+/// Stops the tick interrupt, for handing the core to a kernel.
+pub fn stop_ticks() {
+    set_timer_enabled(false);
+    TICK_INTERVAL.store(0, Ordering::Release);
+}
+
+fn on_tick(_irq: u32) {
+    let interval = TICK_INTERVAL.load(Ordering::Acquire);
+    if interval == 0 {
+        return;
+    }
+    let start = TICK_START.load(Ordering::Relaxed);
+    let tick = (SystemCounter.ticks() - start) / interval;
+    // Counting from the start rather than the last interrupt keeps ticks from drifting,
+    // and moving the compare value forward clears the interrupt
+    set_timer_compare(start + (tick + 1) * interval);
+    while let Some(callback) = with_wheel(|wheel| wheel.pop_expired(tick)) {
+        callback();
     }
+}
 
-    /// Reads the current physical counter value (monotonic timer ticks)
-    fn read_cntpct_el0() -> u64 {
-        #[cfg(target_arch = "aarch64")]
-        {
-            let value: u64;
-            // SAFETY: Reading from cntpct_el0 is side-effect free and always safe in EL0
-            unsafe {
-                asm!("mrs {}, cntpct_el0", out(reg) value);
-            }
-            value
-        }
+fn ms_to_ticks(ms: u64) -> u64 {
+    ms.saturating_mul(TICK_HZ) / 1000
+}
+
+/// Runs `callback` from the tick interrupt once, after `ms` milliseconds.
+pub fn call_after(ms: u64, callback: TimerCallback) -> Result<TimerId, WheelFull> {
+    with_wheel(|wheel| wheel.schedule_once(ms_to_ticks(ms), callback))
+}
+
+/// Runs `callback` from the tick interrupt every `period_ms` milliseconds.
+pub fn call_every(period_ms: u64, callback: TimerCallback) -> Result<TimerId, WheelFull> {
+    with_wheel(|wheel| wheel.schedule_periodic(ms_to_ticks(period_ms), callback))
+}
 
-        #[cfg(not(target_arch = "aarch64"))]
-        {
-            // Provide dummy value for tests or non-ARM platforms
-            0
+/// Stops a callback scheduled with `call_after` or `call_every`.
+pub fn cancel(id: TimerId) -> bool {
+    with_wheel(|wheel| wheel.cancel(id))
+}
+
+/// Waits at least `duration`, sleeping in `wfi` while the tick interrupt is running.
+pub fn sleep(duration: Duration) {
+    wait_counter_ticks(duration_to_ticks(duration, SystemCounter.frequency()));
+}
+
+pub fn sleep_ms(ms: u64) {
+    sleep(Duration::from_millis(ms));
+}
+
+pub fn delay_us(us: u64) {
+    sleep(Duration::from_micros(us));
+}
+
+/// Sleeps in `wfi` while the tick interrupt will wake the core before the deadline, and
+/// spins for whatever is left, so short delays keep counter precision.
+fn wait_counter_ticks(ticks: u64) {
+    let deadline = SystemCounter.ticks().saturating_add(ticks);
+    loop {
+        let now = SystemCounter.ticks();
+        if now >= deadline {
+            return;
+        }
+        let interval = TICK_INTERVAL.load(Ordering::Acquire);
+        if interval != 0 && deadline - now > interval {
+            wait_for_interrupt();
+        } else {
+            core::hint::spin_loop();
         }
     }
 }
+
+fn wait_for_interrupt() {
+    #[cfg(target_arch = "aarch64")]
+    // SAFETY: Only pauses the core until an interrupt is pending
+    unsafe {
+        asm!("wfi")
+    };
+}
+
+/// Sets the counter value the physical timer fires at.
+fn set_timer_compare(value: u64) {
+    #[cfg(target_arch = "aarch64")]
+    // SAFETY: boot.s gives EL1 access to the physical timer, which only this module uses
+    unsafe {
+        asm!("msr CNTP_CVAL_EL0, {}", "isb", in(reg) value)
+    };
+
+    #[cfg(not(target_arch = "aarch64"))]
+    let _ = value;
+}
+
+fn set_timer_enabled(enabled: bool) {
+    #[cfg(target_arch = "aarch64")]
+    // SAFETY: As for `set_timer_compare`. IMASK stays clear so the timer interrupt reaches
+    // the GIC
+    unsafe {
+        asm!("msr CNTP_CTL_EL0, {}", "isb", in(reg) enabled as u64)
+    };
+
+    #[cfg(not(target_arch = "aarch64"))]
+    let _ = enabled;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::clock::mock::MockCounter;
+
+    #[test]
+    fn test_timer_fires_once_per_period() {
+        let counter = MockCounter::new(54_000_000);
+        let mut timer = Timer::with_source(&counter, 1000);
+        assert!(!timer.elapsed());
+
+        counter.advance(54_000_000);
+        assert!(!timer.elapsed());
+        counter.advance(1);
+        assert!(timer.elapsed());
+        assert!(!timer.elapsed());
+
+        // Periods are measured from when `elapsed` last saw one pass
+        counter.advance(2 * 54_000_000);
+        assert!(timer.elapsed());
+        assert!(!timer.elapsed());
+    }
+}
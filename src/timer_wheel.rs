@@ -0,0 +1,276 @@
+//! Hashed timer wheel for one-shot and periodic callbacks.
+//!
+//! Time is counted in ticks. A timer due at tick `t` sits in slot `t % SLOTS`, so
+//! advancing the wheel only looks at the slot of each tick that passes; timers due more
+//! than `SLOTS` ticks out stay in their slot until their round comes up. Timers live in a
+//! fixed table of `CAPACITY` entries and are linked into their slot through it.
+
+/// Called from the tick interrupt when a timer expires.
+pub type TimerCallback = fn();
+
+/// Handle for cancelling a scheduled timer. Stays invalid once the timer is reused.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimerId {
+    index: usize,
+    generation: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WheelFull;
+
+#[derive(Clone, Copy)]
+struct Entry {
+    callback: Option<TimerCallback>,
+    expires: u64,
+    /// Ticks between runs of a periodic timer, 0 for one-shot timers
+    period: u64,
+    generation: u32,
+    next: Option<usize>,
+}
+
+const EMPTY: Entry = Entry {
+    callback: None,
+    expires: 0,
+    period: 0,
+    generation: 0,
+    next: None,
+};
+
+pub struct TimerWheel<const SLOTS: usize, const CAPACITY: usize> {
+    slots: [Option<usize>; SLOTS],
+    entries: [Entry; CAPACITY],
+    /// The tick being expired, every earlier tick has been handled
+    current: u64,
+}
+
+impl<const SLOTS: usize, const CAPACITY: usize> Default for TimerWheel<SLOTS, CAPACITY> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const SLOTS: usize, const CAPACITY: usize> TimerWheel<SLOTS, CAPACITY> {
+    pub const fn new() -> Self {
+        TimerWheel {
+            slots: [None; SLOTS],
+            entries: [EMPTY; CAPACITY],
+            current: 0,
+        }
+    }
+
+    /// The tick the wheel has advanced to.
+    pub fn now(&self) -> u64 {
+        self.current
+    }
+
+    /// Runs `callback` once, `delay` ticks from now. A delay of 0 runs it on the next
+    /// tick.
+    pub fn schedule_once(
+        &mut self,
+        delay: u64,
+        callback: TimerCallback,
+    ) -> Result<TimerId, WheelFull> {
+        self.schedule(delay.max(1), 0, callback)
+    }
+
+    /// Runs `callback` every `period` ticks, starting `period` ticks from now.
+    pub fn schedule_periodic(
+        &mut self,
+        period: u64,
+        callback: TimerCallback,
+    ) -> Result<TimerId, WheelFull> {
+        let period = period.max(1);
+        self.schedule(period, period, callback)
+    }
+
+    fn schedule(
+        &mut self,
+        delay: u64,
+        period: u64,
+        callback: TimerCallback,
+    ) -> Result<TimerId, WheelFull> {
+        let index = self
+            .entries
+            .iter()
+            .position(|entry| entry.callback.is_none())
+            .ok_or(WheelFull)?;
+        let entry = &mut self.entries[index];
+        entry.callback = Some(callback);
+        entry.period = period;
+        entry.generation = entry.generation.wrapping_add(1);
+        let id = TimerId {
+            index,
+            generation: entry.generation,
+        };
+        self.insert(index, self.current + delay);
+        Ok(id)
+    }
+
+    /// Stops a timer. Returns false if it already fired for the last time or was
+    /// cancelled.
+    pub fn cancel(&mut self, id: TimerId) -> bool {
+        match self.entries.get(id.index) {
+            Some(entry) if entry.callback.is_some() && entry.generation == id.generation => {
+                self.unlink(id.index);
+                self.entries[id.index].callback = None;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Advances the wheel towards tick `now`, returning the callback of the next timer
+    /// that is due. Periodic timers are rescheduled before they are returned. Call it
+    /// until it returns `None` and run each callback, the wheel is free to change while
+    /// they run.
+    pub fn pop_expired(&mut self, now: u64) -> Option<TimerCallback> {
+        while self.current <= now {
+            let slot = self.slot(self.current);
+            let mut cursor = self.slots[slot];
+            while let Some(index) = cursor {
+                let entry = self.entries[index];
+                cursor = entry.next;
+                if entry.expires > self.current {
+                    continue;
+                }
+                self.unlink(index);
+                if entry.period == 0 {
+                    self.entries[index].callback = None;
+                } else {
+                    self.insert(index, self.current + entry.period);
+                }
+                return entry.callback;
+            }
+            self.current += 1;
+        }
+        None
+    }
+
+    fn slot(&self, tick: u64) -> usize {
+        (tick % SLOTS as u64) as usize
+    }
+
+    fn insert(&mut self, index: usize, expires: u64) {
+        let slot = self.slot(expires);
+        self.entries[index].expires = expires;
+        self.entries[index].next = self.slots[slot];
+        self.slots[slot] = Some(index);
+    }
+
+    fn unlink(&mut self, index: usize) {
+        let slot = self.slot(self.entries[index].expires);
+        let next = self.entries[index].next;
+        if self.slots[slot] == Some(index) {
+            self.slots[slot] = next;
+            return;
+        }
+        let mut cursor = self.slots[slot];
+        while let Some(previous) = cursor {
+            if self.entries[previous].next == Some(index) {
+                self.entries[previous].next = next;
+                return;
+            }
+            cursor = self.entries[previous].next;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use core::sync::atomic::{AtomicU32, Ordering};
+
+    use std::vec::Vec;
+
+    /// Advances one tick at a time up to `until`, collecting what fired at each tick.
+    fn run<const S: usize, const C: usize>(
+        wheel: &mut TimerWheel<S, C>,
+        until: u64,
+    ) -> Vec<(u64, TimerCallback)> {
+        let mut fired = Vec::new();
+        for tick in wheel.now()..=until {
+            while let Some(callback) = wheel.pop_expired(tick) {
+                fired.push((tick, callback));
+            }
+        }
+        fired
+    }
+
+    // Distinct bodies so the callbacks can't be merged into one function
+    static LAST: AtomicU32 = AtomicU32::new(0);
+    fn a() {
+        LAST.store(1, Ordering::Relaxed);
+    }
+    fn b() {
+        LAST.store(2, Ordering::Relaxed);
+    }
+    fn c() {
+        LAST.store(3, Ordering::Relaxed);
+    }
+
+    /// Jumps straight to `now`, as the tick interrupt does after running late.
+    fn run_at_once<const S: usize, const C: usize>(
+        wheel: &mut TimerWheel<S, C>,
+        now: u64,
+    ) -> Vec<(u64, TimerCallback)> {
+        let mut fired = Vec::new();
+        while let Some(callback) = wheel.pop_expired(now) {
+            fired.push((now, callback));
+        }
+        fired
+    }
+
+    fn ticks_of(fired: &[(u64, TimerCallback)], callback: TimerCallback) -> Vec<u64> {
+        fired
+            .iter()
+            .filter(|(_, fired)| core::ptr::fn_addr_eq(*fired, callback))
+            .map(|(tick, _)| *tick)
+            .collect()
+    }
+
+    #[test]
+    fn test_fires_one_shot_and_periodic_timers() {
+        let mut wheel = TimerWheel::<8, 4>::new();
+        wheel.schedule_once(3, a).unwrap();
+        // Further out than the wheel has slots, so it shares slot 4 with the periodic timer
+        wheel.schedule_once(20, b).unwrap();
+        wheel.schedule_periodic(4, c).unwrap();
+
+        let fired = run(&mut wheel, 21);
+        assert_eq!(ticks_of(&fired, a), [3]);
+        assert_eq!(ticks_of(&fired, b), [20]);
+        assert_eq!(ticks_of(&fired, c), [4, 8, 12, 16, 20]);
+
+        // Falling behind fires everything that was missed, in order
+        let fired = run_at_once(&mut wheel, 30);
+        assert_eq!(ticks_of(&fired, c), [30, 30]);
+    }
+
+    #[test]
+    fn test_cancels_and_reuses_entries() {
+        static RUNS: AtomicU32 = AtomicU32::new(0);
+        fn count() {
+            RUNS.fetch_add(1, Ordering::Relaxed);
+        }
+
+        let mut wheel = TimerWheel::<4, 2>::new();
+        let first = wheel.schedule_periodic(1, count).unwrap();
+        let second = wheel.schedule_once(0, count).unwrap();
+        assert_eq!(wheel.schedule_once(1, count), Err(WheelFull));
+
+        for callback in run(&mut wheel, 2) {
+            (callback.1)();
+        }
+        assert_eq!(RUNS.load(Ordering::Relaxed), 3);
+
+        // The one-shot timer is gone, so its entry can be reused
+        assert!(!wheel.cancel(second));
+        let third = wheel.schedule_once(5, count).unwrap();
+        assert!(!wheel.cancel(second));
+        assert!(wheel.cancel(first));
+        assert!(!wheel.cancel(first));
+        assert!(wheel.cancel(third));
+        assert!(run(&mut wheel, 20).is_empty());
+    }
+}
@@ -0,0 +1,577 @@
+//! XMODEM-CRC and YMODEM (batch of one file) receivers.
+//!
+//! Both are driven by a `tick` callback that returns true once per timeout interval
+//! (one second by convention, e.g. `Timer::new(1000).elapsed()`), so they work on
+//! top of any `SerialInterface` without owning a clock.
+
+use core::fmt;
+
+use crate::serial::SerialInterface;
+
+const SOH: u8 = 0x01;
+const STX: u8 = 0x02;
+const EOT: u8 = 0x04;
+const ACK: u8 = 0x06;
+const NAK: u8 = 0x15;
+const CAN: u8 = 0x18;
+const CRC_MODE: u8 = b'C';
+
+/// Padding XMODEM appends to fill the final block
+pub const SUB: u8 = 0x1A;
+
+const MAX_ERRORS: u32 = 10;
+const START_ATTEMPTS: u32 = 20;
+const PACKET_TIMEOUT_TICKS: u32 = 10;
+const BYTE_TIMEOUT_TICKS: u32 = 1;
+
+pub const MAX_FILE_NAME: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TransferError {
+    /// The sender never started a transfer
+    NoSender,
+    /// The sender cancelled with CAN CAN
+    Cancelled,
+    /// Too many consecutive corrupted or missing packets
+    TooManyErrors,
+    /// A packet arrived out of order, so data was lost
+    OutOfSequence,
+    /// The storage ran out of room
+    StorageFull,
+    /// The YMODEM header block could not be parsed
+    BadHeader,
+}
+
+impl fmt::Display for TransferError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransferError::NoSender => write!(f, "no sender started a transfer"),
+            TransferError::Cancelled => write!(f, "cancelled by the sender"),
+            TransferError::TooManyErrors => write!(f, "too many transmission errors"),
+            TransferError::OutOfSequence => write!(f, "packet out of sequence"),
+            TransferError::StorageFull => write!(f, "file doesn't fit"),
+            TransferError::BadHeader => write!(f, "malformed YMODEM header"),
+        }
+    }
+}
+
+/// Destination for received data, e.g. a RAM region or a file on the SD card.
+pub trait Storage {
+    fn write_at(&mut self, offset: usize, data: &[u8]) -> Result<(), TransferError>;
+}
+
+impl Storage for [u8] {
+    fn write_at(&mut self, offset: usize, data: &[u8]) -> Result<(), TransferError> {
+        let end = offset
+            .checked_add(data.len())
+            .ok_or(TransferError::StorageFull)?;
+        let dest = self
+            .get_mut(offset..end)
+            .ok_or(TransferError::StorageFull)?;
+        dest.copy_from_slice(data);
+        Ok(())
+    }
+}
+
+/// Name and size announced in a YMODEM header block.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FileInfo {
+    name: [u8; MAX_FILE_NAME],
+    name_len: usize,
+    pub size: usize,
+}
+
+impl FileInfo {
+    pub fn name(&self) -> &str {
+        core::str::from_utf8(&self.name[..self.name_len]).unwrap_or("")
+    }
+}
+
+/// CRC-16/XMODEM: polynomial 0x1021, initial value 0, no reflection.
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Receives one file over XMODEM-CRC into `storage`. Returns the number of bytes stored,
+/// which includes the `SUB` padding of the final block.
+pub fn receive_xmodem<S: SerialInterface, W: Storage + ?Sized>(
+    serial: &mut S,
+    storage: &mut W,
+    mut tick: impl FnMut() -> bool,
+) -> Result<usize, TransferError> {
+    let mut receiver = Receiver::new(serial, &mut tick);
+    receiver.receive_data(storage, None)
+}
+
+/// Receives the first file of a YMODEM batch into `storage`, trimmed to its announced size.
+pub fn receive_ymodem<S: SerialInterface, W: Storage + ?Sized>(
+    serial: &mut S,
+    storage: &mut W,
+    mut tick: impl FnMut() -> bool,
+) -> Result<FileInfo, TransferError> {
+    let mut receiver = Receiver::new(serial, &mut tick);
+    let mut block = [0u8; 1024];
+
+    let len = match receiver.start(&mut block, 0)? {
+        Packet::Data(_, len) => len,
+        Packet::EndOfTransfer => return Err(receiver.cancel(TransferError::BadHeader)),
+    };
+    let info = match parse_header(&block[..len]) {
+        Some(info) => info,
+        None => return Err(receiver.cancel(TransferError::BadHeader)),
+    };
+    receiver.serial.write_byte(ACK);
+
+    receiver.receive_data(storage, Some(info.size))?;
+
+    // Decline the rest of the batch by acknowledging the sender's closing null header
+    receiver.serial.write_byte(CRC_MODE);
+    if let Ok(Packet::Data(0, _)) = receiver.read_packet(&mut block) {
+        receiver.serial.write_byte(ACK);
+    }
+
+    Ok(info)
+}
+
+/// Parses "name\0size[ mtime ...]\0" from a YMODEM block 0.
+fn parse_header(block: &[u8]) -> Option<FileInfo> {
+    let name_len = block.iter().position(|&b| b == 0)?;
+    if name_len == 0 || name_len > MAX_FILE_NAME {
+        return None;
+    }
+    let rest = &block[name_len + 1..];
+    let digits = rest.iter().take_while(|b| b.is_ascii_digit()).count();
+    if digits == 0 {
+        return None;
+    }
+    let size = core::str::from_utf8(&rest[..digits]).ok()?.parse().ok()?;
+
+    let mut name = [0u8; MAX_FILE_NAME];
+    name[..name_len].copy_from_slice(&block[..name_len]);
+    Some(FileInfo {
+        name,
+        name_len,
+        size,
+    })
+}
+
+enum Packet {
+    /// Block number and payload length
+    Data(u8, usize),
+    EndOfTransfer,
+}
+
+/// Why a packet couldn't be read
+enum PacketError {
+    /// Nothing arrived at all
+    Timeout,
+    /// A packet arrived damaged or incomplete
+    Retry,
+    Fatal(TransferError),
+}
+
+struct Receiver<'a, S: SerialInterface, T: FnMut() -> bool> {
+    serial: &'a mut S,
+    tick: &'a mut T,
+}
+
+impl<'a, S: SerialInterface, T: FnMut() -> bool> Receiver<'a, S, T> {
+    fn new(serial: &'a mut S, tick: &'a mut T) -> Self {
+        Self { serial, tick }
+    }
+
+    fn read_byte(&mut self, ticks: u32) -> Option<u8> {
+        let mut remaining = ticks;
+        loop {
+            if let Some(byte) = self.serial.try_read_byte() {
+                return Some(byte);
+            }
+            if (self.tick)() {
+                remaining -= 1;
+                if remaining == 0 {
+                    return None;
+                }
+            }
+        }
+    }
+
+    /// Requests CRC mode until the sender answers with the first packet.
+    fn start(&mut self, block: &mut [u8; 1024], expected: u8) -> Result<Packet, TransferError> {
+        let mut errors = 0;
+        for _ in 0..START_ATTEMPTS {
+            self.serial.write_byte(CRC_MODE);
+            match self.read_packet(block) {
+                Ok(Packet::Data(number, len)) if number == expected => {
+                    return Ok(Packet::Data(number, len));
+                }
+                Ok(Packet::Data(..)) => {
+                    return Err(self.cancel(TransferError::OutOfSequence));
+                }
+                Ok(Packet::EndOfTransfer) => return Ok(Packet::EndOfTransfer),
+                Err(PacketError::Fatal(err)) => return Err(err),
+                Err(PacketError::Timeout) => {}
+                Err(PacketError::Retry) => {
+                    errors += 1;
+                    if errors > MAX_ERRORS {
+                        return Err(self.cancel(TransferError::TooManyErrors));
+                    }
+                }
+            }
+        }
+        Err(TransferError::NoSender)
+    }
+
+    /// Receives data packets numbered from 1 until EOT, writing them to `storage`. With
+    /// `limit`, bytes past it (the final block's padding) are dropped.
+    fn receive_data<W: Storage + ?Sized>(
+        &mut self,
+        storage: &mut W,
+        limit: Option<usize>,
+    ) -> Result<usize, TransferError> {
+        let mut block = [0u8; 1024];
+        let mut expected = 1;
+        let mut offset = 0;
+        let mut errors = 0;
+
+        let mut packet = self.start(&mut block, expected)?;
+
+        loop {
+            match packet {
+                Packet::Data(number, len) if number == expected => {
+                    let len = match limit {
+                        Some(limit) => len.min(limit.saturating_sub(offset)),
+                        None => len,
+                    };
+                    if let Err(err) = storage.write_at(offset, &block[..len]) {
+                        return Err(self.cancel(err));
+                    }
+                    offset += len;
+                    expected = expected.wrapping_add(1);
+                    errors = 0;
+                    self.serial.write_byte(ACK);
+                }
+                // Our ACK was lost and the sender repeated the previous block
+                Packet::Data(number, _) if number == expected.wrapping_sub(1) => {
+                    self.serial.write_byte(ACK);
+                }
+                Packet::Data(..) => return Err(self.cancel(TransferError::OutOfSequence)),
+                Packet::EndOfTransfer => {
+                    self.serial.write_byte(ACK);
+                    return Ok(offset);
+                }
+            }
+
+            packet = loop {
+                match self.read_packet(&mut block) {
+                    Ok(packet) => break packet,
+                    Err(PacketError::Fatal(err)) => return Err(err),
+                    Err(PacketError::Timeout | PacketError::Retry) => {
+                        errors += 1;
+                        if errors > MAX_ERRORS {
+                            return Err(self.cancel(TransferError::TooManyErrors));
+                        }
+                        self.serial.write_byte(NAK);
+                    }
+                }
+            };
+        }
+    }
+
+    fn read_packet(&mut self, block: &mut [u8; 1024]) -> Result<Packet, PacketError> {
+        let len = match self.read_byte(PACKET_TIMEOUT_TICKS) {
+            Some(SOH) => 128,
+            Some(STX) => 1024,
+            Some(EOT) => return Ok(Packet::EndOfTransfer),
+            Some(CAN) => {
+                return match self.read_byte(BYTE_TIMEOUT_TICKS) {
+                    Some(CAN) => Err(PacketError::Fatal(TransferError::Cancelled)),
+                    _ => Err(PacketError::Retry),
+                };
+            }
+            Some(_) => {
+                self.purge();
+                return Err(PacketError::Retry);
+            }
+            None => return Err(PacketError::Timeout),
+        };
+
+        let mut header = [0u8; 2];
+        for byte in header.iter_mut() {
+            *byte = self
+                .read_byte(BYTE_TIMEOUT_TICKS)
+                .ok_or(PacketError::Retry)?;
+        }
+        for byte in block[..len].iter_mut() {
+            *byte = self
+                .read_byte(BYTE_TIMEOUT_TICKS)
+                .ok_or(PacketError::Retry)?;
+        }
+        let mut crc = [0u8; 2];
+        for byte in crc.iter_mut() {
+            *byte = self
+                .read_byte(BYTE_TIMEOUT_TICKS)
+                .ok_or(PacketError::Retry)?;
+        }
+
+        if header[0] != !header[1] || u16::from_be_bytes(crc) != crc16(&block[..len]) {
+            self.purge();
+            return Err(PacketError::Retry);
+        }
+
+        Ok(Packet::Data(header[0], len))
+    }
+
+    /// Drops input until the line has been quiet for a timeout, so a NAK lands between packets.
+    fn purge(&mut self) {
+        while self.read_byte(BYTE_TIMEOUT_TICKS).is_some() {}
+    }
+
+    fn cancel(&mut self, err: TransferError) -> TransferError {
+        self.serial.write_bytes(&[CAN, CAN, CAN]);
+        err
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::VecDeque;
+    use std::format;
+    use std::vec::Vec;
+
+    /// In-process XMODEM/YMODEM sender that reacts to each byte the receiver sends.
+    struct Sender {
+        packets: Vec<Vec<u8>>,
+        next: usize,
+        /// Packet indices to corrupt the first time they are sent
+        corrupt: Vec<usize>,
+        /// Packet indices to truncate the first time they are sent
+        truncate: Vec<usize>,
+        sent_eot: bool,
+        retransmissions: usize,
+        done: bool,
+    }
+
+    fn packet(number: u8, payload: &[u8], size: usize) -> Vec<u8> {
+        let mut data = payload.to_vec();
+        data.resize(size, if number == 0 { 0 } else { SUB });
+        let mut packet = vec![if size == 128 { SOH } else { STX }, number, !number];
+        packet.extend_from_slice(&data);
+        packet.extend_from_slice(&crc16(&data).to_be_bytes());
+        packet
+    }
+
+    impl Sender {
+        fn xmodem(data: &[u8], block_size: usize) -> Self {
+            let packets = data
+                .chunks(block_size)
+                .enumerate()
+                .map(|(i, chunk)| packet((i + 1) as u8, chunk, block_size))
+                .collect();
+            Self {
+                packets,
+                next: 0,
+                corrupt: Vec::new(),
+                truncate: Vec::new(),
+                sent_eot: false,
+                retransmissions: 0,
+                done: false,
+            }
+        }
+
+        fn ymodem(name: &str, data: &[u8]) -> Self {
+            let header = format!("{name}\0{} 0\0", data.len());
+            let mut sender = Self::xmodem(data, 1024);
+            sender.packets.insert(0, packet(0, header.as_bytes(), 128));
+            // Null header closing the batch
+            sender.packets.push(packet(0, &[], 128));
+            sender
+        }
+
+        fn is_ymodem(&self) -> bool {
+            self.packets[0][1] == 0
+        }
+
+        fn send(&mut self, index: usize) -> Vec<u8> {
+            let mut packet = self.packets[index].clone();
+            if let Some(pos) = self.corrupt.iter().position(|&i| i == index) {
+                self.corrupt.remove(pos);
+                packet[40] ^= 0x55;
+            } else if let Some(pos) = self.truncate.iter().position(|&i| i == index) {
+                self.truncate.remove(pos);
+                packet.truncate(packet.len() / 2);
+            }
+            packet
+        }
+
+        fn data_end(&self) -> usize {
+            if self.is_ymodem() {
+                self.packets.len() - 1
+            } else {
+                self.packets.len()
+            }
+        }
+
+        fn on_receive(&mut self, byte: u8) -> Vec<u8> {
+            match byte {
+                CRC_MODE if self.next == 0 || self.sent_eot => {
+                    if self.sent_eot {
+                        // YMODEM receiver asking for the next file, offer the end of the batch
+                        self.next = self.packets.len() - 1;
+                    }
+                    self.send(self.next)
+                }
+                // YMODEM receivers send 'C' again after acknowledging block 0
+                CRC_MODE => self.send(self.next),
+                ACK if self.sent_eot => {
+                    self.done = true;
+                    Vec::new()
+                }
+                ACK => {
+                    self.next += 1;
+                    if self.next == self.data_end() {
+                        self.sent_eot = true;
+                        vec![EOT]
+                    } else if self.is_ymodem() && self.next == 1 {
+                        // Wait for the receiver's 'C' before the first data block
+                        Vec::new()
+                    } else {
+                        self.send(self.next)
+                    }
+                }
+                NAK => {
+                    self.retransmissions += 1;
+                    self.send(self.next)
+                }
+                _ => Vec::new(),
+            }
+        }
+    }
+
+    /// Connects a receiver to a `Sender`, delivering the sender's replies as receive data
+    struct Link {
+        sender: Sender,
+        rx: VecDeque<u8>,
+        tx: Vec<u8>,
+    }
+
+    impl Link {
+        fn new(sender: Sender) -> Self {
+            Self {
+                sender,
+                rx: VecDeque::new(),
+                tx: Vec::new(),
+            }
+        }
+    }
+
+    impl SerialInterface for Link {
+        fn write_byte(&mut self, byte: u8) {
+            self.tx.push(byte);
+            let reply = self.sender.on_receive(byte);
+            self.rx.extend(reply);
+        }
+
+        fn read_byte(&mut self) -> u8 {
+            self.rx.pop_front().unwrap()
+        }
+
+        fn try_read_byte(&mut self) -> Option<u8> {
+            self.rx.pop_front()
+        }
+
+        fn flush(&mut self) {}
+    }
+
+    fn payload(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 31 % 251) as u8).collect()
+    }
+
+    #[test]
+    fn test_crc16_known_vector() {
+        assert_eq!(crc16(b"123456789"), 0x31C3);
+    }
+
+    #[test]
+    fn test_xmodem_receives_into_ram() {
+        let data = payload(300);
+        let mut link = Link::new(Sender::xmodem(&data, 128));
+        let mut ram = [0u8; 1024];
+
+        assert_eq!(receive_xmodem(&mut link, &mut ram[..], || true), Ok(384));
+        assert_eq!(&ram[..300], &data[..]);
+        assert!(ram[300..384].iter().all(|&b| b == SUB));
+        assert!(link.sender.done);
+    }
+
+    #[test]
+    fn test_xmodem_retransmits_corrupted_and_truncated_packets() {
+        let data = payload(1024 * 3);
+        let mut sender = Sender::xmodem(&data, 1024);
+        sender.corrupt = vec![0, 2];
+        sender.truncate = vec![1];
+        let mut link = Link::new(sender);
+        let mut ram = [0u8; 4096];
+
+        assert_eq!(receive_xmodem(&mut link, &mut ram[..], || true), Ok(3072));
+        assert_eq!(&ram[..3072], &data[..]);
+        assert_eq!(link.sender.retransmissions, 2);
+    }
+
+    #[test]
+    fn test_xmodem_cancels_when_storage_is_full() {
+        let mut link = Link::new(Sender::xmodem(&payload(512), 128));
+        let mut ram = [0u8; 256];
+
+        assert_eq!(
+            receive_xmodem(&mut link, &mut ram[..], || true),
+            Err(TransferError::StorageFull)
+        );
+        assert!(link.tx.ends_with(&[CAN, CAN, CAN]));
+
+        // An offset that would wrap around is full, not a panic
+        assert_eq!(
+            ram[..].write_at(usize::MAX, &[0; 2]),
+            Err(TransferError::StorageFull)
+        );
+    }
+
+    #[test]
+    fn test_ymodem_receives_named_file_trimmed_to_size() {
+        let data = payload(2500);
+        let mut sender = Sender::ymodem("config.txt", &data);
+        sender.corrupt = vec![2];
+        let mut link = Link::new(sender);
+        let mut ram = [0u8; 4096];
+
+        let info = receive_ymodem(&mut link, &mut ram[..], || true).unwrap();
+        assert_eq!(info.name(), "config.txt");
+        assert_eq!(info.size, 2500);
+        assert_eq!(&ram[..2500], &data[..]);
+        assert!(ram[2500..].iter().all(|&b| b == 0));
+        assert_eq!(link.sender.retransmissions, 1);
+        assert!(link.sender.done);
+    }
+
+    #[test]
+    fn test_gives_up_without_sender() {
+        let mut serial = crate::serial::mock::MockSerial::default();
+        let mut ram = [0u8; 128];
+        assert_eq!(
+            receive_xmodem(&mut serial, &mut ram[..], || true),
+            Err(TransferError::NoSender)
+        );
+    }
+}
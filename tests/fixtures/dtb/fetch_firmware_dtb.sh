@@ -0,0 +1,11 @@
+#!/bin/sh
+# Downloads the Raspberry Pi firmware's own bcm2711-rpi-4-b.dtb next to this script, for the
+# tests that check the parser against the real blob. The blob is committed; rerun this to
+# update it. FIRMWARE_REF picks the branch or tag
+# of github.com/raspberrypi/firmware, whose DTBs are built from the Linux device tree sources.
+set -e
+cd "$(dirname "$0")"
+REF="${FIRMWARE_REF:-stable}"
+
+curl -fsSL -o bcm2711-rpi-4-b.dtb \
+    "https://raw.githubusercontent.com/raspberrypi/firmware/$REF/boot/bcm2711-rpi-4-b.dtb"
@@ -0,0 +1,159 @@
+#!/usr/bin/env python3
+"""Writes rpi4b.dtb, the device tree fixture for the FDT parser tests.
+
+It mirrors the parts of a Raspberry Pi 4 Model B tree the bootloader reads, as the
+firmware hands it over in x0: bcm2711-rpi-4-b.dtb with /memory filled in for a 4 GB
+board. Node paths, cell sizes, `ranges` and compatibles follow the upstream blob. The tests
+assert on the exact nodes written here, so don't replace rpi4b.dtb with a real blob; the
+firmware's own bcm2711-rpi-4-b.dtb sits next to it, from fetch_firmware_dtb.sh.
+"""
+
+import os
+import struct
+
+FDT_MAGIC = 0xD00DFEED
+FDT_BEGIN_NODE, FDT_END_NODE, FDT_PROP, FDT_NOP, FDT_END = 1, 2, 3, 4, 9
+
+
+def cells(*values):
+    return b"".join(struct.pack(">I", v) for v in values)
+
+
+def strings(*values):
+    return b"".join(v.encode() + b"\0" for v in values)
+
+
+def cpu(n):
+    return (f"cpu@{n}", [
+        ("device_type", strings("cpu")),
+        ("compatible", strings("arm,cortex-a72")),
+        ("reg", cells(n)),
+        ("enable-method", strings("spin-table")),
+        ("cpu-release-addr", cells(0, 0xD8 + 8 * n)),
+    ], [])
+
+
+TREE = ("", [
+    ("compatible", strings("raspberrypi,4-model-b", "brcm,bcm2711")),
+    ("model", strings("Raspberry Pi 4 Model B Rev 1.4")),
+    ("#address-cells", cells(2)),
+    ("#size-cells", cells(1)),
+], [
+    ("aliases", [
+        ("serial0", strings("/soc/serial@7e215040")),
+        ("serial1", strings("/soc/serial@7e201000")),
+        ("i2c1", strings("/soc/i2c@7e804000")),
+    ], []),
+    ("chosen", [
+        ("bootargs", strings("coherent_pool=1M 8250.nr_uarts=1 console=ttyS0,115200 "
+                             "console=tty1 root=/dev/mmcblk0p2 rootfstype=ext4 rootwait")),
+        ("stdout-path", strings("serial0:115200n8")),
+    ], []),
+    ("memory@0", [
+        ("device_type", strings("memory")),
+        ("reg", cells(0x0, 0x00000000, 0x3B400000, 0x0, 0x40000000, 0xBC000000)),
+    ], []),
+    ("cpus", [
+        ("#address-cells", cells(1)),
+        ("#size-cells", cells(0)),
+        ("enable-method", strings("brcm,bcm2836-smp")),
+    ], [cpu(n) for n in range(4)]),
+    ("soc", [
+        ("compatible", strings("simple-bus")),
+        ("#address-cells", cells(1)),
+        ("#size-cells", cells(1)),
+        ("ranges", cells(0x7E000000, 0x0, 0xFE000000, 0x01800000,
+                         0x7C000000, 0x0, 0xFC000000, 0x02000000,
+                         0x40000000, 0x0, 0xFF800000, 0x00800000)),
+        ("dma-ranges", cells(0xC0000000, 0x0, 0x00000000, 0x40000000)),
+    ], [
+        ("mailbox@7e00b880", [
+            ("compatible", strings("brcm,bcm2835-mbox")),
+            ("reg", cells(0x7E00B880, 0x40)),
+        ], []),
+        ("gpio@7e200000", [
+            ("compatible", strings("brcm,bcm2711-gpio")),
+            ("reg", cells(0x7E200000, 0x100)),
+        ], []),
+        ("serial@7e201000", [
+            ("compatible", strings("arm,pl011", "arm,primecell")),
+            ("reg", cells(0x7E201000, 0x200)),
+        ], []),
+        ("aux@7e215000", [
+            ("compatible", strings("brcm,bcm2835-aux")),
+            ("reg", cells(0x7E215000, 0x8)),
+        ], []),
+        ("serial@7e215040", [
+            ("compatible", strings("brcm,bcm2835-aux-uart")),
+            ("reg", cells(0x7E215040, 0x40)),
+        ], []),
+        ("i2c@7e804000", [
+            ("compatible", strings("brcm,bcm2711-i2c", "brcm,bcm2835-i2c")),
+            ("reg", cells(0x7E804000, 0x1000)),
+            ("#address-cells", cells(1)),
+            ("#size-cells", cells(0)),
+        ], []),
+        ("interrupt-controller@40041000", [
+            ("compatible", strings("arm,gic-400")),
+            ("reg", cells(0x40041000, 0x1000, 0x40042000, 0x2000,
+                          0x40044000, 0x2000, 0x40046000, 0x2000)),
+            ("interrupt-controller", b""),
+            ("#interrupt-cells", cells(3)),
+        ], []),
+    ]),
+    ("timer", [
+        ("compatible", strings("arm,armv8-timer")),
+        ("interrupts", cells(1, 13, 0xF08, 1, 14, 0xF08, 1, 11, 0xF08, 1, 10, 0xF08)),
+    ], []),
+])
+
+MEMORY_RESERVATIONS = [(0x0, 0x1000)]
+
+
+def pad4(data):
+    return data + b"\0" * (-len(data) % 4)
+
+
+def build():
+    structs = bytearray()
+    string_table = bytearray()
+    string_offsets = {}
+
+    def string_offset(name):
+        if name not in string_offsets:
+            string_offsets[name] = len(string_table)
+            string_table.extend(name.encode() + b"\0")
+        return string_offsets[name]
+
+    def emit(node):
+        name, props, children = node
+        structs.extend(struct.pack(">I", FDT_BEGIN_NODE) + pad4(name.encode() + b"\0"))
+        for prop, value in props:
+            structs.extend(struct.pack(">III", FDT_PROP, len(value), string_offset(prop)))
+            structs.extend(pad4(value))
+        # dtc leaves NOPs behind when nodes are deleted, make sure they're skipped
+        if name == "chosen":
+            structs.extend(struct.pack(">I", FDT_NOP))
+        for child in children:
+            emit(child)
+        structs.extend(struct.pack(">I", FDT_END_NODE))
+
+    emit(TREE)
+    structs.extend(struct.pack(">I", FDT_END))
+
+    header_size = 40
+    rsvmap = b"".join(struct.pack(">QQ", a, s) for a, s in MEMORY_RESERVATIONS + [(0, 0)])
+    off_rsvmap = header_size
+    off_struct = off_rsvmap + len(rsvmap)
+    off_strings = off_struct + len(structs)
+    total = off_strings + len(string_table)
+
+    header = struct.pack(">10I", FDT_MAGIC, total, off_struct, off_strings, off_rsvmap,
+                         17, 16, 0, len(string_table), len(structs))
+    return header + rsvmap + bytes(structs) + bytes(string_table)
+
+
+if __name__ == "__main__":
+    path = os.path.join(os.path.dirname(os.path.abspath(__file__)), "rpi4b.dtb")
+    with open(path, "wb") as f:
+        f.write(build())
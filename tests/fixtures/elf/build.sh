@@ -0,0 +1,8 @@
+#!/bin/sh
+# Regenerates the ELF loader test fixtures. Needs llvm-mc and rust-lld (ships with rustup).
+set -e
+cd "$(dirname "$0")"
+LLD="$(rustc --print sysroot)/lib/rustlib/$(rustc -vV | sed -n 's/^host: //p')/bin/rust-lld"
+
+llvm-mc -triple aarch64-none-elf -filetype=obj kernel.s -o kernel.o
+"$LLD" -flavor gnu -T kernel.ld -z max-page-size=4096 kernel.o -o kernel.elf
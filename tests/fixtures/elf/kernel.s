@@ -0,0 +1,17 @@
+// Minimal AArch64 kernel for the ELF loader tests, see build.sh
+
+    .section .text._start, "ax"
+    .word 0xd503201f            // nop, so the entry point isn't the segment start
+    .global _start
+_start:
+    ldr     x0, =message
+    b       .
+
+    .section .data, "aw"
+message:
+    .ascii "hello from the data segment"
+
+    .section .bss, "aw", %nobits
+    .balign 16
+scratch:
+    .space 256
@@ -0,0 +1,367 @@
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::process::{Command, ExitCode, Stdio};
+use std::thread;
+
+use raspi4_rust_bootloader::boot_image::ImageHeader;
+use raspi4_rust_bootloader::bundle::{self, Kind};
+use raspi4_rust_bootloader::chainload::{ACK, CHECKSUM_ERROR, READY_MARKER, SIZE_ERROR, checksum};
+
+const DEFAULT_BAUD: u32 = 115_200;
+const CHUNK_SIZE: usize = 4096;
+/// Ctrl-], which leaves the terminal since Ctrl-C goes to the board in raw mode
+const QUIT_KEY: u8 = 0x1D;
+
+const USAGE: &str =
+    "Usage: chainload <serial-device> <kernel> [baud] [--dtb <file.dtb> [--initrd <file>]]";
+
+fn main() -> ExitCode {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let dtb = take_option(&mut args, "--dtb");
+    let initrd = take_option(&mut args, "--initrd");
+    if initrd.is_some() && dtb.is_none() {
+        eprintln!("--initrd needs --dtb\n{USAGE}");
+        return ExitCode::FAILURE;
+    }
+    let (device, image_path) = match (args.first(), args.get(1)) {
+        (Some(device), Some(image)) => (device, image),
+        _ => {
+            eprintln!("{USAGE}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let baud = match args.get(2).map(|baud| baud.parse()) {
+        None => DEFAULT_BAUD,
+        Some(Ok(baud)) => baud,
+        Some(Err(_)) => {
+            eprintln!("Invalid baud rate\n{USAGE}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    match run(device, image_path, dtb.as_deref(), initrd.as_deref(), baud) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("chainload: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Removes `name` and the value following it from `args`.
+fn take_option(args: &mut Vec<String>, name: &str) -> Option<String> {
+    let index = args.iter().position(|arg| arg == name)?;
+    args.remove(index);
+    (index < args.len()).then(|| args.remove(index))
+}
+
+fn run(
+    device: &str,
+    image_path: &str,
+    dtb_path: Option<&str>,
+    initrd_path: Option<&str>,
+    baud: u32,
+) -> io::Result<()> {
+    let mut image = fs::read(image_path)?;
+    // A kernel sent with a DTB is a Linux Image, bundled with the files it boots with
+    if let Some(dtb_path) = dtb_path {
+        let dtb = fs::read(dtb_path)?;
+        let initrd = initrd_path.map(fs::read).transpose()?;
+        image = linux_bundle(&image, &dtb, initrd.as_deref());
+    }
+    let image = with_header(image);
+    let mut port = open_port(device, baud)?;
+
+    eprintln!("[chainload] Waiting for the board on {device}...");
+    wait_for_ready(&mut port, &mut io::stdout())?;
+    eprintln!("[chainload] Sending {image_path} ({} bytes)", image.len());
+    send_kernel(&mut port, &image)?;
+    eprintln!("[chainload] Kernel sent, entering terminal (Ctrl-] to quit)");
+
+    terminal(port)
+}
+
+/// Opens `device` and puts it into raw mode at `baud` using the system's `stty`.
+fn open_port(device: &str, baud: u32) -> io::Result<File> {
+    let port = OpenOptions::new().read(true).write(true).open(device)?;
+
+    let device_flag = if cfg!(target_os = "macos") {
+        "-f"
+    } else {
+        "-F"
+    };
+    let status = Command::new("stty")
+        .args([device_flag, device, &baud.to_string(), "raw", "-echo"])
+        .status()?;
+    if !status.success() {
+        return Err(io::Error::other(format!(
+            "stty failed to configure {device}"
+        )));
+    }
+
+    Ok(port)
+}
+
+/// Reads until the board's ready marker, passing everything before it through to `console`
+/// so boot messages stay visible.
+fn wait_for_ready<P: Read, C: Write>(port: &mut P, console: &mut C) -> io::Result<()> {
+    let mut matched = 0;
+    let mut byte = [0u8; 1];
+    while matched < READY_MARKER.len() {
+        port.read_exact(&mut byte)?;
+        if byte[0] == READY_MARKER[matched] {
+            matched += 1;
+        } else {
+            console.write_all(&READY_MARKER[..matched])?;
+            console.write_all(&byte)?;
+            console.flush()?;
+            matched = 0;
+        }
+    }
+    Ok(())
+}
+
+fn linux_bundle(kernel: &[u8], dtb: &[u8], initrd: Option<&[u8]>) -> Vec<u8> {
+    let mut parts = vec![(Kind::Kernel, kernel), (Kind::DeviceTree, dtb)];
+    if let Some(initrd) = initrd {
+        parts.push((Kind::Initrd, initrd));
+    }
+    let mut bundle = vec![0u8; bundle::encoded_len(&parts)];
+    bundle::encode(&parts, &mut bundle).unwrap();
+    bundle
+}
+
+/// Prepends an integrity header so the board can verify the kernel before booting it,
+/// unless the image already carries one.
+fn with_header(image: Vec<u8>) -> Vec<u8> {
+    if ImageHeader::parse(&image).is_some() {
+        return image;
+    }
+    let header = ImageHeader::for_payload(&image);
+    let mut wrapped = header.to_bytes()[..header.size()].to_vec();
+    wrapped.extend_from_slice(&image);
+    wrapped
+}
+
+/// Sends the size and checksum header, then the image, checking the board's reply to each.
+fn send_kernel<P: Read + Write>(port: &mut P, image: &[u8]) -> io::Result<()> {
+    let size = u32::try_from(image.len())
+        .map_err(|_| io::Error::other("Kernel image is larger than 4 GiB"))?;
+
+    port.write_all(&size.to_le_bytes())?;
+    port.write_all(&checksum(image).to_le_bytes())?;
+    port.flush()?;
+    expect_ack(port)?;
+
+    for (i, chunk) in image.chunks(CHUNK_SIZE).enumerate() {
+        port.write_all(chunk)?;
+        let sent = (i * CHUNK_SIZE + chunk.len()) as u64;
+        eprint!("\r[chainload] {:3}%", sent * 100 / image.len() as u64);
+    }
+    port.flush()?;
+    eprintln!();
+
+    expect_ack(port)
+}
+
+/// Reads the board's two byte reply. Ready markers the board sent before it saw the header
+/// may still be buffered ahead of it, so those are skipped.
+fn expect_ack<P: Read>(port: &mut P) -> io::Result<()> {
+    let mut reply = [0u8; 2];
+    loop {
+        port.read_exact(&mut reply[..1])?;
+        if !READY_MARKER.contains(&reply[0]) {
+            break;
+        }
+    }
+    port.read_exact(&mut reply[1..])?;
+    match reply {
+        ACK => Ok(()),
+        SIZE_ERROR => Err(io::Error::other("Board rejected the kernel size")),
+        CHECKSUM_ERROR => Err(io::Error::other("Board reported a checksum mismatch")),
+        other => Err(io::Error::other(format!(
+            "Unexpected reply from board: {other:02x?}"
+        ))),
+    }
+}
+
+/// Copies board output to stdout and stdin to the board until the board closes or
+/// `QUIT_KEY` is pressed. The local terminal is raw meanwhile, so every key goes straight
+/// to the board.
+fn terminal(port: File) -> io::Result<()> {
+    let mut from_board = port.try_clone()?;
+    thread::spawn(move || {
+        let _ = io::copy(&mut from_board, &mut io::stdout());
+    });
+
+    let _raw = RawTerminal::enter();
+    let mut to_board = port;
+    let mut stdin = io::stdin().lock();
+    let mut buf = [0u8; 256];
+    loop {
+        let len = stdin.read(&mut buf)?;
+        let input = &buf[..len];
+        match input.iter().position(|&byte| byte == QUIT_KEY) {
+            Some(end) => return to_board.write_all(&input[..end]),
+            None if len == 0 => return Ok(()),
+            None => to_board.write_all(input)?,
+        }
+    }
+}
+
+/// Raw mode on the local terminal, restored when dropped.
+struct RawTerminal {
+    saved: String,
+}
+
+impl RawTerminal {
+    /// `None` if stdin isn't a terminal.
+    fn enter() -> Option<Self> {
+        let saved = Command::new("stty")
+            .arg("-g")
+            .stdin(Stdio::inherit())
+            .output()
+            .ok()
+            .filter(|output| output.status.success())?;
+        let saved = String::from_utf8(saved.stdout).ok()?.trim().to_owned();
+        let status = Command::new("stty").args(["raw", "-echo"]).status().ok()?;
+        status.success().then_some(RawTerminal { saved })
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        let _ = Command::new("stty").arg(&self.saved).status();
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    use std::ffi::{CStr, c_char, c_int};
+    use std::os::fd::FromRawFd;
+
+    use raspi4_rust_bootloader::boot_image::verify_image;
+    use raspi4_rust_bootloader::bundle::Bundle;
+    use raspi4_rust_bootloader::chainload::{self, ChainloadError, wait_for_host};
+    use raspi4_rust_bootloader::serial::SerialInterface;
+
+    unsafe extern "C" {
+        fn posix_openpt(flags: c_int) -> c_int;
+        fn grantpt(fd: c_int) -> c_int;
+        fn unlockpt(fd: c_int) -> c_int;
+        fn ptsname_r(fd: c_int, buf: *mut c_char, len: usize) -> c_int;
+    }
+
+    const O_RDWR: c_int = 0o2;
+    const O_NOCTTY: c_int = 0o400;
+
+    /// Opens a pseudo-terminal, returning the master side and the path of the slave side.
+    fn open_pty() -> (File, String) {
+        unsafe {
+            let fd = posix_openpt(O_RDWR | O_NOCTTY);
+            assert!(fd >= 0, "posix_openpt failed");
+            assert_eq!(grantpt(fd), 0);
+            assert_eq!(unlockpt(fd), 0);
+            let mut name = [0 as c_char; 64];
+            assert_eq!(ptsname_r(fd, name.as_mut_ptr(), name.len()), 0);
+            let path = CStr::from_ptr(name.as_ptr()).to_str().unwrap().to_owned();
+            (File::from_raw_fd(fd), path)
+        }
+    }
+
+    /// The board end of the wire, driving the kernel's chainload code over the PTY master
+    struct Board(File);
+
+    impl SerialInterface for Board {
+        fn write_byte(&mut self, byte: u8) {
+            self.0.write_all(&[byte]).unwrap();
+        }
+
+        fn read_byte(&mut self) -> u8 {
+            let mut byte = [0u8; 1];
+            self.0.read_exact(&mut byte).unwrap();
+            byte[0]
+        }
+
+        fn try_read_byte(&mut self) -> Option<u8> {
+            Some(self.read_byte())
+        }
+
+        fn flush(&mut self) {}
+    }
+
+    fn run_board(master: File, capacity: usize) -> Result<Vec<u8>, ChainloadError> {
+        let mut board = Board(master);
+        board.0.write_all(b"booting\r\n").unwrap();
+        let header = wait_for_host(&mut board, 1, || false).unwrap();
+        let mut dest = vec![0u8; capacity];
+        let size = chainload::receive(&mut board, header, &mut dest, || false)?;
+        dest.truncate(size);
+        Ok(dest)
+    }
+
+    #[test]
+    fn test_sends_kernel_to_board_over_pty() {
+        let (master, slave) = open_pty();
+        let mut port = open_port(&slave, DEFAULT_BAUD).unwrap();
+        // Closing the master discards output the host hasn't read yet, so the board gets a
+        // clone and the original stays open until the test ends
+        let board_end = master.try_clone().unwrap();
+        let board = thread::spawn(move || run_board(board_end, 64 * 1024));
+
+        let kernel: Vec<u8> = (0..20_000u32).map(|i| (i * 7 + 3) as u8).collect();
+        let image = with_header(kernel.clone());
+        let mut console = Vec::new();
+        wait_for_ready(&mut port, &mut console).unwrap();
+        send_kernel(&mut port, &image).unwrap();
+
+        assert_eq!(console, b"booting\r\n");
+        let received = board.join().unwrap().unwrap();
+        let payload = verify_image(&received, None).unwrap();
+        assert_eq!(&received[payload], &kernel[..]);
+    }
+
+    #[test]
+    fn test_bundles_linux_kernel_with_dtb_and_initrd() {
+        let bundle = linux_bundle(b"Image", b"dtb", Some(b"initramfs"));
+        let parsed = Bundle::parse(&bundle).unwrap();
+
+        assert_eq!(parsed.get(Kind::Kernel), Some(&b"Image"[..]));
+        assert_eq!(parsed.get(Kind::DeviceTree), Some(&b"dtb"[..]));
+        assert_eq!(parsed.get(Kind::Initrd), Some(&b"initramfs"[..]));
+
+        let mut args = vec!["dev".to_string(), "--dtb".to_string(), "b.dtb".to_string()];
+        assert_eq!(take_option(&mut args, "--dtb").as_deref(), Some("b.dtb"));
+        assert_eq!(args, ["dev"]);
+    }
+
+    #[test]
+    fn test_skips_stale_ready_markers_before_reply() {
+        let mut reply = [&READY_MARKER[..], &READY_MARKER, &ACK].concat();
+        expect_ack(&mut &reply[..]).unwrap();
+
+        reply.splice(..0, *b"x");
+        let err = expect_ack(&mut &reply[..]).unwrap_err();
+        assert!(err.to_string().contains("Unexpected reply"));
+    }
+
+    #[test]
+    fn test_reports_board_rejection() {
+        let (master, slave) = open_pty();
+        let mut port = open_port(&slave, DEFAULT_BAUD).unwrap();
+        let board_end = master.try_clone().unwrap();
+        let board = thread::spawn(move || run_board(board_end, 16));
+
+        wait_for_ready(&mut port, &mut io::sink()).unwrap();
+        let err = send_kernel(&mut port, &[0u8; 32]).unwrap_err();
+
+        assert!(err.to_string().contains("size"));
+        assert!(matches!(
+            board.join().unwrap(),
+            Err(ChainloadError::TooLarge { .. })
+        ));
+    }
+}
@@ -0,0 +1,192 @@
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::process::ExitCode;
+
+use ed25519_compact::{KeyPair, Seed};
+use raspi4_rust_bootloader::boot_image::{self, ImageHeader};
+use raspi4_rust_bootloader::bundle::{self, Kind};
+
+const USAGE: &str = "Usage: sign keygen <secret.key> <public.key>
+       sign <secret.key> <kernel> <signed.img> [--dtb <file.dtb> [--initrd <file>]]";
+
+fn main() -> ExitCode {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let dtb = take_option(&mut args, "--dtb");
+    let initrd = take_option(&mut args, "--initrd");
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    let result = match args[..] {
+        ["keygen", secret_path, public_path] => keygen(secret_path, public_path),
+        [secret_path, image_path, output_path] if dtb.is_some() || initrd.is_none() => sign(
+            secret_path,
+            image_path,
+            dtb.as_deref(),
+            initrd.as_deref(),
+            output_path,
+        ),
+        _ => {
+            eprintln!("{USAGE}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("sign: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Writes a fresh key pair as hex. The secret key file holds the 32 byte seed, the public
+/// key file is what the bootloader build embeds via `BOOT_PUBLIC_KEY`.
+fn keygen(secret_path: &str, public_path: &str) -> io::Result<()> {
+    let mut seed = [0u8; Seed::BYTES];
+    File::open("/dev/urandom")?.read_exact(&mut seed)?;
+    let key_pair = KeyPair::from_seed(Seed::new(seed));
+
+    // Readable by the owner only, and never replacing a key images were already signed with
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(secret_path)?
+        .write_all(format!("{}\n", to_hex(&seed)).as_bytes())?;
+    fs::write(public_path, format!("{}\n", to_hex(&key_pair.pk[..])))?;
+    eprintln!("[sign] Wrote {secret_path} and {public_path}");
+    Ok(())
+}
+
+/// Removes `name` and the value following it from `args`.
+fn take_option(args: &mut Vec<String>, name: &str) -> Option<String> {
+    let index = args.iter().position(|arg| arg == name)?;
+    args.remove(index);
+    (index < args.len()).then(|| args.remove(index))
+}
+
+fn sign(
+    secret_path: &str,
+    image_path: &str,
+    dtb_path: Option<&str>,
+    initrd_path: Option<&str>,
+    output_path: &str,
+) -> io::Result<()> {
+    let key_pair = read_key_pair(secret_path)?;
+    let mut payload = fs::read(image_path)?;
+
+    // Re-signing an already wrapped image signs its payload rather than the old header
+    if let Some(Ok(_)) = ImageHeader::parse(&payload) {
+        let range = boot_image::verify_image(&payload, None)
+            .map_err(|err| io::Error::other(format!("{image_path}: {err}")))?;
+        payload.drain(..range.start);
+    }
+
+    // A Linux Image is signed together with the DTB and initramfs it boots with
+    if let Some(dtb_path) = dtb_path {
+        let dtb = fs::read(dtb_path)?;
+        let initrd = initrd_path.map(fs::read).transpose()?;
+        payload = linux_bundle(&payload, &dtb, initrd.as_deref());
+    }
+
+    fs::write(output_path, signed_image(&key_pair, &payload))?;
+    eprintln!(
+        "[sign] Signed {image_path} ({} bytes) into {output_path}",
+        payload.len()
+    );
+    Ok(())
+}
+
+fn linux_bundle(kernel: &[u8], dtb: &[u8], initrd: Option<&[u8]>) -> Vec<u8> {
+    let mut parts = vec![(Kind::Kernel, kernel), (Kind::DeviceTree, dtb)];
+    if let Some(initrd) = initrd {
+        parts.push((Kind::Initrd, initrd));
+    }
+    let mut bundle = vec![0u8; bundle::encoded_len(&parts)];
+    bundle::encode(&parts, &mut bundle).unwrap();
+    bundle
+}
+
+fn signed_image(key_pair: &KeyPair, payload: &[u8]) -> Vec<u8> {
+    let mut header = ImageHeader::for_payload(payload);
+    header.signature = Some(*key_pair.sk.sign(payload, None));
+
+    let mut image = header.to_bytes()[..header.size()].to_vec();
+    image.extend_from_slice(payload);
+    image
+}
+
+fn read_key_pair(path: &str) -> io::Result<KeyPair> {
+    let hex = fs::read_to_string(path)?;
+    let seed = from_hex(hex.trim())
+        .and_then(|bytes| <[u8; Seed::BYTES]>::try_from(bytes).ok())
+        .ok_or_else(|| io::Error::other(format!("{path} is not a hex encoded secret key")))?;
+    Ok(KeyPair::from_seed(Seed::new(seed)))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use boot_image::{SIGNED_HEADER_SIZE, VerifyError};
+
+    #[test]
+    fn test_signed_image_verifies_with_public_key() {
+        let key_pair = KeyPair::from_seed(Seed::new([42; Seed::BYTES]));
+        let kernel: Vec<u8> = (0..10_000u32).map(|i| (i * 3) as u8).collect();
+        let image = signed_image(&key_pair, &kernel);
+
+        let range = boot_image::verify_image(&image, Some(&key_pair.pk)).unwrap();
+        assert_eq!(range.start, SIGNED_HEADER_SIZE);
+        assert_eq!(&image[range], &kernel[..]);
+
+        let mut tampered = image.clone();
+        tampered[SIGNED_HEADER_SIZE] ^= 1;
+        assert!(matches!(
+            boot_image::verify_image(&tampered, Some(&key_pair.pk)),
+            Err(VerifyError::Crc32Mismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn test_key_files_round_trip() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = env::temp_dir().join(format!("sign-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let secret = dir.join("secret.key");
+        let public = dir.join("public.key");
+        keygen(secret.to_str().unwrap(), public.to_str().unwrap()).unwrap();
+
+        let key_pair = read_key_pair(secret.to_str().unwrap()).unwrap();
+        let public_hex = fs::read_to_string(&public).unwrap();
+        assert_eq!(from_hex(public_hex.trim()).unwrap(), &key_pair.pk[..]);
+
+        let mode = fs::metadata(&secret).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        let err = keygen(secret.to_str().unwrap(), public.to_str().unwrap()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(
+            read_key_pair(secret.to_str().unwrap()).unwrap().pk,
+            key_pair.pk
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}